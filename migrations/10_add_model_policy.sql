@@ -0,0 +1,8 @@
+-- Which model replies in an existing conversation use, for a user or for a whole guild. The user's own policy comes first.
+ALTER TABLE user_settings ADD COLUMN model_policy TEXT;
+CREATE TABLE guild_settings (
+    guild        INTEGER PRIMARY KEY
+                         UNIQUE
+                         NOT NULL,
+    model_policy TEXT
+);
@@ -0,0 +1,9 @@
+-- Threads the bot started for conversations, and the turn that messages in them continue from.
+CREATE TABLE threads (
+    thread INTEGER PRIMARY KEY
+                   UNIQUE
+                   NOT NULL,
+    guild  INTEGER NOT NULL,
+    latest INTEGER REFERENCES conversations (message) ON DELETE SET NULL
+)
+WITHOUT ROWID;
@@ -0,0 +1,41 @@
+-- Conversations in direct messages have no guild. SQLite can't drop NOT NULL from a column, so the table is made again without it. Dropping the old table clears the threads' latest turns, so they're found again afterwards.
+PRAGMA defer_foreign_keys = ON;
+CREATE TABLE conversations_new (
+    message        INTEGER  PRIMARY KEY
+                            UNIQUE
+                            NOT NULL,
+    channel        INTEGER  NOT NULL,
+    guild          INTEGER,
+    parent         INTEGER  REFERENCES conversations_new (message) ON DELETE SET NULL,
+    input          TEXT     NOT NULL,
+    output         TEXT     NOT NULL,
+    time           DATETIME NOT NULL
+                            DEFAULT (datetime() ),
+    system_message TEXT,
+    attachments    TEXT,
+    summary        TEXT,
+    prompt_message INTEGER,
+    author         INTEGER,
+    deleted        BOOLEAN  NOT NULL
+                            DEFAULT FALSE,
+    model          TEXT,
+    input_tokens   INTEGER,
+    output_tokens  INTEGER,
+    cost           INTEGER,
+    finish_reason  TEXT
+)
+WITHOUT ROWID;
+INSERT INTO conversations_new (message, channel, guild, parent, input, output, time, system_message, attachments, summary, prompt_message, author, deleted, model, input_tokens, output_tokens, cost, finish_reason)
+SELECT message, channel, guild, parent, input, output, time, system_message, attachments, summary, prompt_message, author, deleted, model, input_tokens, output_tokens, cost, finish_reason
+FROM conversations;
+DROP TABLE conversations;
+ALTER TABLE conversations_new RENAME TO conversations;
+CREATE INDEX conversations_prompt_message ON conversations (prompt_message);
+UPDATE threads
+SET latest = (
+    SELECT message
+    FROM conversations
+    WHERE channel = thread AND NOT deleted
+    ORDER BY message DESC
+    LIMIT 1
+);
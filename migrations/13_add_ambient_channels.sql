@@ -0,0 +1,13 @@
+-- Channels where every message is for the bot, with the personality and model to use there, and how long in seconds each user has to wait between prompts.
+CREATE TABLE ambient_channels (
+    channel     INTEGER PRIMARY KEY
+                        UNIQUE
+                        NOT NULL,
+    guild       INTEGER NOT NULL,
+    personality TEXT,
+    model       TEXT,
+    cooldown    INTEGER NOT NULL
+)
+WITHOUT ROWID;
+-- For finding someone's latest turn in a channel.
+CREATE INDEX conversations_channel_author ON conversations (channel, author);
@@ -0,0 +1,2 @@
+-- What the author went by when asking, to tell people apart in conversations.
+ALTER TABLE conversations ADD COLUMN author_name TEXT;
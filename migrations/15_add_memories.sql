@@ -0,0 +1,10 @@
+-- Facts users asked the bot to remember about them. They're only told to the model if the user turned memory on.
+CREATE TABLE memories (
+    id     INTEGER  PRIMARY KEY,
+    user   INTEGER  NOT NULL,
+    memory TEXT     NOT NULL,
+    time   DATETIME NOT NULL
+                    DEFAULT (datetime() )
+);
+CREATE INDEX memories_user ON memories (user);
+ALTER TABLE user_settings ADD COLUMN memory BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,18 @@
+-- Full-text search over what was asked and answered, kept up to date by triggers.
+CREATE VIRTUAL TABLE conversations_search USING fts5 (
+    input,
+    output,
+    content = 'conversations',
+    content_rowid = 'message'
+);
+INSERT INTO conversations_search (conversations_search) VALUES ('rebuild');
+CREATE TRIGGER conversations_search_insert AFTER INSERT ON conversations BEGIN
+    INSERT INTO conversations_search (rowid, input, output) VALUES (new.message, new.input, new.output);
+END;
+CREATE TRIGGER conversations_search_delete AFTER DELETE ON conversations BEGIN
+    INSERT INTO conversations_search (conversations_search, rowid, input, output) VALUES ('delete', old.message, old.input, old.output);
+END;
+CREATE TRIGGER conversations_search_update AFTER UPDATE OF input, output ON conversations BEGIN
+    INSERT INTO conversations_search (conversations_search, rowid, input, output) VALUES ('delete', old.message, old.input, old.output);
+    INSERT INTO conversations_search (rowid, input, output) VALUES (new.message, new.input, new.output);
+END;
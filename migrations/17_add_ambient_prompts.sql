@@ -0,0 +1,9 @@
+-- When each user last prompted the bot in each ambient channel, to make them wait between prompts even while a reply is still coming or failed.
+CREATE TABLE ambient_prompts (
+    channel INTEGER  NOT NULL,
+    user    INTEGER  NOT NULL,
+    time    DATETIME NOT NULL
+                     DEFAULT (datetime() ),
+    PRIMARY KEY (channel, user)
+)
+WITHOUT ROWID;
@@ -0,0 +1,2 @@
+-- A JSON array of the URLs of the images attached to the input, or NULL if there were none.
+ALTER TABLE conversations ADD COLUMN attachments TEXT;
@@ -0,0 +1,2 @@
+-- The number of images generated, for charges that are per image rather than per token.
+ALTER TABLE spending ADD COLUMN images INTEGER NOT NULL DEFAULT 0;
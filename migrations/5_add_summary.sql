@@ -0,0 +1,2 @@
+-- A summary of the conversation up to and including this message, made when the conversation no longer fit in a model's context window.
+ALTER TABLE conversations ADD COLUMN summary TEXT;
@@ -0,0 +1,5 @@
+-- What went into the cost besides plain input and output tokens. Earlier rows are each one request.
+ALTER TABLE spending ADD COLUMN cached_input_tokens INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE spending ADD COLUMN reasoning_tokens INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE spending ADD COLUMN requests INTEGER NOT NULL DEFAULT 1;
+ALTER TABLE spending ADD COLUMN search_calls INTEGER NOT NULL DEFAULT 0;
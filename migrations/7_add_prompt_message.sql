@@ -0,0 +1,3 @@
+-- The user's message each reply was to, so edits to it can be followed.
+ALTER TABLE conversations ADD COLUMN prompt_message INTEGER;
+CREATE INDEX conversations_prompt_message ON conversations (prompt_message);
@@ -0,0 +1,3 @@
+-- Who asked, so only they can delete the reply, and whether the prompt or reply was deleted, which leaves the turn out of conversations.
+ALTER TABLE conversations ADD COLUMN author INTEGER;
+ALTER TABLE conversations ADD COLUMN deleted BOOLEAN NOT NULL DEFAULT FALSE;
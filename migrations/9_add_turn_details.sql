@@ -0,0 +1,6 @@
+-- Which model replied, how many tokens it took and what it cost in nanodollars, and why the reply ended.
+ALTER TABLE conversations ADD COLUMN model TEXT;
+ALTER TABLE conversations ADD COLUMN input_tokens INTEGER;
+ALTER TABLE conversations ADD COLUMN output_tokens INTEGER;
+ALTER TABLE conversations ADD COLUMN cost INTEGER;
+ALTER TABLE conversations ADD COLUMN finish_reason TEXT;
@@ -13,14 +13,16 @@ use crate::util::interaction_reply;
 pub const DEFAULT_DAILY_ALLOWANCE: u32 = 2_500_000;
 /// The number of days' worth of allowance a user can save up before it stops accruing, by default.
 pub const DEFAULT_ACCRUAL_DAYS: f32 = 4.0;
+/// How far into the negative a request may take a user's allowance at most, in nanodollars, by default.
+pub const DEFAULT_OVERSPEND_MARGIN: u32 = 1_000_000;
 
 const MILLISECONDS_PER_DAY: u64 = 1000 * 60 * 60 * 24;
 
 #[derive(Debug)]
-/// Be aware of range issues converting millidollars (`f32`) to nanodollars (`i32`).
+/// Be aware of range issues converting millidollars (`f32`) to nanodollars (`i64`).
 pub enum Allowance {
 	Millidollars(f32),
-	Nanodollars(i32),
+	Nanodollars(i64),
 	Infinite,
 }
 
@@ -38,7 +40,11 @@ impl Allowance {
 		let duration = time_to_full - Utc::now();
 		let days_left = duration.num_milliseconds() as f32 / MILLISECONDS_PER_DAY as f32;
 		let missing_allowance = days_left * daily_allowance as f32;
-		Self::Nanodollars((daily_allowance as f32 * accrual_days - missing_allowance) as i32)
+		Self::Nanodollars((daily_allowance as f32 * accrual_days - missing_allowance) as i64)
+	}
+	/// A cost in nanodollars, as the most there can be if it's more.
+	pub fn from_cost(cost: u64) -> Self {
+		Self::Nanodollars(nanodollars_to_i64(cost))
 	}
 	pub async fn check(
 		executor: &Pool<Sqlite>,
@@ -60,6 +66,15 @@ impl Allowance {
 			Self::Infinite => false,
 		}
 	}
+	/// Whether spending `cost` nanodollars would leave the allowance no further below 0 than `margin`.
+	pub fn can_afford(&self, cost: u64, margin: u32) -> bool {
+		let nanodollars = match self {
+			Self::Millidollars(n) => (*n * MILLIDOLLARS_PER_NANODOLLAR) as i64,
+			Self::Nanodollars(n) => *n,
+			Self::Infinite => return true,
+		};
+		nanodollars_to_i64(cost) <= nanodollars.saturating_add(margin as i64)
+	}
 }
 
 impl Display for Allowance {
@@ -75,6 +90,20 @@ impl Display for Allowance {
 	}
 }
 
+/// Converts a cost in nanodollars to how it's stored, as the most there can be if it's more.
+pub fn nanodollars_to_i64(cost: u64) -> i64 {
+	i64::try_from(cost).unwrap_or(i64::MAX)
+}
+
+/// Tells the user a request could cost more than they have.
+pub fn unaffordable_message(cost: u64, allowance: &Allowance) -> String {
+	format!(
+		"This could cost up to {}, but you only have {} left.",
+		Allowance::from_cost(cost),
+		allowance
+	)
+}
+
 pub async fn allowance_and_max(
 	executor: &Pool<Sqlite>,
 	user: UserId,
@@ -118,34 +147,60 @@ pub async fn spend_allowance(
 	is_allowance_infinite: bool,
 ) -> (Allowance, Allowance) {
 	let cost = model.get_cost(token_usage);
+	let new_time = take_from_allowance(executor, user, cost, daily_allowance).await;
 
-	let added_milliseconds = cost as u64 * MILLISECONDS_PER_DAY / daily_allowance as u64;
-	let time = time_to_full(executor, user).await.unwrap_or_else(Utc::now);
-	let new_time = time + Duration::milliseconds(added_milliseconds as i64);
 	let user_id = user.get() as i64;
-
+	let search_calls = model.search_calls(token_usage);
+	let model = model.name();
+	let stored_cost = nanodollars_to_i64(cost);
 	query!(
 		"
-		INSERT INTO allowances (user, time_to_full)
-		VALUES (?, ?)
+		INSERT INTO spending (user, cost, input_tokens, output_tokens, model, cached_input_tokens, reasoning_tokens, requests, search_calls)
+		VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
 		",
 		user_id,
-		new_time,
+		stored_cost,
+		token_usage.prompt_tokens,
+		token_usage.completion_tokens,
+		model,
+		token_usage.prompt_tokens_details.cached_tokens,
+		token_usage.completion_tokens_details.reasoning_tokens,
+		token_usage.requests,
+		search_calls,
 	)
 	.execute(executor)
 	.await
 	.unwrap();
 
-	let model = model.name();
+	let allowance = if is_allowance_infinite {
+		Allowance::Infinite
+	} else {
+		Allowance::from_time_to_full(new_time, daily_allowance, accrual_days)
+	};
+
+	(allowance, Allowance::from_cost(cost))
+}
+
+/// Takes the price of a generated image from the user's allowance, then returns the new allowance and what the cost ended up being.
+pub async fn spend_allowance_on_image(
+	executor: &Pool<Sqlite>,
+	user: UserId,
+	model: &str,
+	cost: u32,
+	daily_allowance: u32,
+	accrual_days: f32,
+	is_allowance_infinite: bool,
+) -> (Allowance, Allowance) {
+	let new_time = take_from_allowance(executor, user, cost as u64, daily_allowance).await;
+
+	let user_id = user.get() as i64;
 	query!(
 		"
-		INSERT INTO spending (user, cost, input_tokens, output_tokens, model)
-		VALUES (?, ?, ?, ?, ?)
+		INSERT INTO spending (user, cost, input_tokens, output_tokens, model, images)
+		VALUES (?, ?, 0, 0, ?, 1)
 		",
 		user_id,
 		cost,
-		token_usage.prompt_tokens,
-		token_usage.completion_tokens,
 		model,
 	)
 	.execute(executor)
@@ -158,7 +213,38 @@ pub async fn spend_allowance(
 		Allowance::from_time_to_full(new_time, daily_allowance, accrual_days)
 	};
 
-	(allowance, Allowance::Nanodollars(cost as i32))
+	(allowance, Allowance::from_cost(cost as u64))
+}
+
+/// Pushes back the time the user's allowance is full by how long it takes to accrue the cost, and returns the new time.
+async fn take_from_allowance(
+	executor: &Pool<Sqlite>,
+	user: UserId,
+	cost: u64,
+	daily_allowance: u32,
+) -> DateTime<Utc> {
+	let added_milliseconds = (cost as u128 * MILLISECONDS_PER_DAY as u128
+		/ daily_allowance as u128)
+		.min(i64::MAX as u128);
+	let time = time_to_full(executor, user).await.unwrap_or_else(Utc::now);
+	let new_time = Duration::try_milliseconds(added_milliseconds as i64)
+		.and_then(|added| time.checked_add_signed(added))
+		.unwrap_or(DateTime::<Utc>::MAX_UTC);
+	let user_id = user.get() as i64;
+
+	query!(
+		"
+		INSERT INTO allowances (user, time_to_full)
+		VALUES (?, ?)
+		",
+		user_id,
+		new_time,
+	)
+	.execute(executor)
+	.await
+	.unwrap();
+
+	new_time
 }
 
 const PRECISION_MULTIPLIER: f32 = 100.0;
@@ -247,7 +333,9 @@ pub async fn command_expenditure(
 }
 pub fn register_check_expenditure() -> CreateCommand {
 	CreateCommand::new("spent")
-		.description("Check how many millidollars you have or everyone has used on GPT prompts.")
+		.description(
+			"Check how many millidollars you have or everyone has used on GPT prompts and images.",
+		)
 		.add_option(
 			CreateCommandOption::new(
 				CommandOptionType::Boolean,
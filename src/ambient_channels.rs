@@ -0,0 +1,276 @@
+//! Channels where the bot answers every message, without needing to be mentioned.
+
+use serenity::{
+	all::{ChannelId, CommandInteraction, CommandOptionType, GuildId, Permissions, UserId},
+	builder::{CreateCommand, CreateCommandOption},
+	prelude::Context,
+};
+use sqlx::{query, Pool, Sqlite};
+
+use crate::{gpt::Gpt, util::interaction_reply};
+
+/// How long in seconds each user has to wait between prompts in an ambient channel, by default.
+pub const DEFAULT_COOLDOWN: u32 = 30;
+/// The longest cooldown that can be set, in seconds
+const MAX_COOLDOWN: u32 = 3600;
+
+/// How the bot behaves in a channel where it answers every message
+pub struct AmbientChannel {
+	/// The personality new conversations in the channel start with, instead of the user's own
+	pub personality: Option<String>,
+	/// The model replies in the channel use, instead of the user's own
+	pub model: Option<String>,
+	/// In seconds
+	pub cooldown: u32,
+}
+
+pub async fn get_ambient_channel(
+	executor: &Pool<Sqlite>,
+	channel: ChannelId,
+) -> Option<AmbientChannel> {
+	let channel_id = channel.get() as i64;
+	query!(
+		"
+		SELECT
+			personality,
+			model,
+			cooldown
+		FROM
+			ambient_channels
+		WHERE
+			channel = ?
+		",
+		channel_id
+	)
+	.fetch_optional(executor)
+	.await
+	.unwrap()
+	.map(|record| AmbientChannel {
+		personality: record.personality,
+		model: record.model,
+		cooldown: record.cooldown as u32,
+	})
+}
+
+async fn set_ambient_channel(
+	executor: &Pool<Sqlite>,
+	guild: GuildId,
+	channel: ChannelId,
+	settings: &AmbientChannel,
+) {
+	let guild_id = guild.get() as i64;
+	let channel_id = channel.get() as i64;
+	query!(
+		"
+		INSERT INTO
+			ambient_channels (channel, guild, personality, model, cooldown)
+		VALUES
+			(?, ?, ?, ?, ?)
+		ON CONFLICT (channel)
+			DO UPDATE SET
+				personality = excluded.personality,
+				model = excluded.model,
+				cooldown = excluded.cooldown
+		",
+		channel_id,
+		guild_id,
+		settings.personality,
+		settings.model,
+		settings.cooldown,
+	)
+	.execute(executor)
+	.await
+	.unwrap();
+}
+
+async fn remove_ambient_channel(executor: &Pool<Sqlite>, channel: ChannelId) {
+	let channel_id = channel.get() as i64;
+	query!("DELETE FROM ambient_channels WHERE channel = ?", channel_id)
+		.execute(executor)
+		.await
+		.unwrap();
+	query!("DELETE FROM ambient_prompts WHERE channel = ?", channel_id)
+		.execute(executor)
+		.await
+		.unwrap();
+}
+
+/// Notes that the user prompted the bot in the ambient channel just now, which starts their cooldown there whether or not the reply works out.
+pub async fn start_cooldown(executor: &Pool<Sqlite>, channel: ChannelId, user: UserId) {
+	let channel_id = channel.get() as i64;
+	let user_id = user.get() as i64;
+	query!(
+		"
+		INSERT INTO
+			ambient_prompts (channel, user)
+		VALUES
+			(?, ?)
+		ON CONFLICT (channel, user)
+			DO UPDATE SET
+				time = excluded.time
+		",
+		channel_id,
+		user_id,
+	)
+	.execute(executor)
+	.await
+	.unwrap();
+}
+
+/// Whether the user prompted the bot in the channel less than `cooldown` seconds ago.
+pub async fn is_on_cooldown(
+	executor: &Pool<Sqlite>,
+	channel: ChannelId,
+	user: UserId,
+	cooldown: u32,
+) -> bool {
+	let channel_id = channel.get() as i64;
+	let user_id = user.get() as i64;
+	let earliest = format!("-{cooldown} seconds");
+	query!(
+		"
+		SELECT COUNT(*) AS count
+		FROM ambient_prompts
+		WHERE channel = ? AND user = ? AND time >= datetime('now', ?)
+		",
+		channel_id,
+		user_id,
+		earliest,
+	)
+	.fetch_one(executor)
+	.await
+	.unwrap()
+	.count > 0
+}
+
+/// Turn answering every message in the channel on, with its settings, or off.
+pub async fn command_ambient(
+	context: Context,
+	interaction: CommandInteraction,
+	executor: &Pool<Sqlite>,
+	gpt: &Gpt,
+) -> Result<(), ()> {
+	let guild = interaction.guild_id.ok_or(())?;
+	// Discord hides the command from others, but servers can change who sees it.
+	let can_manage_channels = interaction
+		.member
+		.as_ref()
+		.and_then(|member| member.permissions)
+		.is_some_and(|permissions| permissions.manage_channels());
+	if !can_manage_channels {
+		let _ = interaction_reply(
+			context,
+			interaction,
+			"You need the Manage Channels permission to change this.",
+			true,
+		)
+		.await;
+		return Ok(());
+	}
+
+	let option = |name: &str| {
+		interaction
+			.data
+			.options
+			.iter()
+			.find(|option| option.name == name)
+			.map(|option| &option.value)
+	};
+	let enabled = option("enabled")
+		.and_then(|value| value.as_bool())
+		.ok_or(())?;
+	let channel = interaction.channel_id;
+	if !enabled {
+		remove_ambient_channel(executor, channel).await;
+		let _ = interaction_reply(
+			context,
+			interaction,
+			"I'll only answer in this channel when mentioned again.",
+			false,
+		)
+		.await;
+		return Ok(());
+	}
+
+	let settings = AmbientChannel {
+		personality: option("personality")
+			.and_then(|value| value.as_str())
+			.map(str::to_string),
+		model: option("model")
+			.and_then(|value| value.as_str())
+			.map(str::to_string),
+		cooldown: option("cooldown")
+			.and_then(|value| value.as_i64())
+			.map_or(DEFAULT_COOLDOWN, |cooldown| cooldown as u32),
+	};
+	set_ambient_channel(executor, guild, channel, &settings).await;
+
+	let personality = settings.personality.as_deref().unwrap_or("everyone's own");
+	let model = settings
+		.model
+		.as_deref()
+		.map(|name| {
+			gpt.get_model_by_name(name)
+				.map_or(name, |model| model.friendly_name())
+		})
+		.unwrap_or("everyone's own");
+	let output = format!(
+		"I'll answer every message in this channel now, with {personality} personality and {model} model, at most once every {} seconds per person.",
+		settings.cooldown
+	);
+	let _ = interaction_reply(context, interaction, output, false).await;
+	Ok(())
+}
+
+pub fn register_ambient(gpt: &Gpt) -> CreateCommand {
+	let mut personality_option = CreateCommandOption::new(
+		CommandOptionType::String,
+		"personality",
+		"The personality new conversations here start with. Leave out for everyone's own.",
+	)
+	.required(false);
+	for personality in gpt.personalities() {
+		personality_option = personality_option.add_string_choice(
+			format!("{} {}", personality.name(), personality.emoji()),
+			personality.name(),
+		);
+	}
+	let mut model_option = CreateCommandOption::new(
+		CommandOptionType::String,
+		"model",
+		"The model replies here use. Leave out for everyone's own.",
+	)
+	.required(false);
+	for model in gpt.models() {
+		model_option = model_option.add_string_choice(model.friendly_name(), model.name());
+	}
+
+	CreateCommand::new("ambient")
+		.description(
+			"Sets whether I answer every message in this channel, without being mentioned.",
+		)
+		.default_member_permissions(Permissions::MANAGE_CHANNELS)
+		.dm_permission(false)
+		.add_option(
+			CreateCommandOption::new(
+				CommandOptionType::Boolean,
+				"enabled",
+				"Whether to answer every message here",
+			)
+			.required(true),
+		)
+		.add_option(personality_option)
+		.add_option(model_option)
+		.add_option(
+			CreateCommandOption::new(
+				CommandOptionType::Integer,
+				"cooldown",
+				format!(
+					"Seconds each person has to wait between prompts. {DEFAULT_COOLDOWN} by default."
+				),
+			)
+			.min_int_value(0)
+			.max_int_value(MAX_COOLDOWN as u64)
+			.required(false),
+		)
+}
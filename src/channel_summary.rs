@@ -0,0 +1,421 @@
+//! Summarizing what was said in a channel, for someone who missed it.
+
+use serenity::{
+	all::{ChannelId, CommandInteraction, CommandOptionType, Message, MessageId, UserId},
+	builder::{CreateCommand, CreateCommandOption, GetMessages},
+	http::Http,
+	prelude::{Context, SerenityError},
+};
+use sqlx::{Pool, Sqlite};
+
+use crate::{
+	allowances::{allowance_and_max, spend_allowance, unaffordable_message},
+	discord_client::{display_name, parse_message_link},
+	gpt::{ChatMessage, Gpt, GptModel, MessageChoice, Sampling, TokenUsage},
+	token_estimation::Tokenizer,
+	user_settings::get_model_setting,
+	util::{format_chat_message, interaction_followup, interaction_reply},
+};
+
+const SYSTEM_MESSAGE: &str = "Summarize the chat messages the user gives you, for someone who missed them. Each message starts with the name of who sent it. Keep it brief, but keep the main topics, any decisions, open questions and who said what when it matters. Reply with only the summary.";
+const EMOJI: &str = "📝";
+/// How many of the latest messages are summarized when the user doesn't say.
+const DEFAULT_COUNT: u32 = 50;
+/// The most messages summarized at once, since each page of 100 is a request to Discord
+const MAX_MESSAGES: u32 = 500;
+/// The most messages Discord gives at once
+const PAGE_SIZE: u32 = 100;
+
+/// Splits a line into pieces of at most `budget` tokens each, so that even a huge message fits in a chunk.
+fn split_line(tokenizer: Tokenizer, line: &str, budget: u32) -> Vec<String> {
+	let mut pieces = Vec::new();
+	let mut rest = line;
+	while budget > 0 && tokenizer.count(rest) > budget {
+		// The longest prefix that fits, found by bisecting on character boundaries. There's always at least one character, so that it makes progress.
+		let boundaries = rest
+			.char_indices()
+			.map(|(index, _)| index)
+			.skip(1)
+			.collect::<Vec<_>>();
+		let fitting = boundaries.partition_point(|&end| tokenizer.count(&rest[..end]) <= budget);
+		let Some(&end) = boundaries.get(fitting.saturating_sub(1)) else {
+			break;
+		};
+		pieces.push(rest[..end].to_string());
+		rest = &rest[end..];
+	}
+	pieces.push(rest.to_string());
+	pieces
+}
+
+/// Splits the transcript into chunks that each fit in the model's context window, next to the summary of the chunks before and the new summary, which are at most as long as a default reply each, since that's what they're asked for with.
+fn chunk_transcript(model: &GptModel, lines: &[String]) -> Vec<String> {
+	let tokenizer = model.tokenizer();
+	let budget = model
+		.context_length()
+		.saturating_sub(2 * model.default_reply_tokens() + tokenizer.count(SYSTEM_MESSAGE));
+	let mut chunks = Vec::new();
+	let mut chunk = String::new();
+	let mut tokens = 0;
+	let lines = lines
+		.iter()
+		.flat_map(|line| split_line(tokenizer, line, budget));
+	for line in lines {
+		let line_tokens = tokenizer.count(&line);
+		if !chunk.is_empty() && tokens + line_tokens > budget {
+			chunks.push(std::mem::take(&mut chunk));
+			tokens = 0;
+		}
+		if !chunk.is_empty() {
+			chunk.push('\n');
+		}
+		chunk.push_str(&line);
+		tokens += line_tokens;
+	}
+	if !chunk.is_empty() {
+		chunks.push(chunk);
+	}
+	chunks
+}
+
+impl Gpt {
+	/// Summarizes the messages, oldest first, a chunk at a time if they don't all fit in the model's context window, and charges the user for it. Each chunk builds on the summary of the ones before.
+	///
+	/// An OK result is the formatted summary. An error is a message for the user.
+	pub async fn summarize_channel(
+		&self,
+		executor: &Pool<Sqlite>,
+		user: UserId,
+		messages: &[String],
+	) -> Result<String, String> {
+		let model = get_model_setting(executor, user)
+			.await
+			.and_then(|name| self.get_model_by_name(&name))
+			.unwrap_or(self.default_model());
+		let custom_api_key = self.custom_api_key(user, model);
+
+		let (allowance, max_allowance) = allowance_and_max(
+			executor,
+			user,
+			self.daily_allowance(),
+			self.accrual_days(),
+			custom_api_key.is_some(),
+		)
+		.await;
+		if allowance.is_out() {
+			return Err(format!(
+				"You are out of allowance. ({}/{})",
+				allowance, max_allowance
+			));
+		}
+
+		let chunks = chunk_transcript(model, messages);
+		let earlier_summary_cost = model.get_cost(TokenUsage {
+			prompt_tokens: model.default_reply_tokens(),
+			..Default::default()
+		});
+		let worst_case_cost = chunks
+			.iter()
+			.enumerate()
+			.map(|(index, chunk)| {
+				let history = [
+					ChatMessage::system(SYSTEM_MESSAGE.to_string()),
+					ChatMessage::user(chunk.clone()),
+				];
+				let cost = model.get_worst_case_cost(&history, &[], Sampling::default());
+				if index == 0 {
+					cost
+				} else {
+					cost.saturating_add(earlier_summary_cost)
+				}
+			})
+			.fold(0u64, u64::saturating_add);
+		if !allowance.can_afford(worst_case_cost, self.overspend_margin()) {
+			return Err(unaffordable_message(worst_case_cost, &allowance));
+		}
+
+		let api_key = custom_api_key.or(self.api_key(model));
+		let mut usage = TokenUsage::default();
+		let mut summary: Option<MessageChoice> = None;
+		let mut error = None;
+		for chunk in chunks {
+			let transcript = match &summary {
+				Some(choice) => format!(
+					"Summary of what came before: {}\n\n{chunk}",
+					choice.message.content
+				),
+				None => chunk,
+			};
+			let history = [
+				ChatMessage::system(SYSTEM_MESSAGE.to_string()),
+				ChatMessage::user(transcript),
+			];
+			match self
+				.send(&history, model, api_key, &[], Sampling::default())
+				.await
+			{
+				Ok(mut response) => {
+					usage += response.usage;
+					summary = Some(response.message_choices.swap_remove(0));
+				}
+				Err(send_error) => {
+					error = Some(send_error.user_message().to_string());
+					break;
+				}
+			}
+		}
+
+		// The chunks summarized before an error were still paid for.
+		if usage.requests == 0 {
+			return Err(error.unwrap_or_default());
+		}
+		let (allowance, cost) = spend_allowance(
+			executor,
+			user,
+			usage,
+			model,
+			self.daily_allowance(),
+			self.accrual_days(),
+			custom_api_key.is_some(),
+		)
+		.await;
+		if let Some(error) = error {
+			return Err(error);
+		}
+
+		Ok(format_chat_message(
+			&summary.unwrap(),
+			EMOJI,
+			cost,
+			allowance,
+			(self.default_model() != model).then_some(model),
+			Some(&format!("({} messages)", messages.len())),
+		))
+	}
+}
+
+/// Gets the messages to summarize, oldest first: the ones from `since` on, or else the latest `count`. At most `MAX_MESSAGES` either way.
+async fn fetch_messages(
+	http: &Http,
+	channel: ChannelId,
+	since: Option<MessageId>,
+	count: u32,
+) -> Result<Vec<Message>, SerenityError> {
+	let mut messages = Vec::new();
+	if let Some(since) = since {
+		messages.push(channel.message(http, since).await?);
+		let mut after = since;
+		while messages.len() < MAX_MESSAGES as usize {
+			let page = channel
+				.messages(http, GetMessages::new().after(after).limit(PAGE_SIZE as u8))
+				.await?;
+			let Some(newest) = page.iter().map(|message| message.id).max() else {
+				break;
+			};
+			after = newest;
+			messages.extend(page);
+		}
+		messages.sort_by_key(|message| message.id);
+		messages.truncate(MAX_MESSAGES as usize);
+	} else {
+		let mut before = None;
+		while messages.len() < count as usize {
+			let limit = (count as usize - messages.len()).min(PAGE_SIZE as usize);
+			let mut request = GetMessages::new().limit(limit as u8);
+			if let Some(before) = before {
+				request = request.before(before);
+			}
+			let page = channel.messages(http, request).await?;
+			let Some(oldest) = page.iter().map(|message| message.id).min() else {
+				break;
+			};
+			before = Some(oldest);
+			messages.extend(page);
+		}
+		messages.sort_by_key(|message| message.id);
+	}
+	Ok(messages)
+}
+
+/// A message as a line of the transcript, like "Alice: Hello", with the text of any embeds, since the bot puts long replies in them. Messages without any text are left out.
+fn transcript_line(message: &Message) -> Option<String> {
+	let text = std::iter::once(message.content.as_str())
+		.chain(
+			message
+				.embeds
+				.iter()
+				.filter_map(|embed| embed.description.as_deref()),
+		)
+		.filter(|text| !text.trim().is_empty())
+		.collect::<Vec<_>>()
+		.join("\n");
+	if text.is_empty() {
+		return None;
+	}
+	let name = display_name(
+		&message.author,
+		message
+			.member
+			.as_ref()
+			.and_then(|member| member.nick.as_ref()),
+	);
+	Some(format!("{name}: {text}"))
+}
+
+/// Summarizes the latest messages in the channel, or the ones since a linked message, just for the user.
+pub async fn command_summarize(
+	context: Context,
+	interaction: CommandInteraction,
+	executor: &Pool<Sqlite>,
+	gpt: &Gpt,
+) -> Result<(), ()> {
+	let option = |name: &str| {
+		interaction
+			.data
+			.options
+			.iter()
+			.find(|option| option.name == name)
+			.map(|option| &option.value)
+	};
+	let count = option("count")
+		.and_then(|value| value.as_i64())
+		.map_or(DEFAULT_COUNT, |count| count as u32);
+	let since = match option("since").and_then(|value| value.as_str()) {
+		Some(link) => match parse_message_link(link) {
+			Some(ids)
+				if ids.guild_id == interaction.guild_id
+					&& ids.channel_id == interaction.channel_id =>
+			{
+				Some(ids.message_id)
+			}
+			_ => {
+				let _ = interaction_reply(
+					context,
+					interaction,
+					"That isn't a link to a message in this channel.",
+					true,
+				)
+				.await;
+				return Ok(());
+			}
+		},
+		None => None,
+	};
+
+	// In a guild, the member's permissions are the ones they have in this channel. Direct messages are the user's own.
+	let can_read_history = interaction.member.as_ref().is_none_or(|member| {
+		member.permissions.is_some_and(|permissions| {
+			permissions.view_channel() && permissions.read_message_history()
+		})
+	});
+	if !can_read_history {
+		let _ = interaction_reply(
+			context,
+			interaction,
+			"You need to be able to read the message history here to summarize it.",
+			true,
+		)
+		.await;
+		return Ok(());
+	}
+
+	interaction
+		.defer_ephemeral(&context)
+		.await
+		.map_err(|_| ())?;
+
+	let messages = match fetch_messages(&context.http, interaction.channel_id, since, count).await {
+		Ok(messages) => messages,
+		Err(error) => {
+			eprintln!("Could not fetch messages to summarize: {error}");
+			let _ = interaction_followup(
+				context,
+				interaction,
+				"I couldn't read the messages here.",
+				true,
+				false,
+			)
+			.await;
+			return Ok(());
+		}
+	};
+	let lines = messages
+		.iter()
+		.filter_map(transcript_line)
+		.collect::<Vec<_>>();
+	if lines.is_empty() {
+		let _ = interaction_followup(
+			context,
+			interaction,
+			"There's nothing to summarize.",
+			true,
+			false,
+		)
+		.await;
+		return Ok(());
+	}
+
+	let (response, always_embed) = match gpt
+		.summarize_channel(executor, interaction.user.id, &lines)
+		.await
+	{
+		Ok(summary) => (summary, true),
+		Err(error) => (error, false),
+	};
+	let _ = interaction_followup(context, interaction, response, true, always_embed).await;
+	Ok(())
+}
+
+pub fn register_summarize() -> CreateCommand {
+	CreateCommand::new("summarize")
+		.description("Summarizes what was said in this channel, just for you.")
+		.add_option(
+			CreateCommandOption::new(
+				CommandOptionType::Integer,
+				"count",
+				format!("How many of the latest messages to summarize. {DEFAULT_COUNT} by default."),
+			)
+			.min_int_value(1)
+			.max_int_value(MAX_MESSAGES as u64)
+			.required(false),
+		)
+		.add_option(
+			CreateCommandOption::new(
+				CommandOptionType::String,
+				"since",
+				format!("A link to the message to summarize from instead, up to {MAX_MESSAGES} messages."),
+			)
+			.required(false),
+		)
+}
+
+#[cfg(test)]
+mod tests {
+	use serde_json::json;
+
+	use super::{chunk_transcript, SYSTEM_MESSAGE};
+	use crate::gpt::GptModel;
+
+	#[test]
+	fn huge_lines_are_split_to_fit_in_chunks() {
+		let model: GptModel = serde_json::from_value(json!({
+			"name": "gpt-test",
+			"friendly_name": "GPT Test",
+			"input_cost": 1,
+			"output_cost": 4,
+			"default_output_tokens": 100,
+			"context_length": 1000,
+		}))
+		.unwrap();
+		let tokenizer = model.tokenizer();
+		let budget = 1000 - 2 * 100 - tokenizer.count(SYSTEM_MESSAGE);
+
+		let huge_line = format!("Alice:{}", " word".repeat(5000));
+		let lines = [String::from("Bob: Hi"), huge_line.clone()];
+		let chunks = chunk_transcript(&model, &lines);
+		assert!(chunks.len() > 5, "{}", chunks.len());
+		assert!(chunks.iter().all(|chunk| tokenizer.count(chunk) <= budget));
+		assert_eq!(chunks[0], "Bob: Hi");
+		assert_eq!(chunks[1..].concat(), huge_line);
+	}
+}
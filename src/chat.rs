@@ -0,0 +1,80 @@
+//! The bot's side of a conversation, separate from Discord so that conversations can be driven without it.
+
+use serenity::{
+	all::{ChannelId, MessageId, UserId},
+	async_trait,
+	builder::CreateThread,
+	http::Http,
+	prelude::SerenityError,
+};
+
+use crate::{
+	conversations::MessageIds,
+	util::{edit_reply, finish_reply, reply},
+};
+
+/// A message that asks the bot something.
+#[derive(Debug, Clone)]
+pub struct IncomingMessage {
+	pub ids: MessageIds,
+	pub author: UserId,
+	/// What the author goes by, to tell people apart in conversations
+	pub author_name: String,
+}
+
+/// Where the bot sends its replies.
+#[async_trait]
+pub trait Chat: Sync {
+	/// Replies to a message, and returns the IDs of the reply.
+	async fn reply(&self, to: MessageIds, content: String) -> Result<MessageIds, SerenityError>;
+	/// Sends a message to a channel without replying to anything.
+	async fn send(&self, channel: ChannelId, content: String) -> Result<MessageId, SerenityError>;
+	/// Starts a public thread from a message, and returns the thread's ID.
+	async fn start_thread(
+		&self,
+		from: MessageIds,
+		name: String,
+	) -> Result<ChannelId, SerenityError>;
+	/// Edits one of the bot's own messages.
+	async fn edit(&self, message: MessageIds, content: String) -> Result<(), SerenityError>;
+	/// Edits one of the bot's own replies into its final form, with a button the asker can press to get another reply instead.
+	async fn finish(
+		&self,
+		message: MessageIds,
+		content: String,
+		asker: UserId,
+	) -> Result<(), SerenityError>;
+}
+
+#[async_trait]
+impl Chat for Http {
+	async fn reply(&self, to: MessageIds, content: String) -> Result<MessageIds, SerenityError> {
+		let message = reply(to, self, content).await?;
+		Ok(MessageIds::new(to.guild_id, message.channel_id, message.id))
+	}
+	async fn send(&self, channel: ChannelId, content: String) -> Result<MessageId, SerenityError> {
+		Ok(channel.say(self, content).await?.id)
+	}
+	async fn start_thread(
+		&self,
+		from: MessageIds,
+		name: String,
+	) -> Result<ChannelId, SerenityError> {
+		let thread = from
+			.channel_id
+			.create_thread_from_message(self, from.message_id, CreateThread::new(name))
+			.await?;
+		Ok(thread.id)
+	}
+	async fn edit(&self, message: MessageIds, content: String) -> Result<(), SerenityError> {
+		edit_reply(message, self, content).await
+	}
+	async fn finish(
+		&self,
+		message: MessageIds,
+		content: String,
+		asker: UserId,
+	) -> Result<(), SerenityError> {
+		finish_reply(message, self, content, asker).await
+	}
+}
@@ -1,30 +1,48 @@
 use std::{collections::HashMap, fs, path::Path};
 
-use reqwest::header::HeaderValue;
 use serde::Deserialize;
 use serenity::all::{RoleId, UserId};
 
 use crate::{
-	allowances::{DEFAULT_ACCRUAL_DAYS, DEFAULT_DAILY_ALLOWANCE},
+	allowances::{DEFAULT_ACCRUAL_DAYS, DEFAULT_DAILY_ALLOWANCE, DEFAULT_OVERSPEND_MARGIN},
+	conversations::{ModelPolicy, DEFAULT_EDIT_WINDOW},
 	gpt::GptModel,
+	image_generation::ImageCommand,
 	one_off_response::OneOffCommand,
 	response_styles::{extract_custom, PersonalityPreset},
+	tools::get_tool_by_name,
 };
 
 #[derive(Debug, Clone)]
 pub struct Config {
 	pub daily_allowance: u32,
 	pub accrual_days: f32,
+	pub overspend_margin: u32,
+	pub summarize_old_messages: bool,
+	/// In seconds
+	pub edit_window: u32,
+	pub remove_deleted_messages: bool,
+	pub model_policy: ModelPolicy,
+	pub threads: bool,
+	pub direct_messages: bool,
+	/// In days. Conversations are kept forever without it.
+	pub retention_days: Option<u32>,
 	pub models: Vec<GptModel>,
 	pub search_models: Vec<GptModel>,
 	pub personalities: Vec<PersonalityPreset>,
 	pub one_offs: Vec<OneOffCommand>,
+	pub image_commands: Vec<ImageCommand>,
 	pub prototyping_roles: Vec<RoleId>,
 }
 
 impl Config {
 	pub fn from_file<P: AsRef<Path>>(path: P) -> Self {
-		PartialConfig::from_file(path).into()
+		Self::from_toml(&fs::read_to_string(path).expect("Failed to read config file."))
+	}
+	pub fn from_toml(text: &str) -> Self {
+		toml::from_str::<PartialConfig>(text)
+			.expect("Failed to parse config file.")
+			.into()
 	}
 }
 
@@ -33,17 +51,34 @@ impl From<PartialConfig> for Config {
 		let config = Self {
 			daily_allowance: value.daily_allowance.unwrap_or(DEFAULT_DAILY_ALLOWANCE),
 			accrual_days: value.accrual_days.unwrap_or(DEFAULT_ACCRUAL_DAYS),
+			overspend_margin: value.overspend_margin.unwrap_or(DEFAULT_OVERSPEND_MARGIN),
+			summarize_old_messages: value.summarize_old_messages.unwrap_or(false),
+			edit_window: value.edit_window.unwrap_or(DEFAULT_EDIT_WINDOW),
+			remove_deleted_messages: value.remove_deleted_messages.unwrap_or(false),
+			model_policy: value.model_policy.unwrap_or_default(),
+			threads: value.threads.unwrap_or(false),
+			direct_messages: value.direct_messages.unwrap_or(false),
+			retention_days: value.retention_days,
 			models: value.models.expect("There needs to be at least one model."),
 			search_models: value.search_models.unwrap_or_default(),
 			personalities: value
 				.personalities
 				.expect("There needs to be at least one personality."),
 			one_offs: value.one_offs.unwrap_or_default(),
+			image_commands: value.image_commands.unwrap_or_default(),
 			prototyping_roles: value.prototyping_roles.unwrap_or_default(),
 		};
 		if config.models.is_empty() {
 			panic!("There needs to be at least one model.");
 		}
+		if let Some(model) = config
+			.models
+			.iter()
+			.chain(&config.search_models)
+			.find(|model| !model.has_base_url())
+		{
+			panic!("Model {} needs a base URL for its provider.", model.name());
+		}
 		if config.personalities.is_empty() {
 			panic!("There needs to be at least one personality.");
 		}
@@ -54,6 +89,15 @@ impl From<PartialConfig> for Config {
 		{
 			panic!("Don't name any personality \"custom(whatever)\".");
 		}
+		if let Some(tool) = config
+			.personalities
+			.iter()
+			.flat_map(|personality| personality.tools())
+			.chain(config.one_offs.iter().flat_map(|one_off| one_off.tools()))
+			.find(|tool| get_tool_by_name(tool).is_none())
+		{
+			panic!("There is no tool called \"{tool}\".");
+		}
 		config
 	}
 }
@@ -62,20 +106,23 @@ impl From<PartialConfig> for Config {
 struct PartialConfig {
 	daily_allowance: Option<u32>,
 	accrual_days: Option<f32>,
+	overspend_margin: Option<u32>,
+	summarize_old_messages: Option<bool>,
+	edit_window: Option<u32>,
+	remove_deleted_messages: Option<bool>,
+	model_policy: Option<ModelPolicy>,
+	threads: Option<bool>,
+	direct_messages: Option<bool>,
+	retention_days: Option<u32>,
 	models: Option<Vec<GptModel>>,
 	search_models: Option<Vec<GptModel>>,
 	personalities: Option<Vec<PersonalityPreset>>,
 	one_offs: Option<Vec<OneOffCommand>>,
+	image_commands: Option<Vec<ImageCommand>>,
 	prototyping_roles: Option<Vec<RoleId>>,
 }
 
-impl PartialConfig {
-	fn from_file<P: AsRef<Path>>(path: P) -> Self {
-		toml::from_str(&fs::read_to_string(path).expect("Failed to read config file."))
-			.expect("Failed to parse config file.")
-	}
-}
-
+#[derive(Default)]
 pub struct CustomApiKeys(HashMap<UserId, String>);
 
 impl CustomApiKeys {
@@ -96,18 +143,26 @@ impl CustomApiKeys {
 			.collect();
 		Self(map)
 	}
-	pub fn into_headers(self) -> HashMap<UserId, HeaderValue> {
+	pub fn into_map(self) -> HashMap<UserId, String> {
 		self.0
-			.into_iter()
-			.map(|(user_id, api_key)| {
-				let header =
-					HeaderValue::from_bytes(format!("Bearer {api_key}").as_bytes()).unwrap();
-				(user_id, header)
-			})
-			.collect()
 	}
 }
 
+/// Where to get the API key for a model.
+#[derive(Debug, Clone, Default, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum ApiKeySource {
+	/// The OpenAI API key file the bot uses for everything by default
+	#[default]
+	Default,
+	/// A file containing only the key
+	File(String),
+	/// An environment variable containing the key
+	Env(String),
+	/// No key, like for a server running locally
+	None,
+}
+
 #[derive(Deserialize)]
 struct CustomApiKeyEntry {
 	user: String,
@@ -0,0 +1,150 @@
+use std::fmt::Write;
+
+use serenity::{
+	all::{CommandInteraction, CommandType},
+	builder::CreateCommand,
+	prelude::Context,
+};
+use sqlx::{query, Pool, Sqlite};
+
+use crate::{allowances::Allowance, gpt::Gpt, util::interaction_reply};
+
+/// Tells the user who asked for a reply, which model gave it and what it cost. Works on the prompt as well as the reply.
+pub async fn command_conversation_info(
+	context: Context,
+	interaction: CommandInteraction,
+	executor: &Pool<Sqlite>,
+	gpt: &Gpt,
+) -> Result<(), ()> {
+	let message_id = interaction.data.target_id.ok_or(())?.to_message_id();
+	let message_id = message_id.get() as i64;
+	let channel_id = interaction.channel_id.get() as i64;
+	let record = query!(
+		r#"
+		SELECT
+			conversations.message,
+			conversations.guild,
+			conversations.channel,
+			conversations.parent,
+			conversations.author,
+			conversations.time,
+			conversations.system_message,
+			conversations.model,
+			conversations.input_tokens,
+			conversations.output_tokens,
+			conversations.cost,
+			conversations.finish_reason,
+			conversations.deleted,
+			parents.guild AS parent_guild,
+			parents.channel AS "parent_channel?"
+		FROM
+			conversations
+			LEFT JOIN conversations AS parents ON parents.message = conversations.parent
+		WHERE
+			(conversations.message = ? OR conversations.prompt_message = ?)
+			AND conversations.channel = ?
+		ORDER BY
+			conversations.time DESC
+		LIMIT 1
+		"#,
+		message_id,
+		message_id,
+		channel_id,
+	)
+	.fetch_optional(executor)
+	.await
+	.unwrap();
+	let Some(record) = record else {
+		let _ = interaction_reply(
+			context,
+			interaction,
+			"That message isn't part of a conversation.",
+			true,
+		)
+		.await;
+		return Ok(());
+	};
+
+	let earlier_turns = query!(
+		"
+		WITH RECURSIVE ancestors (message_n, next)
+		AS (
+			SELECT message, parent
+			FROM conversations
+			WHERE message = ?
+			UNION ALL
+			SELECT message, parent
+			FROM ancestors,
+				conversations
+			WHERE message = next
+		)
+		SELECT COUNT(*) - 1 AS count
+		FROM ancestors
+		",
+		record.message,
+	)
+	.fetch_one(executor)
+	.await
+	.unwrap()
+	.count;
+
+	let mut content = String::from("**Conversation info**");
+	if let Some(author) = record.author {
+		write!(content, "\nAsked by <@{author}>").unwrap();
+	}
+	write!(content, " <t:{}:f>", record.time.and_utc().timestamp()).unwrap();
+	if let Some(model) = record.model {
+		let name = gpt
+			.get_model_by_name(&model)
+			.map(|model| model.friendly_name())
+			.unwrap_or(&model);
+		write!(content, "\nModel: {name}").unwrap();
+	}
+	if let Some(personality) = record
+		.system_message
+		.as_deref()
+		.and_then(|name| gpt.get_personality_by_name(name))
+	{
+		write!(content, "\nPersonality: {}", personality.name()).unwrap();
+	}
+	if let (Some(input_tokens), Some(output_tokens)) = (record.input_tokens, record.output_tokens) {
+		write!(content, "\nTokens: {input_tokens} in, {output_tokens} out").unwrap();
+	}
+	if let Some(cost) = record.cost {
+		write!(content, "\nCost: {}", Allowance::Nanodollars(cost)).unwrap();
+	}
+	if let Some(finish_reason) = record.finish_reason {
+		let ending = match finish_reason.as_str() {
+			"stop" => "finished",
+			"length" => "cut off at the length limit",
+			"content_filter" => "cut off by the content filter",
+			reason => reason,
+		};
+		write!(content, "\nThe reply {ending}.").unwrap();
+	}
+	// The turn before can be in another channel, like the one a thread was started from.
+	let (parent_guild, parent_channel) = match record.parent_channel {
+		Some(channel) => (record.parent_guild, channel),
+		None => (record.guild, record.channel),
+	};
+	match record.parent {
+		Some(parent) => write!(
+			content,
+			"\nTurn {} of its conversation, continuing from https://discord.com/channels/{}/{parent_channel}/{parent}",
+			earlier_turns + 1,
+			parent_guild.map_or(String::from("@me"), |guild| guild.to_string()),
+		)
+		.unwrap(),
+		None => content.push_str("\nThe start of its conversation."),
+	}
+	if record.deleted {
+		content.push_str("\nDeleted, so it's left out of the conversation.");
+	}
+
+	let _ = interaction_reply(context, interaction, content, true).await;
+	Ok(())
+}
+
+pub fn register_conversation_info() -> CreateCommand {
+	CreateCommand::new("Conversation info").kind(CommandType::Message)
+}
@@ -0,0 +1,223 @@
+//! Finding past turns by what was said in them.
+
+use std::collections::{hash_map::Entry, HashMap};
+
+use chrono::NaiveDateTime;
+use serenity::{
+	all::{
+		ChannelId, ChannelType, CommandDataOptionValue, CommandInteraction, CommandOptionType,
+		GuildId, Member, MessageId, UserId,
+	},
+	builder::{CreateCommand, CreateCommandOption},
+	constants,
+	prelude::Context,
+};
+use sqlx::{query, Pool, Sqlite};
+
+use crate::{
+	conversations::{permission_channel, MessageIds},
+	util::interaction_reply,
+};
+
+/// How many of the best matches are looked at, before leaving out the ones in channels the user can't view
+const MAX_MATCHES: i64 = 50;
+/// How many matches are shown
+const MAX_RESULTS: usize = 10;
+/// The longest query, so that it and the matches fit in one message
+const MAX_QUERY_LENGTH: u16 = 100;
+
+/// A turn that matched a search
+pub struct SearchResult {
+	/// Of the reply
+	pub ids: MessageIds,
+	pub time: NaiveDateTime,
+	/// The part of the prompt or reply that matched, with the matching words in bold
+	pub snippet: String,
+}
+
+/// Turns the user's words into a full-text query for turns containing all of them. Each word is quoted, so that quotes and operators in them can't make the query invalid.
+fn full_text_query(text: &str) -> Option<String> {
+	let words = text
+		.split_whitespace()
+		.map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+		.collect::<Vec<_>>();
+	(!words.is_empty()).then(|| words.join(" "))
+}
+
+/// The user's turns whose prompt or reply contains all the words, best matches first. Deleted turns are left out.
+pub async fn search_turns(executor: &Pool<Sqlite>, user: UserId, text: &str) -> Vec<SearchResult> {
+	let Some(full_text_query) = full_text_query(text) else {
+		return Vec::new();
+	};
+	let user_id = user.get() as i64;
+	query!(
+		r#"
+		SELECT
+			conversations.message,
+			conversations.guild,
+			conversations.channel,
+			conversations.time,
+			snippet(conversations_search, -1, '**', '**', '…', 16) AS "snippet!: String"
+		FROM
+			conversations_search
+			JOIN conversations ON conversations.message = conversations_search.rowid
+		WHERE
+			conversations_search MATCH ? AND conversations.author = ? AND NOT conversations.deleted
+		ORDER BY
+			rank
+		LIMIT ?
+		"#,
+		full_text_query,
+		user_id,
+		MAX_MATCHES,
+	)
+	.fetch_all(executor)
+	.await
+	.unwrap()
+	.into_iter()
+	.map(|record| SearchResult {
+		ids: MessageIds::new(
+			record.guild.map(|guild| GuildId::new(guild as u64)),
+			ChannelId::new(record.channel as u64),
+			MessageId::new(record.message as u64),
+		),
+		time: record.time,
+		snippet: record.snippet,
+	})
+	.collect()
+}
+
+/// Whether the member can view the channel in the guild right now, as far as the cache knows. Private threads are only visible to those who can manage threads in the parent channel, and to their members, which Discord is asked about.
+async fn can_view(
+	context: &Context,
+	guild_id: GuildId,
+	channel: ChannelId,
+	member: &Member,
+) -> bool {
+	{
+		let Some(guild) = context.cache.guild(guild_id) else {
+			return false;
+		};
+		if let Some(channel) = permission_channel(&guild, channel) {
+			return guild.user_permissions_in(channel, member).view_channel();
+		}
+		let Some(parent) = guild
+			.threads
+			.iter()
+			.find(|thread| thread.id == channel && thread.kind == ChannelType::PrivateThread)
+			.and_then(|thread| thread.parent_id)
+			.and_then(|parent| guild.channels.get(&parent))
+		else {
+			return false;
+		};
+		let permissions = guild.user_permissions_in(parent, member);
+		if !permissions.view_channel() {
+			return false;
+		}
+		if permissions.manage_threads() {
+			return true;
+		}
+	}
+	channel
+		.get_thread_members(&context.http)
+		.await
+		.is_ok_and(|members| {
+			members
+				.iter()
+				.any(|thread_member| thread_member.user_id == member.user.id)
+		})
+}
+
+/// Lists the user's past turns matching the search, with links to them, in channels they can still view.
+pub async fn command_history(
+	context: Context,
+	interaction: CommandInteraction,
+	executor: &Pool<Sqlite>,
+) -> Result<(), ()> {
+	let subcommand = interaction.data.options.first().ok_or(())?;
+	let CommandDataOptionValue::SubCommand(options) = &subcommand.value else {
+		return Err(());
+	};
+	if subcommand.name != "search" {
+		return Err(());
+	}
+	let text = options
+		.iter()
+		.find(|option| option.name == "query")
+		.and_then(|option| option.value.as_str())
+		.ok_or(())?;
+
+	let user = interaction.user.id;
+	let mut members = HashMap::new();
+	if let (Some(guild_id), Some(member)) = (interaction.guild_id, &interaction.member) {
+		members.insert(guild_id, Some(member.as_ref().clone()));
+	}
+	let mut results = Vec::new();
+	for result in search_turns(executor, user, text).await {
+		if results.len() == MAX_RESULTS {
+			break;
+		}
+		let visible = match result.ids.guild_id {
+			// Direct messages with the bot are the user's own.
+			None => true,
+			Some(guild_id) => {
+				if let Entry::Vacant(entry) = members.entry(guild_id) {
+					entry.insert(guild_id.member(&context, user).await.ok());
+				}
+				match &members[&guild_id] {
+					Some(member) => {
+						can_view(&context, guild_id, result.ids.channel_id, member).await
+					}
+					None => false,
+				}
+			}
+		};
+		if visible {
+			results.push(result);
+		}
+	}
+
+	let output = if results.is_empty() {
+		format!("None of your conversations I can show you mention \"{text}\".")
+	} else {
+		let mut output = format!("**Your conversations mentioning \"{text}\"**");
+		for result in results {
+			let (guild_id, channel_id, message_id) = result.ids.as_i64s();
+			let entry = format!(
+				"\n\n<t:{}:d> https://discord.com/channels/{}/{channel_id}/{message_id}\n> {}",
+				result.time.and_utc().timestamp(),
+				guild_id.map_or(String::from("@me"), |guild_id| guild_id.to_string()),
+				result.snippet.replace('\n', " "),
+			);
+			// The best matches that fit in one message are shown.
+			if output.chars().count() + entry.chars().count() > constants::MESSAGE_CODE_LIMIT {
+				break;
+			}
+			output.push_str(&entry);
+		}
+		output
+	};
+	let _ = interaction_reply(context, interaction, output, true).await;
+	Ok(())
+}
+
+pub fn register_history() -> CreateCommand {
+	CreateCommand::new("history")
+		.description("Looks through your past conversations.")
+		.add_option(
+			CreateCommandOption::new(
+				CommandOptionType::SubCommand,
+				"search",
+				"Finds your past conversations mentioning all the words, in channels you can view.",
+			)
+			.add_sub_option(
+				CreateCommandOption::new(
+					CommandOptionType::String,
+					"query",
+					"The words to look for",
+				)
+				.max_length(MAX_QUERY_LENGTH)
+				.required(true),
+			),
+		)
+}
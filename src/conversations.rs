@@ -1,38 +1,246 @@
-use std::sync::Arc;
+use std::{
+	collections::HashSet,
+	sync::Arc,
+	time::{Duration, Instant},
+};
 
+use serde::Deserialize;
 use serenity::{
-	all::{Cache, ChannelId, GuildId},
+	all::{Cache, ChannelId, ChannelType, Guild, GuildChannel, GuildId, UserId},
 	model::prelude::{Message, MessageId},
-	prelude::Context,
+	prelude::SerenityError,
 };
 use sqlx::{query, Pool, Sqlite};
 
 use crate::{
-	allowances::{allowance_and_max, spend_allowance},
-	gpt::{ChatMessage, Gpt},
+	allowances::{
+		allowance_and_max, nanodollars_to_i64, spend_allowance, unaffordable_message, Allowance,
+	},
+	ambient_channels::{get_ambient_channel, start_cooldown},
+	chat::{Chat, IncomingMessage},
+	gpt::{ChatMessage, CompletionStream, Gpt, GptModel, MessageChoice, Sampling, TokenUsage},
+	gpt_error::GptError,
+	history::{attribute, get_exchanges_from_database, Exchange, Trimmed},
+	memories::recall_memories,
 	response_styles::Personality,
-	user_settings::{get_model_setting, get_user_personality},
-	util::{format_chat_message, reply},
+	tools::{self, run_tool_calls, MAX_TOOL_ROUNDS},
+	user_settings::{
+		get_model_policy, get_model_setting, get_sampling_settings, get_user_personality,
+	},
+	util::{format_chat_message, format_partial_chat_message, format_truncated_chat_message},
 };
 
+/// Goes after the personality's system message, since prompts start with the name of who sent them.
+pub const SPEAKERS_NOTE: &str = "Several people may take part in this conversation. Each of their messages starts with their name and a colon.";
+
+/// How often at most a reply is edited while its completion is streaming in.
+const STREAM_EDIT_INTERVAL: Duration = Duration::from_millis(1500);
+/// How long after asking, in seconds, editing the message asks again, by default.
+pub const DEFAULT_EDIT_WINDOW: u32 = 120;
+
+/// Which model a reply continuing a conversation uses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ModelPolicy {
+	/// The model of the turn being replied to, whoever is replying
+	Sticky,
+	/// The replying user's own model
+	#[default]
+	Replier,
+	/// The model of the turn being replied to if the same user asked for it, since they chose it and pay for it. Anyone else replying uses their own model.
+	AskerPaysSticky,
+}
+
+impl ModelPolicy {
+	pub const ALL: [Self; 3] = [Self::Sticky, Self::Replier, Self::AskerPaysSticky];
+
+	/// How it's written in the config file and stored in the database.
+	pub fn name(self) -> &'static str {
+		match self {
+			Self::Sticky => "sticky",
+			Self::Replier => "replier",
+			Self::AskerPaysSticky => "asker-pays-sticky",
+		}
+	}
+	pub fn from_name(name: &str) -> Option<Self> {
+		Self::ALL.into_iter().find(|policy| policy.name() == name)
+	}
+	pub fn description(self) -> &'static str {
+		match self {
+			Self::Sticky => "Replies continue with the model the conversation was on",
+			Self::Replier => "Replies use the replier's own model",
+			Self::AskerPaysSticky => {
+				"Your own follow-ups stay on the same model, others' use their own"
+			}
+		}
+	}
+}
+
+/// Where a new reply is sent
+#[derive(Clone, Copy)]
+enum Placement {
+	/// As a reply to the message
+	Reply,
+	/// In a new thread started from the message
+	NewThread,
+	/// In a thread that was started from the message, without replying to it, since it's outside the thread
+	Thread(ChannelId),
+}
+
+/// A prompt to reply to, and where the reply goes in the conversation
+struct Turn {
+	/// The message the reply goes to
+	message: IncomingMessage,
+	prompt: ChatMessage,
+	parent: Option<MessageIds>,
+	/// The personality to start a new conversation with, instead of the author's own
+	personality: Option<String>,
+	/// A reply of the bot's to edit into the new reply and replace in the database, instead of sending a new one
+	replacing: Option<MessageIds>,
+	placement: Placement,
+}
+
 impl Gpt {
 	/// Start or continue a conversation, based on the presence of `parent`.
 	pub async fn query(
 		&self,
 		executor: &Pool<Sqlite>,
-		context: Context,
+		chat: &impl Chat,
 		input: String,
-		message: Message,
+		images: Vec<String>,
+		message: IncomingMessage,
 		parent: Option<MessageIds>,
 	) {
-		let custom_authorization_header = self.custom_authorization_header(message.author.id);
+		let turn = Turn {
+			message,
+			prompt: ChatMessage::user_with_images(input, images),
+			parent,
+			personality: None,
+			replacing: None,
+			placement: Placement::Reply,
+		};
+		self.respond(executor, chat, turn).await;
+	}
+
+	/// Start a conversation in a new thread from the message, so that messages in the thread continue it.
+	pub async fn query_in_new_thread(
+		&self,
+		executor: &Pool<Sqlite>,
+		chat: &impl Chat,
+		input: String,
+		images: Vec<String>,
+		message: IncomingMessage,
+	) {
+		let turn = Turn {
+			message,
+			prompt: ChatMessage::user_with_images(input, images),
+			parent: None,
+			personality: None,
+			replacing: None,
+			placement: Placement::NewThread,
+		};
+		self.respond(executor, chat, turn).await;
+	}
+
+	/// Reply again to the prompt that `reply` was the reply to, as a sibling of `reply` in the conversation. The new reply is sent as a reply to the prompt, and charged to `asker`.
+	pub async fn regenerate(
+		&self,
+		executor: &Pool<Sqlite>,
+		chat: &impl Chat,
+		asker: UserId,
+		asker_name: String,
+		reply: MessageIds,
+	) {
+		let Some(stored) = get_stored_prompt(executor, reply).await else {
+			return;
+		};
+		// Turns from before prompts were stored are replied to themselves.
+		let prompt_message = stored.prompt_message.unwrap_or(reply.message_id);
+		// A thread started from the prompt has the prompt's ID, and the prompt is outside it.
+		let placement = if prompt_message.get() == reply.channel_id.get() {
+			Placement::Thread(reply.channel_id)
+		} else {
+			Placement::Reply
+		};
+		let message = IncomingMessage {
+			ids: MessageIds::new(reply.guild_id, reply.channel_id, prompt_message),
+			author: asker,
+			author_name: asker_name,
+		};
+		let turn = Turn {
+			message,
+			prompt: stored.prompt,
+			parent: stored.parent,
+			personality: stored.personality,
+			replacing: None,
+			placement,
+		};
+		self.respond(executor, chat, turn).await;
+	}
+
+	/// Reply to `message` again after it was edited, replacing `reply`, the earlier reply to it. Nothing happens if the prompt stayed the same.
+	pub async fn rerun(
+		&self,
+		executor: &Pool<Sqlite>,
+		chat: &impl Chat,
+		input: String,
+		images: Vec<String>,
+		message: IncomingMessage,
+		reply: MessageIds,
+	) {
+		let Some(stored) = get_stored_prompt(executor, reply).await else {
+			return;
+		};
+		if stored.prompt.content == input && stored.prompt.images == images {
+			return;
+		}
+		let turn = Turn {
+			message,
+			prompt: ChatMessage::user_with_images(input, images),
+			parent: stored.parent,
+			personality: stored.personality,
+			replacing: Some(reply),
+			placement: Placement::Reply,
+		};
+		self.respond(executor, chat, turn).await;
+	}
+
+	/// Replies to the prompt of the turn, continuing from its parent if there is one.
+	async fn respond(&self, executor: &Pool<Sqlite>, chat: &impl Chat, turn: Turn) {
+		let Turn {
+			message,
+			prompt,
+			parent,
+			personality,
+			replacing,
+			placement,
+		} = turn;
+		// Ambient channels can have their own personality and model.
+		let ambient_channel = get_ambient_channel(executor, message.ids.channel_id).await;
+		if ambient_channel.is_some() {
+			start_cooldown(executor, message.ids.channel_id, message.author).await;
+		}
+		let (channel_personality, channel_model) = ambient_channel
+			.map(|channel| (channel.personality, channel.model))
+			.unwrap_or_default();
+		let model = self
+			.choose_model(executor, message.author, parent, channel_model)
+			.await;
+
+		if prompt.content.is_empty() && !model.supports_images() {
+			let reply = format!("{} can't see images.", model.friendly_name());
+			let _ = chat.reply(message.ids, reply).await;
+			return;
+		}
+
+		let custom_api_key = self.custom_api_key(message.author, model);
 
 		let (allowance, max_allowance) = allowance_and_max(
 			executor,
-			message.author.id,
+			message.author,
 			self.daily_allowance(),
 			self.accrual_days(),
-			custom_authorization_header.is_some(),
+			custom_api_key.is_some(),
 		)
 		.await;
 		if allowance.is_out() {
@@ -40,13 +248,31 @@ impl Gpt {
 				"You are out of allowance. ({}/{})",
 				allowance, max_allowance
 			);
-			message.reply(context.http, reply).await.unwrap();
+			let _ = chat.reply(message.ids, reply).await;
 			return;
 		}
 
-		let (history, personality) = if let Some(parent_id) = parent {
+		let api_key = custom_api_key.or(self.api_key(model));
+		let sampling = get_sampling_settings(executor, message.author).await;
+		let memories = recall_memories(
+			executor,
+			message.author,
+			&message.author_name,
+			&prompt.content,
+		)
+		.await;
+		let (mut history, personality, trimmed, mut usage) = if let Some(parent_id) = parent {
 			let Some(values) = self
-				.continue_conversation(executor, parent_id, &input)
+				.continue_conversation(
+					executor,
+					parent_id,
+					attribute(prompt.clone(), Some(&message.author_name)),
+					memories.as_deref(),
+					model,
+					api_key,
+					sampling,
+					&allowance,
+				)
 				.await
 			else {
 				// Parent not found.
@@ -54,175 +280,334 @@ impl Gpt {
 			};
 			values
 		} else {
-			self.start_conversation(executor, &message, &input).await
+			let (history, personality) = self
+				.start_conversation(
+					executor,
+					message.author,
+					attribute(prompt.clone(), Some(&message.author_name)),
+					memories.as_deref(),
+					personality.or(channel_personality),
+				)
+				.await;
+			(
+				history,
+				personality,
+				Trimmed::Nothing,
+				TokenUsage::default(),
+			)
 		};
+		if model.supports_images() {
+			self.inline_images(&mut history).await;
+		} else {
+			history
+				.iter_mut()
+				.for_each(|message| message.images.clear());
+		}
 
-		let model = get_model_setting(executor, message.author.id)
-			.await
-			.and_then(|name| {
-				let model = self.get_model_by_name(&name);
-				if model.is_none() {
-					println!("Warning: could not get model by name of {name}.");
-				}
-				model
-			})
-			.unwrap_or(self.default_model());
+		let tools = tools::definitions(personality.tools());
 
-		let authorization_header =
-			custom_authorization_header.unwrap_or(self.authorization_header());
+		let author = message.author;
+		let is_allowance_infinite = custom_api_key.is_some();
 
-		let response = match self
-			.send(
-				&history,
-				model.name(),
-				model.api_version(),
-				authorization_header,
-			)
+		let worst_case_cost = model.get_worst_case_cost(&history, &tools, sampling);
+		if !allowance.can_afford(worst_case_cost, self.overspend_margin()) {
+			let reply = unaffordable_message(worst_case_cost, &allowance);
+			let _ = chat.reply(message.ids, reply).await;
+			self.charge_unfinished_reply(executor, author, usage, model, is_allowance_infinite)
+				.await;
+			return;
+		}
+
+		let mut stream = match self
+			.send_streaming(&history, model, api_key, &tools, sampling)
 			.await
 		{
-			Ok(response) => response,
-			Err(error_message) => {
-				message.reply(context.http, error_message).await.unwrap();
+			Ok(stream) => stream,
+			Err(error) => {
+				let _ = chat
+					.reply(message.ids, error.user_message().to_string())
+					.await;
+				self.charge_unfinished_reply(executor, author, usage, model, is_allowance_infinite)
+					.await;
+				return;
+			}
+		};
+
+		let emoji = personality.emoji();
+
+		let partial = format_partial_chat_message("", emoji);
+		let own_message = match (replacing, placement) {
+			(Some(own_message), _) => chat.edit(own_message, partial).await.map(|_| own_message),
+			(None, Placement::Reply) => chat.reply(message.ids, partial).await,
+			(None, Placement::NewThread) => {
+				reply_in_new_thread(executor, chat, message.ids, &prompt.content, partial).await
+			}
+			(None, Placement::Thread(thread)) => chat
+				.send(thread, partial)
+				.await
+				.map(|reply| MessageIds::new(message.ids.guild_id, thread, reply)),
+		};
+		let Ok(own_message) = own_message else {
+			self.charge_unfinished_reply(executor, author, usage, model, is_allowance_infinite)
+				.await;
+			return;
+		};
+		let mut round = 1;
+		let response = loop {
+			if let Err(error) = stream_into_message(&mut stream, chat, own_message, emoji).await {
+				// Nothing is stored for a completion that never finished, and its usage isn't known, but what came before it is charged.
+				eprintln!("Completion stream interrupted: {error}");
+				let truncated = format_truncated_chat_message(stream.content(), emoji);
+				let _ = chat.edit(own_message, truncated).await;
+				self.charge_unfinished_reply(executor, author, usage, model, is_allowance_infinite)
+					.await;
+				return;
+			}
+			let response = stream.into_response();
+			usage += response.usage;
+			let message = &response.message_choices[0].message;
+			if message.tool_calls.is_empty() {
+				break response;
+			}
+			history.push(message.clone());
+			history.extend(run_tool_calls(&message.tool_calls, personality.tools()));
+			round += 1;
+			let tools = if round < MAX_TOOL_ROUNDS {
+				tools.as_slice()
+			} else {
+				&[]
+			};
+			// Each round sends the whole history again, so it's only sent if the rounds before and it could still be afforded together.
+			let worst_case_cost = model
+				.get_cost(usage)
+				.saturating_add(model.get_worst_case_cost(&history, tools, sampling));
+			if !allowance.can_afford(worst_case_cost, self.overspend_margin()) {
+				let reply = unaffordable_message(worst_case_cost, &allowance);
+				let _ = chat.edit(own_message, reply).await;
+				self.charge_unfinished_reply(executor, author, usage, model, is_allowance_infinite)
+					.await;
 				return;
 			}
+			stream = match self
+				.send_streaming(&history, model, api_key, tools, sampling)
+				.await
+			{
+				Ok(stream) => stream,
+				Err(error) => {
+					let _ = chat
+						.edit(own_message, error.user_message().to_string())
+						.await;
+					self.charge_unfinished_reply(
+						executor,
+						author,
+						usage,
+						model,
+						is_allowance_infinite,
+					)
+					.await;
+					return;
+				}
+			};
 		};
 
 		let (allowance, cost) = spend_allowance(
 			executor,
-			message.author.id,
-			response.usage,
+			author,
+			usage,
 			model,
 			self.daily_allowance(),
 			self.accrual_days(),
-			custom_authorization_header.is_some(),
+			is_allowance_infinite,
 		)
 		.await;
 
-		let guild_id = message.guild_id.unwrap();
-
 		let full_reply = format_chat_message(
 			&response.message_choices[0],
-			personality.emoji(),
+			emoji,
 			cost,
 			allowance,
 			(model.name() != self.default_model().name()).then_some(model),
+			trimmed.note().as_deref(),
 		);
-		let output = &response.message_choices[0].message.content;
-		let own_message = reply(message, &context.http, full_reply).await.unwrap();
+		let _ = chat.finish(own_message, full_reply, author).await;
 
-		if let Some(parent) = parent {
-			store_child_message(
-				executor,
-				&own_message,
-				guild_id,
-				parent,
-				&input,
-				output,
-				personality,
-			)
-			.await;
+		let reply = Reply {
+			message: own_message,
+			choice: &response.message_choices[0],
+			model,
+			usage,
+		};
+		if replacing.is_some() {
+			replace_message(executor, &message, &prompt, &reply).await;
 		} else {
-			store_root_message(
-				executor,
-				&own_message,
-				guild_id,
-				&input,
-				output,
-				personality,
-			)
-			.await;
+			store_message(executor, &message, parent, &prompt, personality, &reply).await;
+		}
+	}
+
+	/// Charges the user for what was already used on a reply that failed partway, like summarizing the history or the rounds of tool calls before.
+	async fn charge_unfinished_reply(
+		&self,
+		executor: &Pool<Sqlite>,
+		user: UserId,
+		usage: TokenUsage,
+		model: &GptModel,
+		is_allowance_infinite: bool,
+	) {
+		if usage.requests == 0 {
+			return;
+		}
+		spend_allowance(
+			executor,
+			user,
+			usage,
+			model,
+			self.daily_allowance(),
+			self.accrual_days(),
+			is_allowance_infinite,
+		)
+		.await;
+	}
+
+	/// The model to reply to the user with: the channel's, else the user's own. Continuing a conversation follows the model policy of the user, else of the guild, else of the config.
+	async fn choose_model(
+		&self,
+		executor: &Pool<Sqlite>,
+		user: UserId,
+		parent: Option<MessageIds>,
+		channel_model: Option<String>,
+	) -> &GptModel {
+		let own_model = match channel_model {
+			Some(name) => Some(name),
+			None => get_model_setting(executor, user).await,
+		};
+		let own_model = own_model
+			.and_then(|name| {
+				let model = self.get_model_by_name(&name);
+				if model.is_none() {
+					println!("Warning: could not get model by name of {name}.");
+				}
+				model
+			})
+			.unwrap_or(self.default_model());
+		let Some(parent) = parent else {
+			return own_model;
+		};
+		let policy = get_model_policy(executor, user, parent.guild_id)
+			.await
+			.unwrap_or(self.model_policy());
+		if policy == ModelPolicy::Replier {
+			return own_model;
+		}
+		let Some((parent_model, parent_author)) = get_turn_model(executor, parent).await else {
+			return own_model;
+		};
+		if policy == ModelPolicy::AskerPaysSticky && parent_author != Some(user) {
+			return own_model;
 		}
+		// Turns from before models were stored, or on models since removed, don't have one to stick to.
+		parent_model
+			.and_then(|name| self.get_model_by_name(&name))
+			.unwrap_or(own_model)
 	}
 
-	/// Start a new conversation.
+	/// Start a new conversation, with the given personality or else the user's, and what the bot remembers about the user.
 	async fn start_conversation(
 		&'_ self,
 		executor: &Pool<Sqlite>,
-		message: &Message,
-		input: &str,
+		user: UserId,
+		prompt: ChatMessage,
+		memories: Option<&str>,
+		personality: Option<String>,
 	) -> (Vec<ChatMessage>, Personality<'_>) {
-		let personality = get_user_personality(executor, message.author.id)
-			.await
+		let personality = match personality {
+			Some(personality) => Some(personality),
+			None => get_user_personality(executor, user).await,
+		};
+		let personality = personality
 			.and_then(|name| self.get_personality_by_name(&name))
 			.unwrap_or(Personality::Preset(self.default_personality()));
-		let history = [
-			ChatMessage::system(personality.system_message().to_string()),
-			ChatMessage::user(input.to_string()),
-		]
-		.to_vec();
+		let history = vec![system_message(&personality, memories), prompt];
 		(history, personality)
 	}
 
-	/// Attempt to continue an existing conversation from a reply.
+	/// Attempt to continue an existing conversation from a reply, with as much of the history as fits in the model's context window. Also returns what was left out, and the tokens spent on summarizing it, which is only done if the allowance covers it and the reply.
+	#[allow(clippy::too_many_arguments)]
 	async fn continue_conversation(
 		&'_ self,
 		executor: &Pool<Sqlite>,
 		parent: MessageIds,
-		input: &str,
-	) -> Option<(Vec<ChatMessage>, Personality<'_>)> {
+		mut prompt: ChatMessage,
+		memories: Option<&str>,
+		model: &GptModel,
+		api_key: Option<&str>,
+		sampling: Sampling,
+		allowance: &Allowance,
+	) -> Option<(Vec<ChatMessage>, Personality<'_>, Trimmed, TokenUsage)> {
 		let personality = get_message_personality(executor, parent)
 			.await
 			.and_then(|per| self.get_personality_by_name(&per))
 			.unwrap_or(Personality::Preset(self.default_personality()));
-		let mut history =
-			get_history_from_database(executor, parent, personality.system_message().to_string())
-				.await;
-		if history.len() == 1 {
+		let mut exchanges = get_exchanges_from_database(executor, parent).await;
+		if exchanges.is_empty() {
 			// Found no actual history, so ignore this message. This most typically happens when replying to a bot message that was not a GPT response, like an error message.
 			return None;
 		}
-		history.push(ChatMessage::user(input.to_string()));
-		Some((history, personality))
+		if !model.supports_images() {
+			exchanges.iter_mut().for_each(Exchange::clear_images);
+			prompt.images.clear();
+		}
+		let system_message = system_message(&personality, memories);
+		let tools = tools::definitions(personality.tools());
+		let prompt_tokens = model
+			.tokenizer()
+			.estimate_prompt_tokens(&[system_message.clone(), prompt.clone()], &tools);
+		let reply_tokens = model.reply_tokens(sampling);
+		let can_afford = |history_tokens: u32, summary_cost: u64| {
+			let reply_cost = model.get_cost(TokenUsage {
+				prompt_tokens: prompt_tokens + history_tokens,
+				completion_tokens: reply_tokens,
+				requests: 1,
+				..Default::default()
+			});
+			allowance.can_afford(
+				reply_cost.saturating_add(summary_cost),
+				self.overspend_margin(),
+			)
+		};
+		let (fitted, trimmed, usage) = self
+			.fit_history(
+				executor,
+				exchanges,
+				model,
+				api_key,
+				prompt_tokens + reply_tokens,
+				can_afford,
+			)
+			.await;
+		let history = std::iter::once(system_message)
+			.chain(fitted)
+			.chain(std::iter::once(prompt))
+			.collect();
+		Some((history, personality, trimmed, usage))
 	}
 }
 
-async fn get_history_from_database(
-	executor: &Pool<Sqlite>,
-	parent: MessageIds,
-	system_message: String,
-) -> Vec<ChatMessage> {
-	let (guild_id, channel_id, message_id) = parent.as_i64s();
-	let stored_history = query!(
-		"
-		WITH RECURSIVE chain (
-			next,
-			input_n,
-			output_n
-		)
-		AS (
-			SELECT parent,
-				input,
-				output
-			FROM conversations
-			WHERE message = ? AND channel = ? AND guild = ?
-			UNION ALL
-			SELECT parent,
-				input,
-				output
-			FROM chain,
-				conversations
-			WHERE message = next
-			LIMIT 20
-		)
-		SELECT input_n AS input,
-			output_n AS output
-		FROM chain;
-		",
-		message_id,
-		channel_id,
-		guild_id
-	)
-	.fetch_all(executor)
-	.await
-	.unwrap();
-	std::iter::once(ChatMessage::system(system_message))
-		.chain(stored_history.into_iter().rev().flat_map(|record| {
-			[
-				ChatMessage::user(record.input),
-				ChatMessage::assistant(record.output),
-			]
-		}))
-		.collect()
+/// Streams a completion into the message as it arrives, editing it at most every `STREAM_EDIT_INTERVAL`.
+async fn stream_into_message(
+	stream: &mut CompletionStream,
+	chat: &impl Chat,
+	own_message: MessageIds,
+	emoji: &str,
+) -> Result<(), GptError> {
+	let mut last_edit = Instant::now();
+	while stream.advance().await? {
+		if last_edit.elapsed() >= STREAM_EDIT_INTERVAL {
+			let partial = format_partial_chat_message(stream.content(), emoji);
+			let _ = chat.edit(own_message, partial).await;
+			last_edit = Instant::now();
+		}
+	}
+	Ok(())
 }
 
 async fn get_message_personality(executor: &Pool<Sqlite>, parent: MessageIds) -> Option<String> {
@@ -234,7 +619,7 @@ async fn get_message_personality(executor: &Pool<Sqlite>, parent: MessageIds) ->
 		FROM
 			conversations
 		WHERE
-			message = ? AND channel = ? AND guild = ?
+			message = ? AND channel = ? AND guild IS ?
 		",
 		message_id,
 		channel_id,
@@ -246,99 +631,465 @@ async fn get_message_personality(executor: &Pool<Sqlite>, parent: MessageIds) ->
 	.and_then(|record| record.system_message)
 }
 
-async fn store_root_message(
+/// The personality's system message, followed by what the bot remembers about whoever sent the prompt.
+fn system_message(personality: &Personality, memories: Option<&str>) -> ChatMessage {
+	let mut content = format!("{}\n\n{SPEAKERS_NOTE}", personality.system_message());
+	if let Some(memories) = memories {
+		content.push_str("\n\n");
+		content.push_str(memories);
+	}
+	ChatMessage::system(content)
+}
+
+/// The prompt a reply was to, with what it needs to be replied to again
+struct StoredPrompt {
+	prompt: ChatMessage,
+	/// Absent for turns from before prompts were stored
+	prompt_message: Option<MessageId>,
+	parent: Option<MessageIds>,
+	personality: Option<String>,
+}
+
+async fn get_stored_prompt(executor: &Pool<Sqlite>, reply: MessageIds) -> Option<StoredPrompt> {
+	let (guild_id, channel_id, message_id) = reply.as_i64s();
+	let record = query!(
+		"
+		SELECT
+			child.input,
+			child.prompt_message,
+			child.attachments,
+			child.system_message,
+			parent.message AS parent_message,
+			parent.channel AS parent_channel
+		FROM
+			conversations AS child
+			LEFT JOIN conversations AS parent ON parent.message = child.parent
+		WHERE
+			child.message = ? AND child.channel = ? AND child.guild IS ?
+		",
+		message_id,
+		channel_id,
+		guild_id,
+	)
+	.fetch_optional(executor)
+	.await
+	.unwrap()?;
+	let images = record
+		.attachments
+		.and_then(|attachments| serde_json::from_str(&attachments).ok())
+		.unwrap_or_default();
+	let parent = record
+		.parent_message
+		.zip(record.parent_channel)
+		.map(|(message, channel)| {
+			MessageIds::new(
+				reply.guild_id,
+				ChannelId::new(channel as u64),
+				MessageId::new(message as u64),
+			)
+		});
+	Some(StoredPrompt {
+		prompt: ChatMessage::user_with_images(record.input, images),
+		prompt_message: record
+			.prompt_message
+			.map(|message| MessageId::new(message as u64)),
+		parent,
+		personality: record.system_message,
+	})
+}
+
+/// The latest reply to the user's message, if it was sent at most `window` seconds ago.
+pub async fn get_recent_reply(
+	executor: &Pool<Sqlite>,
+	prompt_message: MessageIds,
+	window: u32,
+) -> Option<MessageIds> {
+	let (guild_id, _, prompt_message_id) = prompt_message.as_i64s();
+	let earliest = format!("-{window} seconds");
+	let record = query!(
+		"
+		SELECT
+			message,
+			channel
+		FROM
+			conversations
+		WHERE
+			prompt_message = ? AND guild IS ? AND time >= datetime('now', ?) AND NOT deleted
+		ORDER BY
+			time DESC,
+			message DESC
+		LIMIT 1
+		",
+		prompt_message_id,
+		guild_id,
+		earliest,
+	)
+	.fetch_optional(executor)
+	.await
+	.unwrap()?;
+	Some(MessageIds::new(
+		prompt_message.guild_id,
+		ChannelId::new(record.channel as u64),
+		MessageId::new(record.message as u64),
+	))
+}
+
+/// A finished reply of the bot's, and what it took
+struct Reply<'a> {
+	message: MessageIds,
+	choice: &'a MessageChoice,
+	model: &'a GptModel,
+	/// Everything the reply took, including tool rounds and summarizing
+	usage: TokenUsage,
+}
+
+async fn store_message(
 	executor: &Pool<Sqlite>,
-	message: &Message,
-	guild_id: GuildId,
-	input: &str,
-	output: &str,
+	message: &IncomingMessage,
+	parent: Option<MessageIds>,
+	input: &ChatMessage,
 	personality: Personality<'_>,
+	reply: &Reply<'_>,
 ) {
-	let message_id = message.id.get() as i64;
-	let channel_id = message.channel_id.get() as i64;
-	let guild_id = guild_id.get() as i64;
+	let (guild_id, channel_id, message_id) = reply.message.as_i64s();
+	let prompt_message_id = message.ids.message_id.get() as i64;
+	let author_id = message.author.get() as i64;
+	let parent_id = parent.map(|parent| parent.message_id.get() as i64);
 	let system_message = personality.database_name();
+	let attachments = attachments_to_json(&input.images);
+	let model = reply.model.name();
+	let cost = nanodollars_to_i64(reply.model.get_cost(reply.usage));
 	query!(
 		"
 		INSERT INTO
-			conversations (message, channel, guild, input, output, system_message)
+			conversations (message, channel, guild, prompt_message, author, author_name, parent, input, output, system_message, attachments, model, input_tokens, output_tokens, cost, finish_reason)
 		VALUES
-			(?, ?, ?, ?, ?, ?)
+			(?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
 		",
 		message_id,
 		channel_id,
 		guild_id,
-		input,
-		output,
+		prompt_message_id,
+		author_id,
+		message.author_name,
+		parent_id,
+		input.content,
+		reply.choice.message.content,
 		system_message,
+		attachments,
+		model,
+		reply.usage.prompt_tokens,
+		reply.usage.completion_tokens,
+		cost,
+		reply.choice.finish_reason,
+	)
+	.execute(executor)
+	.await
+	.unwrap();
+
+	query!(
+		"UPDATE threads SET latest = ? WHERE thread = ?",
+		message_id,
+		channel_id,
 	)
 	.execute(executor)
 	.await
 	.unwrap();
 }
 
-async fn store_child_message(
+/// Starts a thread from the message and sends the first reply there, or replies to the message if the thread can't be started.
+async fn reply_in_new_thread(
 	executor: &Pool<Sqlite>,
-	message: &Message,
-	guild_id: GuildId,
-	parent: MessageIds,
-	input: &str,
-	output: &str,
-	personality: Personality<'_>,
+	chat: &impl Chat,
+	message: MessageIds,
+	prompt: &str,
+	content: String,
+) -> Result<MessageIds, SerenityError> {
+	let Some(guild) = message.guild_id else {
+		return chat.reply(message, content).await;
+	};
+	let Ok(thread) = chat.start_thread(message, thread_name(prompt)).await else {
+		return chat.reply(message, content).await;
+	};
+	let guild_id = guild.get() as i64;
+	let thread_id = thread.get() as i64;
+	query!(
+		"INSERT INTO threads (thread, guild) VALUES (?, ?)",
+		thread_id,
+		guild_id,
+	)
+	.execute(executor)
+	.await
+	.unwrap();
+	let reply = chat.send(thread, content).await?;
+	Ok(MessageIds::new(message.guild_id, thread, reply))
+}
+
+/// The first line of the prompt, cut to the 100 characters a thread name can have.
+fn thread_name(prompt: &str) -> String {
+	let first_line = prompt.lines().next().unwrap_or_default().trim();
+	if first_line.is_empty() {
+		String::from("Conversation")
+	} else {
+		first_line.chars().take(100).collect()
+	}
+}
+
+/// If the channel is a thread the bot started for a conversation, the turn that messages in it continue from, if there's one left.
+pub async fn get_thread_conversation(
+	executor: &Pool<Sqlite>,
+	thread: ChannelId,
+) -> Option<Option<MessageIds>> {
+	let thread_id = thread.get() as i64;
+	query!(
+		"
+		SELECT guild, latest
+		FROM threads
+		WHERE thread = ?
+		",
+		thread_id,
+	)
+	.fetch_optional(executor)
+	.await
+	.unwrap()
+	.map(|record| {
+		record.latest.map(|latest| {
+			MessageIds::new(
+				Some(GuildId::new(record.guild as u64)),
+				thread,
+				MessageId::new(latest as u64),
+			)
+		})
+	})
+}
+
+/// Replaces the input and output of an existing reply.
+async fn replace_message(
+	executor: &Pool<Sqlite>,
+	message: &IncomingMessage,
+	input: &ChatMessage,
+	reply: &Reply<'_>,
 ) {
-	let message_id = message.id.get() as i64;
-	let channel_id = message.channel_id.get() as i64;
-	let guild_id = guild_id.get() as i64;
-	let parent_id = parent.message_id.get() as i64;
-	let system_message = personality.database_name();
+	let (guild_id, channel_id, message_id) = reply.message.as_i64s();
+	let prompt_message_id = message.ids.message_id.get() as i64;
+	let author_id = message.author.get() as i64;
+	let attachments = attachments_to_json(&input.images);
+	let model = reply.model.name();
+	let cost = nanodollars_to_i64(reply.model.get_cost(reply.usage));
 	query!(
 		"
-		INSERT INTO
-			conversations (message, channel, guild, parent, input, output, system_message)
-		VALUES
-			(?, ?, ?, ?, ?, ?, ?)
+		UPDATE conversations
+		SET prompt_message = ?, author = ?, author_name = ?, input = ?, output = ?, attachments = ?, model = ?, input_tokens = ?, output_tokens = ?, cost = ?, finish_reason = ?
+		WHERE message = ? AND channel = ? AND guild IS ?
 		",
+		prompt_message_id,
+		author_id,
+		message.author_name,
+		input.content,
+		reply.choice.message.content,
+		attachments,
+		model,
+		reply.usage.prompt_tokens,
+		reply.usage.completion_tokens,
+		cost,
+		reply.choice.finish_reason,
 		message_id,
 		channel_id,
 		guild_id,
-		parent_id,
-		input,
-		output,
-		system_message,
 	)
 	.execute(executor)
 	.await
 	.unwrap();
+	forget_summaries_from(executor, message_id).await;
+}
+
+/// Drops the summaries of the turn and every turn after it, as they may include what it used to say. They're made again when needed.
+async fn forget_summaries_from(executor: &Pool<Sqlite>, message_id: i64) {
+	query!(
+		"
+		WITH RECURSIVE descendants (message_n)
+		AS (
+			SELECT ?
+			UNION ALL
+			SELECT message
+			FROM descendants,
+				conversations
+			WHERE parent = message_n
+		)
+		UPDATE conversations
+		SET summary = NULL
+		WHERE message IN descendants AND summary IS NOT NULL
+		",
+		message_id,
+	)
+	.execute(executor)
+	.await
+	.unwrap();
+}
+
+/// Leaves the turns whose prompt or reply is one of the messages out of conversations from now on, by marking them as deleted or removing them altogether.
+pub async fn delete_turns(
+	executor: &Pool<Sqlite>,
+	channel: ChannelId,
+	messages: &[MessageId],
+	remove: bool,
+) {
+	let channel_id = channel.get() as i64;
+	let mut channels = HashSet::from([channel_id]);
+	for message in messages {
+		let message_id = message.get() as i64;
+		// A prompt can be in another channel than the reply, like the one a thread was started from.
+		let turns = query!(
+			"
+			SELECT message, channel
+			FROM conversations
+			WHERE ((message = ? AND channel = ?) OR prompt_message = ?) AND NOT deleted
+			",
+			message_id,
+			channel_id,
+			message_id,
+		)
+		.fetch_all(executor)
+		.await
+		.unwrap();
+		for turn in turns {
+			channels.insert(turn.channel);
+			forget_summaries_from(executor, turn.message).await;
+			if remove {
+				query!("DELETE FROM conversations WHERE message = ?", turn.message)
+					.execute(executor)
+					.await
+					.unwrap();
+			} else {
+				query!(
+					"UPDATE conversations SET deleted = TRUE WHERE message = ?",
+					turn.message
+				)
+				.execute(executor)
+				.await
+				.unwrap();
+			}
+		}
+	}
+
+	// A conversation thread continues from the latest turn that's left.
+	for channel_id in channels {
+		query!(
+			"
+			UPDATE threads
+			SET latest = (
+				SELECT message
+				FROM conversations
+				WHERE channel = thread AND NOT deleted
+				ORDER BY message DESC
+				LIMIT 1
+			)
+			WHERE thread = ?
+			",
+			channel_id,
+		)
+		.execute(executor)
+		.await
+		.unwrap();
+	}
+}
+
+/// Which model gave the reply and who asked for it, if it's a stored turn.
+async fn get_turn_model(
+	executor: &Pool<Sqlite>,
+	reply: MessageIds,
+) -> Option<(Option<String>, Option<UserId>)> {
+	let (guild_id, channel_id, message_id) = reply.as_i64s();
+	query!(
+		"
+		SELECT model, author
+		FROM conversations
+		WHERE message = ? AND channel = ? AND guild IS ?
+		",
+		message_id,
+		channel_id,
+		guild_id,
+	)
+	.fetch_optional(executor)
+	.await
+	.unwrap()
+	.map(|record| {
+		(
+			record.model,
+			record.author.map(|author| UserId::new(author as u64)),
+		)
+	})
+}
+
+/// Who asked for the reply, if it's one of the bot's that is still around.
+pub async fn get_reply_author(executor: &Pool<Sqlite>, reply: MessageIds) -> Option<UserId> {
+	let (guild_id, channel_id, message_id) = reply.as_i64s();
+	query!(
+		"
+		SELECT author
+		FROM conversations
+		WHERE message = ? AND channel = ? AND guild IS ? AND NOT deleted
+		",
+		message_id,
+		channel_id,
+		guild_id,
+	)
+	.fetch_optional(executor)
+	.await
+	.unwrap()?
+	.author
+	.map(|author| UserId::new(author as u64))
+}
+
+/// Attachments are stored as a JSON array of URLs, or `NULL` if there are none.
+fn attachments_to_json(images: &[String]) -> Option<String> {
+	(!images.is_empty()).then(|| serde_json::to_string(images).unwrap())
 }
 
 #[derive(Debug, Clone, Copy)]
 pub struct MessageIds {
-	pub guild_id: GuildId,
+	/// None in direct messages
+	pub guild_id: Option<GuildId>,
 	pub channel_id: ChannelId,
 	pub message_id: MessageId,
 }
 
+/// The channel whose permissions decide who can view the channel: the channel itself, or the parent channel of a public thread, since anyone who can view that can view the thread. Threads aren't among the guild's channels. Private threads and unknown channels have none.
+pub fn permission_channel(guild: &Guild, channel: ChannelId) -> Option<&GuildChannel> {
+	if let Some(channel) = guild.channels.get(&channel) {
+		return Some(channel);
+	}
+	let thread = guild.threads.iter().find(|thread| thread.id == channel)?;
+	if thread.kind != ChannelType::PublicThread {
+		return None;
+	}
+	thread
+		.parent_id
+		.and_then(|parent| guild.channels.get(&parent))
+}
+
 impl MessageIds {
-	pub fn new(guild_id: GuildId, channel_id: ChannelId, message_id: MessageId) -> Self {
+	pub fn new(guild_id: Option<GuildId>, channel_id: ChannelId, message_id: MessageId) -> Self {
 		Self {
 			guild_id,
 			channel_id,
 			message_id,
 		}
 	}
-	/// Whether the message is allowed to reply to this message in this context. A message is allowed reply to a message when the guild of that message is the same as where the reply is, and the replying user has access to view the channel.
+	/// Whether the message is allowed to reply to this message in this context. A message is allowed reply to a message when the guild of that message is the same as where the reply is, and the replying user has access to view the channel, or the parent channel of a public thread.
 	pub fn is_allowed_to_be_replied_to(&self, message: &Message, cache: &Arc<Cache>) -> bool {
-		if self.guild_id != message.guild_id.unwrap() {
-			// Cross-guild replying is not allowed.
+		if self.guild_id != message.guild_id {
+			// Cross-guild replying is not allowed, nor replying between a guild and direct messages.
 			false
 		} else if self.channel_id == message.channel_id {
 			// Same channel is always allowed.
 			true
 		} else {
-			let Some(guild) = cache.guild(self.guild_id) else {
+			// Direct messages with someone else are off limits.
+			let Some(guild) = self.guild_id.and_then(|guild_id| cache.guild(guild_id)) else {
 				return false;
 			};
-			let Some(channel) = guild.channels.get(&self.channel_id) else {
+			let Some(channel) = permission_channel(&guild, self.channel_id) else {
 				return false;
 			};
 			guild
@@ -351,9 +1102,9 @@ impl MessageIds {
 		}
 	}
 	/// This is how they are stored in the database, out of necessity. Returns guild ID, channel ID and message ID, in that order.
-	pub fn as_i64s(self) -> (i64, i64, i64) {
+	pub fn as_i64s(self) -> (Option<i64>, i64, i64) {
 		(
-			self.guild_id.get() as i64,
+			self.guild_id.map(|guild_id| guild_id.get() as i64),
 			self.channel_id.get() as i64,
 			self.message_id.get() as i64,
 		)
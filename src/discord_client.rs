@@ -1,8 +1,26 @@
 use itertools::Itertools;
-use serenity::{all::Cache, async_trait, model::prelude::*, prelude::*};
+use serenity::{
+	all::Cache,
+	async_trait,
+	builder::{CreateInteractionResponse, CreateInteractionResponseMessage},
+	model::prelude::*,
+	prelude::*,
+};
 use sqlx::{query, Pool, Sqlite};
 
-use crate::{allowances, conversations::MessageIds, gpt::Gpt, user_settings};
+use crate::{
+	allowances,
+	ambient_channels::{self, get_ambient_channel, is_on_cooldown},
+	channel_summary,
+	chat::IncomingMessage,
+	conversation_info, conversation_search,
+	conversations::{
+		delete_turns, get_recent_reply, get_reply_author, get_thread_conversation, MessageIds,
+	},
+	gpt::Gpt,
+	memories, user_data, user_settings,
+	util::parse_regenerate_button,
+};
 
 /// If there is a mention on either end of the string, removes it and trims. Removes only one mention.
 fn strip_mention<'l>(text: &'l str, mentions: &[String]) -> Option<&'l str> {
@@ -13,6 +31,15 @@ fn strip_mention<'l>(text: &'l str, mentions: &[String]) -> Option<&'l str> {
 		.map(str::trim)
 }
 
+/// Like `strip_mention`, but without a mention it just trims, if `needs_mention` is false.
+fn strip_optional_mention<'l>(
+	text: &'l str,
+	mentions: &[String],
+	needs_mention: bool,
+) -> Option<&'l str> {
+	strip_mention(text, mentions).or_else(|| (!needs_mention).then(|| text.trim()))
+}
+
 /// If there is a message link at the start of the string, removes it and trims the start, and returns both the remaining message and the IDs from the link.
 fn extract_message_link(mut text: &str) -> Option<(&str, MessageIds)> {
 	text = text.strip_prefix("https://")?;
@@ -21,7 +48,10 @@ fn extract_message_link(mut text: &str) -> Option<(&str, MessageIds)> {
 		.or_else(|| text.strip_prefix("canary."))
 		.unwrap_or(text);
 	let text = text.strip_prefix("discord.com/channels/")?;
-	let mut section = 0;
+	// Links to direct messages have no guild ID.
+	let direct = text.strip_prefix("@me/");
+	let text = direct.unwrap_or(text);
+	let mut section = if direct.is_some() { 1 } else { 0 };
 	let mut ids = [0, 0, 0];
 	for (index, byte) in text.bytes().enumerate() {
 		match byte {
@@ -30,10 +60,14 @@ fn extract_message_link(mut text: &str) -> Option<(&str, MessageIds)> {
 				ids[section] += (byte - b'0') as u64;
 			}
 			b'/' if section < 2 => section += 1,
-			b' ' if section == 2 && ids.into_iter().all(|id| id != 0) => {
+			b' ' if section == 2
+				&& ids[1] != 0
+				&& ids[2] != 0
+				&& (direct.is_some() || ids[0] != 0) =>
+			{
 				let text = text[index..].trim_start();
 				let parent = MessageIds::new(
-					GuildId::new(ids[0]),
+					direct.is_none().then(|| GuildId::new(ids[0])),
 					ChannelId::new(ids[1]),
 					MessageId::new(ids[2]),
 				);
@@ -47,6 +81,68 @@ fn extract_message_link(mut text: &str) -> Option<(&str, MessageIds)> {
 	None
 }
 
+/// The IDs from a message link on its own.
+pub fn parse_message_link(link: &str) -> Option<MessageIds> {
+	// Links are otherwise followed by the rest of a message, so give this one an end.
+	extract_message_link(&format!("{} ", link.trim())).map(|(_, ids)| ids)
+}
+
+/// Whether the bot can start a thread from the message, which it can in text and announcement channels but not in threads.
+fn can_start_thread(cache: &Cache, message: MessageIds) -> bool {
+	message
+		.guild_id
+		.and_then(|guild_id| cache.guild(guild_id))
+		.is_some_and(|guild| {
+			guild
+				.channels
+				.get(&message.channel_id)
+				.is_some_and(|channel| {
+					matches!(channel.kind, ChannelType::Text | ChannelType::News)
+				})
+		})
+}
+
+/// What someone goes by where they sent a message: their nickname in the guild, else their display name on Discord.
+pub fn display_name(user: &User, nick: Option<&String>) -> String {
+	nick.or(user.global_name.as_ref())
+		.unwrap_or(&user.name)
+		.clone()
+}
+
+fn incoming_message(message: &Message) -> IncomingMessage {
+	IncomingMessage {
+		ids: MessageIds::new(message.guild_id, message.channel_id, message.id),
+		author: message.author.id,
+		author_name: display_name(
+			&message.author,
+			message
+				.member
+				.as_ref()
+				.and_then(|member| member.nick.as_ref()),
+		),
+	}
+}
+
+/// Image types all the providers accept
+const IMAGE_TYPES: [&str; 4] = ["image/png", "image/jpeg", "image/webp", "image/gif"];
+/// The largest image the APIs accept, in bytes
+const MAX_IMAGE_SIZE: u32 = 20_000_000;
+
+/// The URLs of the attachments that can be sent to the API as images.
+fn image_urls(attachments: &[Attachment]) -> Vec<String> {
+	attachments
+		.iter()
+		.filter(|attachment| {
+			attachment
+				.content_type
+				.as_deref()
+				.is_some_and(|content_type| IMAGE_TYPES.contains(&content_type))
+				&& attachment.size <= MAX_IMAGE_SIZE
+		})
+		.map(|attachment| attachment.url.clone())
+		.collect()
+}
+
 async fn get_message(
 	context: &Context,
 	channel_id: ChannelId,
@@ -58,21 +154,26 @@ async fn get_message(
 	context.http.get_message(channel_id, message_id).await.ok()
 }
 
+/// Gets the contents and image URLs of a referenced message, fetching it again if they're missing.
 async fn get_referenced_contents(
 	http: &std::sync::Arc<serenity::http::Http>,
 	mut referenced: Message,
-) -> Option<String> {
-	if !referenced.content.is_empty() {
-		return Some(std::mem::take(&mut referenced.content));
-	}
-	let Ok(mut referenced) = http.get_message(referenced.channel_id, referenced.id).await else {
-		return None;
-	};
-	if !referenced.content.is_empty() {
-		Some(std::mem::take(&mut referenced.content))
-	} else {
-		None
+) -> Option<(String, Vec<String>)> {
+	let has_contents =
+		|message: &Message| !message.content.is_empty() || !message.attachments.is_empty();
+	if !has_contents(&referenced) {
+		let Ok(fetched) = http.get_message(referenced.channel_id, referenced.id).await else {
+			return None;
+		};
+		if !has_contents(&fetched) {
+			return None;
+		}
+		referenced = fetched;
 	}
+	Some((
+		std::mem::take(&mut referenced.content),
+		image_urls(&referenced.attachments),
+	))
 }
 
 async fn is_own_message(executor: &Pool<Sqlite>, message_id: MessageId) -> bool {
@@ -98,8 +199,8 @@ enum ReferencedMessage {
 	None,
 	/// Message, and whether it used a message link
 	Own(MessageIds, bool),
-	/// Message, and message's contents
-	Others(MessageIds, String),
+	/// Message, and its author's name, and message's contents and image URLs
+	Others(MessageIds, String, String, Vec<String>),
 }
 
 impl ReferencedMessage {
@@ -111,17 +212,21 @@ impl ReferencedMessage {
 	) -> Option<(Self, &'l str)> {
 		let message =
 			if let Some(referenced) = std::mem::take(&mut message.referenced_message).map(|m| *m) {
-				let referenced_ids = MessageIds::new(
-					message.guild_id.unwrap(),
-					referenced.channel_id,
-					referenced.id,
+				let referenced_ids =
+					MessageIds::new(message.guild_id, referenced.channel_id, referenced.id);
+				let author_name = display_name(
+					&referenced.author,
+					referenced
+						.member
+						.as_ref()
+						.and_then(|member| member.nick.as_ref()),
 				);
 				if referenced.author.id == context.cache.current_user().id {
 					ReferencedMessage::Own(referenced_ids, false)
-				} else if let Some(referenced_contents) =
+				} else if let Some((contents, images)) =
 					get_referenced_contents(&context.http, referenced).await
 				{
-					ReferencedMessage::Others(referenced_ids, referenced_contents)
+					ReferencedMessage::Others(referenced_ids, author_name, contents, images)
 				} else {
 					// It has a referenced message, but the bot couldn't get it.
 					println!(
@@ -139,7 +244,7 @@ impl ReferencedMessage {
 				{
 					content = text;
 					let referenced_ids = MessageIds::new(
-						message.guild_id.unwrap(),
+						message.guild_id,
 						linked_message.channel_id,
 						linked_message.id,
 					);
@@ -149,7 +254,15 @@ impl ReferencedMessage {
 					} else {
 						ReferencedMessage::Others(
 							referenced_ids,
+							display_name(
+								&linked_message.author,
+								linked_message
+									.member
+									.as_ref()
+									.and_then(|member| member.nick.as_ref()),
+							),
 							std::mem::take(&mut linked_message.content),
+							image_urls(&linked_message.attachments),
 						)
 					}
 				} else {
@@ -160,48 +273,60 @@ impl ReferencedMessage {
 			};
 		Some((message, content))
 	}
+	/// Gets the parent to continue from, the text of the input, and the URLs of the images that go with it. Without `needs_mention`, as in direct messages, the text doesn't need to mention the bot.
 	async fn get_parent_and_content(
 		self,
 		mut reply_body: &str,
+		mut images: Vec<String>,
 		mentions: &[String],
-	) -> Option<(Option<MessageIds>, String)> {
+		needs_mention: bool,
+	) -> Option<(Option<MessageIds>, String, Vec<String>)> {
 		let mut parent = None;
 		let content = match self {
 			Self::Own(referenced, was_link) => {
 				if was_link {
-					reply_body = strip_mention(reply_body, mentions)?;
+					reply_body = strip_optional_mention(reply_body, mentions, needs_mention)?;
+				}
+				if reply_body.is_empty() && images.is_empty() {
+					// Nothing to reply with, like when only a file was attached.
+					return None;
 				}
 				parent = Some(referenced);
 				reply_body.to_string()
 			}
-			Self::Others(_, referenced_contents) => {
-				reply_body = strip_mention(reply_body, mentions)?;
+			Self::Others(_, author_name, referenced_contents, mut referenced_images) => {
+				reply_body = strip_optional_mention(reply_body, mentions, needs_mention)?;
+				referenced_images.append(&mut images);
+				images = referenced_images;
 				if reply_body.is_empty() {
 					// A message replying to something, but containing nothing but a mention to the bot
 					// Stripping mentions so replies can be used to repeat queries, possibly with different settings.
 					let referenced_contents = strip_mention(&referenced_contents, mentions)
 						.map(str::to_string)
 						.unwrap_or(referenced_contents);
-					if referenced_contents.is_empty() {
-						// Referenced message had only a mention, or otherwise no content (like only an image), makes no sense, ignore.
+					if referenced_contents.is_empty() && images.is_empty() {
+						// Referenced message had only a mention, or otherwise no content (like only a file), makes no sense, ignore.
 						return None;
 					}
 					referenced_contents
+				} else if referenced_contents.is_empty() {
+					// A message replying to an image, and containing its own text as well
+					reply_body.to_string()
 				} else {
 					// A message replying to something, and containing its own text as well
-					format!("{reply_body} \"{referenced_contents}\"")
+					format!("{reply_body} (quoting {author_name}: \"{referenced_contents}\")")
 				}
 			}
 			Self::None => {
-				reply_body = strip_mention(reply_body, mentions)?;
-				if reply_body.is_empty() {
+				reply_body = strip_optional_mention(reply_body, mentions, needs_mention)?;
+				if reply_body.is_empty() && images.is_empty() {
 					// Nothing other than a mention, ignore.
 					return None;
 				}
 				reply_body.to_string()
 			}
 		};
-		Some((parent, content))
+		Some((parent, content, images))
 	}
 	fn is_allowed_to_be_replied_to(
 		&self,
@@ -211,7 +336,9 @@ impl ReferencedMessage {
 		match self {
 			Self::None => true,
 			Self::Own(message_ids, _) => message_ids.is_allowed_to_be_replied_to(message, cache),
-			Self::Others(message_ids, _) => message_ids.is_allowed_to_be_replied_to(message, cache),
+			Self::Others(message_ids, ..) => {
+				message_ids.is_allowed_to_be_replied_to(message, cache)
+			}
 		}
 	}
 }
@@ -234,32 +361,185 @@ impl DiscordEventHandler {
 		}
 	}
 	/// The message looks like something to start or continue a conversation with.
-	async fn handle_conversation_message(&self, context: Context, mut message: Message) {
+	async fn handle_conversation_message(&self, context: Context, message: Message) {
+		if self.is_cooling_down(&context, &message).await {
+			let _ = message.react(&context.http, '⏳').await;
+			return;
+		}
+		let Some((message, content, images, parent)) =
+			self.parse_conversation_message(&context, message).await
+		else {
+			return;
+		};
+		if parent.is_none() && self.gpt.threads() && can_start_thread(&context.cache, message.ids) {
+			self.gpt
+				.query_in_new_thread(
+					&self.database,
+					context.http.as_ref(),
+					content,
+					images,
+					message,
+				)
+				.await;
+			return;
+		}
+		self.gpt
+			.query(
+				&self.database,
+				context.http.as_ref(),
+				content,
+				images,
+				message,
+				parent,
+			)
+			.await;
+	}
+	/// Whether the message is in an ambient channel without mentioning the bot, too soon after the author's last prompt there.
+	async fn is_cooling_down(&self, context: &Context, message: &Message) -> bool {
+		let own_id = context.cache.current_user().id;
+		if message.author.bot || message.mentions_user_id(own_id) {
+			return false;
+		}
+		let Some(channel) = get_ambient_channel(&self.database, message.channel_id).await else {
+			return false;
+		};
+		is_on_cooldown(
+			&self.database,
+			message.channel_id,
+			message.author.id,
+			channel.cooldown,
+		)
+		.await
+	}
+	/// Gets the text and image URLs to prompt with from a message, and the parent to continue from, if the message is to be replied to. That's when it mentions the bot, or it's in a direct message, a conversation thread or an ambient channel.
+	async fn parse_conversation_message(
+		&self,
+		context: &Context,
+		mut message: Message,
+	) -> Option<(IncomingMessage, String, Vec<String>, Option<MessageIds>)> {
+		let own_id = context.cache.current_user().id;
+		if message.author.id == own_id {
+			return None;
+		}
+		let is_direct_message = message.guild_id.is_none();
+		if is_direct_message && !self.gpt.direct_messages() {
+			return None;
+		}
+		// Everything in direct messages and ambient channels is for the bot, except what other bots say in ambient channels.
+		let needs_mention = !is_direct_message
+			&& (message.author.bot
+				|| get_ambient_channel(&self.database, message.channel_id)
+					.await
+					.is_none());
+		let mentioned = message.mentions_user_id(own_id) || !needs_mention;
+		// Pinging the bot in a reply continues from the message replied to, like anywhere else. Other bots need to ping it even in threads, so bots can't keep replying to each other.
+		if self.gpt.threads()
+			&& !message.author.bot
+			&& !(mentioned && message.referenced_message.is_some())
+		{
+			if let Some(latest) = get_thread_conversation(&self.database, message.channel_id).await
+			{
+				return self.parse_thread_message(message, latest);
+			}
+		}
+		if !mentioned {
+			return None;
+		}
+
 		let content = std::mem::take(&mut message.content);
+		let images = image_urls(&message.attachments);
 
-		let Some((referenced, content)) = ReferencedMessage::get_referenced_and_content(
+		let (referenced, content) = ReferencedMessage::get_referenced_and_content(
 			&self.database,
-			&context,
+			context,
 			&mut message,
 			&content,
 		)
-		.await
-		else {
-			return;
-		};
+		.await?;
 
 		if !referenced.is_allowed_to_be_replied_to(&message, &context.cache) {
-			return;
+			return None;
 		}
-		let Some((parent, content)) = referenced
-			.get_parent_and_content(content, &self.mentions)
-			.await
+		let (parent, content, images) = referenced
+			.get_parent_and_content(content, images, &self.mentions, needs_mention)
+			.await?;
+
+		Some((incoming_message(&message), content, images, parent))
+	}
+	/// Messages in a conversation thread continue from the latest turn in it, with or without a mention.
+	fn parse_thread_message(
+		&self,
+		message: Message,
+		latest: Option<MessageIds>,
+	) -> Option<(IncomingMessage, String, Vec<String>, Option<MessageIds>)> {
+		let content = message.content.trim();
+		let content = strip_mention(content, &self.mentions).unwrap_or(content);
+		let images = image_urls(&message.attachments);
+		if content.is_empty() && images.is_empty() {
+			return None;
+		}
+		Some((
+			incoming_message(&message),
+			content.to_string(),
+			images,
+			latest,
+		))
+	}
+	/// A message was edited soon after the bot replied to it, so it gets a new reply in place of the old one.
+	async fn handle_edited_message(&self, context: Context, message: Message, reply: MessageIds) {
+		let Some((message, content, images, _)) =
+			self.parse_conversation_message(&context, message).await
 		else {
 			return;
 		};
-
 		self.gpt
-			.query(&self.database, context, content, message, parent)
+			.rerun(
+				&self.database,
+				context.http.as_ref(),
+				content,
+				images,
+				message,
+				reply,
+			)
+			.await;
+	}
+	/// Someone pressed a button on one of the bot's messages.
+	async fn handle_component(&self, context: Context, interaction: ComponentInteraction) {
+		let Some(asker) = parse_regenerate_button(&interaction.data.custom_id) else {
+			return;
+		};
+		if interaction.user.id != asker {
+			let response = CreateInteractionResponseMessage::new()
+				.content("Only the person who asked can regenerate this reply.")
+				.ephemeral(true);
+			let _ = interaction
+				.create_response(&context.http, CreateInteractionResponse::Message(response))
+				.await;
+			return;
+		}
+		let _ = interaction
+			.create_response(&context.http, CreateInteractionResponse::Acknowledge)
+			.await;
+		let reply = MessageIds::new(
+			interaction.guild_id,
+			interaction.message.channel_id,
+			interaction.message.id,
+		);
+		let asker_name = display_name(
+			&interaction.user,
+			interaction
+				.member
+				.as_ref()
+				.and_then(|member| member.nick.as_ref()),
+		);
+		self.gpt
+			.regenerate(
+				&self.database,
+				context.http.as_ref(),
+				asker,
+				asker_name,
+				reply,
+			)
 			.await;
 	}
 }
@@ -267,15 +547,98 @@ impl DiscordEventHandler {
 #[async_trait]
 impl EventHandler for DiscordEventHandler {
 	async fn message(&self, context: Context, message: Message) {
-		let own_id = context.cache.current_user().id;
-		if message.author.id != own_id
-			&& message.mentions_user_id(own_id)
-			&& !message.content.is_empty()
-		{
+		if !message.content.is_empty() || !message.attachments.is_empty() {
 			self.handle_conversation_message(context, message).await;
 		}
 	}
 
+	async fn message_delete(
+		&self,
+		_context: Context,
+		channel_id: ChannelId,
+		deleted_message_id: MessageId,
+		_guild_id: Option<GuildId>,
+	) {
+		delete_turns(
+			&self.database,
+			channel_id,
+			&[deleted_message_id],
+			self.gpt.remove_deleted_messages(),
+		)
+		.await;
+	}
+
+	async fn message_delete_bulk(
+		&self,
+		_context: Context,
+		channel_id: ChannelId,
+		deleted_message_ids: Vec<MessageId>,
+		_guild_id: Option<GuildId>,
+	) {
+		delete_turns(
+			&self.database,
+			channel_id,
+			&deleted_message_ids,
+			self.gpt.remove_deleted_messages(),
+		)
+		.await;
+	}
+
+	/// The user who asked can delete a reply by reacting to it with ❌.
+	async fn reaction_add(&self, context: Context, reaction: Reaction) {
+		let Some(user_id) = reaction.user_id else {
+			return;
+		};
+		if !matches!(&reaction.emoji, ReactionType::Unicode(emoji) if emoji == "❌") {
+			return;
+		}
+		let reply = MessageIds::new(reaction.guild_id, reaction.channel_id, reaction.message_id);
+		if get_reply_author(&self.database, reply).await != Some(user_id) {
+			return;
+		}
+		if reaction
+			.channel_id
+			.delete_message(&context.http, reaction.message_id)
+			.await
+			.is_ok()
+		{
+			// The deletion event would do this too, but it isn't guaranteed to arrive.
+			delete_turns(
+				&self.database,
+				reaction.channel_id,
+				&[reaction.message_id],
+				self.gpt.remove_deleted_messages(),
+			)
+			.await;
+		}
+	}
+
+	async fn message_update(
+		&self,
+		context: Context,
+		_old: Option<Message>,
+		_new: Option<Message>,
+		event: MessageUpdateEvent,
+	) {
+		// Updates without content are from things like embeds loading.
+		if event.content.is_none() {
+			return;
+		}
+		if self.gpt.edit_window() == 0 {
+			return;
+		}
+		let ids = MessageIds::new(event.guild_id, event.channel_id, event.id);
+		let Some(reply) = get_recent_reply(&self.database, ids, self.gpt.edit_window()).await
+		else {
+			return;
+		};
+		// The event only has what changed, and the cached message may be outdated.
+		let Ok(message) = event.channel_id.message(&context.http, event.id).await else {
+			return;
+		};
+		self.handle_edited_message(context, message, reply).await;
+	}
+
 	async fn interaction_create(&self, context: Context, interaction: Interaction) {
 		if let Interaction::Command(interaction) = interaction {
 			let _ = match interaction.data.name.as_str() {
@@ -301,6 +664,78 @@ impl EventHandler for DiscordEventHandler {
 					)
 					.await
 				}
+				"temperature" => {
+					user_settings::command_set_temperature(
+						context,
+						interaction,
+						&self.database,
+						&self.gpt,
+					)
+					.await
+				}
+				"length" => {
+					user_settings::command_set_length(
+						context,
+						interaction,
+						&self.database,
+						&self.gpt,
+					)
+					.await
+				}
+				"modelpolicy" => {
+					user_settings::command_set_model_policy(
+						context,
+						interaction,
+						&self.database,
+						&self.gpt,
+					)
+					.await
+				}
+				"ambient" => {
+					ambient_channels::command_ambient(
+						context,
+						interaction,
+						&self.database,
+						&self.gpt,
+					)
+					.await
+				}
+				"summarize" => {
+					channel_summary::command_summarize(
+						context,
+						interaction,
+						&self.database,
+						&self.gpt,
+					)
+					.await
+				}
+				"remember" => {
+					memories::command_remember(context, interaction, &self.database).await
+				}
+				"Remember this" => {
+					memories::command_remember_message(
+						context,
+						interaction,
+						&self.database,
+						&self.gpt,
+					)
+					.await
+				}
+				"memory" => memories::command_memory(context, interaction, &self.database).await,
+				"forget" => memories::command_forget(context, interaction, &self.database).await,
+				"history" => {
+					conversation_search::command_history(context, interaction, &self.database).await
+				}
+				"mydata" => user_data::command_my_data(context, interaction, &self.database).await,
+				"Conversation info" => {
+					conversation_info::command_conversation_info(
+						context,
+						interaction,
+						&self.database,
+						&self.gpt,
+					)
+					.await
+				}
 				"personality" => {
 					user_settings::command_set_personality(context, interaction, &self.database)
 						.await
@@ -319,12 +754,18 @@ impl EventHandler for DiscordEventHandler {
 						one_off
 							.handle(context, interaction, &self.gpt, &self.database)
 							.await
+					} else if let Some(image_command) = self.gpt.get_image_command_by_name(name) {
+						image_command
+							.handle(context, interaction, &self.gpt, &self.database)
+							.await
 					} else {
 						eprintln!("Received unknown command: {}", name);
 						Err(())
 					}
 				}
 			};
+		} else if let Interaction::Component(interaction) = interaction {
+			self.handle_component(context, interaction).await;
 		}
 	}
 
@@ -333,7 +774,8 @@ impl EventHandler for DiscordEventHandler {
 		let arg = std::env::args().nth(1);
 		if let Some(arg) = arg {
 			if &arg == "register" {
-				let mut command_count = 3 + self.gpt.one_offs().len();
+				let mut command_count =
+					15 + self.gpt.one_offs().len() + self.gpt.image_commands().len();
 				if !self.gpt.models().is_empty() {
 					command_count += 1;
 				}
@@ -344,6 +786,18 @@ impl EventHandler for DiscordEventHandler {
 				commands.extend([
 					allowances::register(),
 					allowances::register_check_expenditure(),
+					user_settings::register_set_temperature(),
+					user_settings::register_set_length(),
+					conversation_info::register_conversation_info(),
+					user_settings::register_set_model_policy(),
+					ambient_channels::register_ambient(&self.gpt),
+					channel_summary::register_summarize(),
+					memories::register_remember(),
+					memories::register_remember_message(),
+					memories::register_memory(),
+					memories::register_forget(),
+					user_data::register_my_data(),
+					conversation_search::register_history(),
 				]);
 				if !self.gpt.models().is_empty() {
 					commands.push(user_settings::register_set_model(&self.gpt));
@@ -355,6 +809,9 @@ impl EventHandler for DiscordEventHandler {
 				for one_off in self.gpt.one_offs() {
 					commands.push(one_off.create());
 				}
+				for image_command in self.gpt.image_commands() {
+					commands.push(image_command.create());
+				}
 				for guild in context.cache.guilds() {
 					let commands = guild
 						.set_commands(&context.http, commands.clone())
@@ -1,137 +1,291 @@
 //! I used this as a starting point: https://github.com/Maxuss/chatgpt_rs Copyright (c) 2022 Maksim Petrov
 //! But there is almost nothing left of it.
 
-use reqwest::{
-	header::{HeaderValue, AUTHORIZATION},
-	Url,
-};
-use serde::{Deserialize, Serialize};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use reqwest::header::CONTENT_TYPE;
+use serde::{ser::SerializeStruct, Deserialize, Serialize, Serializer};
 use serenity::all::{RoleId, UserId};
-use std::{collections::HashMap, fmt::Display};
+use std::{collections::HashMap, fmt::Display, fs, time::Duration};
 
 use crate::{
-	config::{Config, CustomApiKeys},
+	config::{ApiKeySource, Config, CustomApiKeys},
+	conversations::ModelPolicy,
+	gpt_error::GptError,
+	image_generation::{ImageCommand, ImageRequest, ImageResponse},
 	one_off_response::OneOffCommand,
+	providers::{Provider, ProviderKind},
 	response_styles::{extract_custom, Personality, PersonalityPreset},
+	token_estimation::Tokenizer,
 };
 
+/// For users who haven't set their own.
 const TEMPERATURE: f32 = 0.5;
-const MAX_TOKENS: u32 = 400;
+/// For models that don't specify their default reply length, and users who haven't set their own.
+const DEFAULT_OUTPUT_TOKENS: u32 = 400;
+/// For models that don't specify theirs. Small enough for any current model.
+const DEFAULT_CONTEXT_LENGTH: u32 = 16_385;
+/// How long to wait for a connection to the API
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to wait for a response to start, or for a whole download
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(120);
+/// How long a stream may go without sending anything before it's considered cut off. Streams can go on for much longer in total.
+const CHUNK_TIMEOUT: Duration = Duration::from_secs(120);
 
 // The client that operates the GPT API
 #[derive(Debug, Clone)]
 pub struct Gpt {
 	client: reqwest::Client,
-	api_url: Url,
-	authorization_header: HeaderValue,
-	custom_authorization_headers: HashMap<UserId, HeaderValue>,
+	/// The keys for every key source used by any model
+	api_keys: HashMap<ApiKeySource, String>,
+	custom_api_keys: HashMap<UserId, String>,
 	config: Config,
 }
 
 impl Gpt {
-	/// Constructs a new GPT API client with provided API key and URL.
+	/// Constructs a new GPT API client with provided API key.
 	///
-	/// `api_url` is the URL of the /v1/chat/completions endpoint. Can be used to set a proxy.
-	pub fn new<S>(
-		api_key: S,
-		api_url: Option<Url>,
-		config: Config,
-		custom_api_keys: CustomApiKeys,
-	) -> Result<Self, ()>
+	/// `api_key` is the key for models that don't specify their own key source. Any other keys the models need are loaded here.
+	pub fn new<S>(api_key: S, config: Config, custom_api_keys: CustomApiKeys) -> Result<Self, ()>
 	where
 		S: Display,
 	{
-		let api_url = api_url
-			.unwrap_or_else(|| Url::parse("https://api.openai.com/v1/chat/completions").unwrap());
+		let mut api_keys = HashMap::from([(ApiKeySource::Default, api_key.to_string())]);
+		for model in config.models.iter().chain(&config.search_models) {
+			let source = &model.api_key;
+			if api_keys.contains_key(source) {
+				continue;
+			}
+			let key = match source {
+				ApiKeySource::Default | ApiKeySource::None => continue,
+				ApiKeySource::File(path) => {
+					fs::read_to_string(path).expect("Could not read API key file")
+				}
+				ApiKeySource::Env(variable) => {
+					std::env::var(variable).expect("Could not read API key environment variable")
+				}
+			};
+			api_keys.insert(source.clone(), key.trim().to_string());
+		}
 
-		let authorization_header =
-			HeaderValue::from_bytes(format!("Bearer {api_key}").as_bytes()).unwrap();
+		// No timeout for whole requests, since streams last as long as the completion takes.
 		let client = reqwest::ClientBuilder::new()
-			.timeout(core::time::Duration::from_secs(120))
+			.connect_timeout(CONNECT_TIMEOUT)
 			.build()
 			.unwrap();
 
 		Ok(Self {
 			client,
-			api_url,
-			authorization_header,
-			custom_authorization_headers: custom_api_keys.into_headers(),
+			api_keys,
+			custom_api_keys: custom_api_keys.into_map(),
 			config,
 		})
 	}
 
+	/// Downloads the images in the history and replaces their URLs with data URLs. This way the API doesn't have to fetch them itself, and images whose links have expired since are left out instead of failing the request.
+	pub async fn inline_images(&self, history: &mut [ChatMessage]) {
+		for message in history {
+			let mut images = Vec::with_capacity(message.images.len());
+			for url in std::mem::take(&mut message.images) {
+				match self.download_image(&url).await {
+					Ok(image) => images.push(image),
+					Err(error) => eprintln!("Could not download image {url}: {error}"),
+				}
+			}
+			message.images = images;
+		}
+	}
+
+	async fn download_image(&self, url: &str) -> Result<String, String> {
+		if url.starts_with("data:") {
+			return Ok(url.to_string());
+		}
+		let response = self
+			.client
+			.get(url)
+			.timeout(RESPONSE_TIMEOUT)
+			.send()
+			.await
+			.and_then(reqwest::Response::error_for_status)
+			.map_err(|error| error.to_string())?;
+		let media_type = response
+			.headers()
+			.get(CONTENT_TYPE)
+			.and_then(|value| value.to_str().ok())
+			.filter(|media_type| media_type.starts_with("image/"))
+			.ok_or("not an image")?
+			.to_string();
+		let bytes = response.bytes().await.map_err(|error| error.to_string())?;
+		Ok(format!(
+			"data:{media_type};base64,{}",
+			BASE64_STANDARD.encode(bytes)
+		))
+	}
+
 	/// Sends a conversation to the API and gets the next message.
 	pub async fn send(
 		&self,
 		history: &[ChatMessage],
-		model: &str,
-		api_version: u32,
-		authorization_header: &HeaderValue,
-	) -> Result<CompletionResponse, String> {
+		model: &GptModel,
+		api_key: Option<&str>,
+		tools: &[ToolDefinition],
+		sampling: Sampling,
+	) -> Result<CompletionResponse, GptError> {
+		let request = CompletionRequest::new(model, sampling)
+			.with_messages(history)
+			.with_tools(tools);
 		let response = self
-			.client
-			.post(self.api_url.clone())
-			.header(AUTHORIZATION, authorization_header)
-			.json(&CompletionRequest::new(model, api_version).with_messages(history))
-			.send()
-			.await
-			.map_err(|error| {
-				println!("{error}");
-				String::from("Boop beep, problem sending request.")
-			})?;
-
-		let response = response.json_or_raw().await.map_err(|err| {
-			println!("{err}");
-			String::from("Bloop bloop, unknown error")
-		})?;
+			.send_with_retries(model.provider(), || {
+				model
+					.provider()
+					.build_request(&self.client, model.base_url(), api_key, &request)
+			})
+			.await?;
 
 		// let (response, text) = response.json_and_text().await;
 		// println!("{text}");
 		// println!("{response:?}");
 
-		match response {
+		match response.parse_or_raw(model.provider()).await? {
 			ServerResponse::Error { error } => {
-				eprintln!("Backend error: {}, {}", error.message, error.error_type);
-				let text = match error.error_type.as_str() {
-					"insufficient_quota" => "Boop bloop, out of credit.",
-					"server_error" => "Boop bloop, server error.",
-					"requests" => "Beep bloop, probably rate-limited.",
-					_ => "Boop bloop, unknown error",
-				};
-				Err(String::from(text))
+				Err(GptError::from_api_error(None, Some(error), None))
 			}
-			ServerResponse::Completion(completion) => {
-				if [
-					completion.usage.completion_tokens_details.reasoning_tokens,
-					completion
-						.usage
-						.completion_tokens_details
-						.accepted_prediction_tokens,
-					completion
-						.usage
-						.completion_tokens_details
-						.rejected_prediction_tokens,
-					completion.usage.completion_tokens_details.audio_tokens,
-					completion.usage.completion_tokens_details.audio_tokens,
-					completion.usage.prompt_tokens_details.audio_tokens,
-					completion.usage.prompt_tokens_details.cached_tokens,
-				]
-				.iter()
-				.any(|tokens| *tokens != 0)
-				{
-					println!("Some of the fancier token costs included in response:");
-					println!("{}", completion.message_choices[0].message.content);
-					println!("{:?}", completion.usage);
-				}
+			ServerResponse::Completion(mut completion) => {
+				completion.usage.requests = 1;
+				warn_about_fancy_tokens(&completion);
 				Ok(completion)
 			}
 		}
 	}
-	pub fn authorization_header(&self) -> &HeaderValue {
-		&self.authorization_header
+	/// Sends a conversation to the API, asking for the next message to be streamed back.
+	///
+	/// This returns as soon as the API has accepted the request, so errors that happen before any of the completion is generated are still reported here.
+	pub async fn send_streaming(
+		&self,
+		history: &[ChatMessage],
+		model: &GptModel,
+		api_key: Option<&str>,
+		tools: &[ToolDefinition],
+		sampling: Sampling,
+	) -> Result<CompletionStream, GptError> {
+		let request = CompletionRequest::new(model, sampling)
+			.with_messages(history)
+			.with_tools(tools)
+			.with_streaming();
+		let response = self
+			.send_with_retries(model.provider(), || {
+				model
+					.provider()
+					.build_request(&self.client, model.base_url(), api_key, &request)
+			})
+			.await?;
+		let tokenizer = model.tokenizer();
+		let prompt_estimate = (!model.provider().streams_usage())
+			.then(|| (tokenizer, tokenizer.estimate_prompt_tokens(history, tools)));
+		Ok(CompletionStream::new(
+			response,
+			model.provider(),
+			prompt_estimate,
+		))
+	}
+	/// Asks OpenAI's images API to generate an image, and gets the image file.
+	pub async fn generate_image(
+		&self,
+		request: &ImageRequest<'_>,
+		api_key: Option<&str>,
+	) -> Result<Vec<u8>, GptError> {
+		let provider = ProviderKind::Openai.provider();
+		let url = format!(
+			"{}/images/generations",
+			provider.default_base_url().unwrap()
+		);
+		let response = self
+			.send_with_retries(provider, || {
+				let builder = self.client.post(&url).json(request);
+				match api_key {
+					Some(api_key) => builder.bearer_auth(api_key),
+					None => builder,
+				}
+			})
+			.await?;
+		let body = response.bytes().await.map_err(GptError::Transport)?;
+		let response: ImageResponse = serde_json::from_slice(&body)
+			.map_err(|error| GptError::MalformedBody(error.to_string()))?;
+		let image = response
+			.data
+			.into_iter()
+			.next()
+			.ok_or_else(|| GptError::MalformedBody(String::from("No image received.")))?;
+		// Some models send the image itself, others a link to it.
+		match (image.b64_json, image.url) {
+			(Some(data), _) => BASE64_STANDARD
+				.decode(data)
+				.map_err(|error| GptError::MalformedBody(error.to_string())),
+			(None, Some(url)) => {
+				let response = self
+					.client
+					.get(url)
+					.timeout(RESPONSE_TIMEOUT)
+					.send()
+					.await
+					.and_then(reqwest::Response::error_for_status)
+					.map_err(GptError::Transport)?;
+				let bytes = response.bytes().await.map_err(GptError::Transport)?;
+				Ok(bytes.to_vec())
+			}
+			(None, None) => Err(GptError::MalformedBody(String::from("No image received."))),
+		}
+	}
+	/// Sends the request until it gets a successful response, retrying with exponential backoff when the problem looks temporary.
+	async fn send_with_retries<F>(
+		&self,
+		provider: &'static dyn Provider,
+		build_request: F,
+	) -> Result<reqwest::Response, GptError>
+	where
+		F: Fn() -> reqwest::RequestBuilder,
+	{
+		let mut attempt = 0;
+		loop {
+			attempt += 1;
+			let error = match tokio::time::timeout(RESPONSE_TIMEOUT, build_request().send()).await {
+				Err(_) => GptError::TimedOut,
+				Ok(Ok(response)) if response.status().is_success() => return Ok(response),
+				Ok(Ok(response)) => {
+					let status = response.status();
+					let headers = response.headers().clone();
+					let error = match response.parse_or_raw(provider).await {
+						Ok(ServerResponse::Error { error }) => Some(error),
+						_ => None,
+					};
+					GptError::from_response_parts(status, &headers, error)
+				}
+				Ok(Err(error)) => GptError::Transport(error),
+			};
+			let Some(delay) = error.retry_delay(attempt) else {
+				eprintln!("GPT request failed after {attempt} attempt(s): {error}");
+				return Err(error);
+			};
+			eprintln!("GPT request failed ({error}), retrying in {delay:?}.");
+			tokio::time::sleep(delay).await;
+		}
+	}
+	/// The bot's own key for the model, if the model needs one.
+	pub fn api_key(&self, model: &GptModel) -> Option<&str> {
+		self.api_keys.get(&model.api_key).map(String::as_str)
+	}
+	/// The user's own key, if they have one and it can be used for the model. Only models using the default key can use custom keys.
+	pub fn custom_api_key(&self, user: UserId, model: &GptModel) -> Option<&str> {
+		(model.api_key == ApiKeySource::Default)
+			.then(|| self.user_api_key(user))
+			.flatten()
+	}
+	/// The OpenAI key the bot uses by default.
+	pub fn default_api_key(&self) -> &str {
+		&self.api_keys[&ApiKeySource::Default]
 	}
-	pub fn custom_authorization_header(&self, user: UserId) -> Option<&HeaderValue> {
-		self.custom_authorization_headers.get(&user)
+	/// The user's own OpenAI key, if they have one.
+	pub fn user_api_key(&self, user: UserId) -> Option<&str> {
+		self.custom_api_keys.get(&user).map(String::as_str)
 	}
 	pub fn daily_allowance(&self) -> u32 {
 		self.config.daily_allowance
@@ -139,6 +293,30 @@ impl Gpt {
 	pub fn accrual_days(&self) -> f32 {
 		self.config.accrual_days
 	}
+	pub fn overspend_margin(&self) -> u32 {
+		self.config.overspend_margin
+	}
+	pub fn summarize_old_messages(&self) -> bool {
+		self.config.summarize_old_messages
+	}
+	pub fn edit_window(&self) -> u32 {
+		self.config.edit_window
+	}
+	pub fn remove_deleted_messages(&self) -> bool {
+		self.config.remove_deleted_messages
+	}
+	pub fn model_policy(&self) -> ModelPolicy {
+		self.config.model_policy
+	}
+	pub fn threads(&self) -> bool {
+		self.config.threads
+	}
+	pub fn direct_messages(&self) -> bool {
+		self.config.direct_messages
+	}
+	pub fn retention_days(&self) -> Option<u32> {
+		self.config.retention_days
+	}
 	pub fn get_model_by_name(&self, name: &str) -> Option<&GptModel> {
 		self.config
 			.models
@@ -179,18 +357,86 @@ impl Gpt {
 	pub fn one_offs(&self) -> &Vec<OneOffCommand> {
 		&self.config.one_offs
 	}
+	pub fn get_image_command_by_name(&self, name: &str) -> Option<&ImageCommand> {
+		self.config
+			.image_commands
+			.iter()
+			.find(|command| command.name() == name)
+	}
+	pub fn image_commands(&self) -> &Vec<ImageCommand> {
+		&self.config.image_commands
+	}
 	pub fn prototyping_roles(&self) -> &Vec<RoleId> {
 		&self.config.prototyping_roles
 	}
 }
 
+/// Logs usage that isn't priced in, to see whether it ever happens.
+fn warn_about_fancy_tokens(completion: &CompletionResponse) {
+	if [
+		completion
+			.usage
+			.completion_tokens_details
+			.accepted_prediction_tokens,
+		completion
+			.usage
+			.completion_tokens_details
+			.rejected_prediction_tokens,
+		completion.usage.completion_tokens_details.audio_tokens,
+		completion.usage.prompt_tokens_details.audio_tokens,
+	]
+	.iter()
+	.any(|tokens| *tokens != 0)
+	{
+		println!("Some of the fancier token costs included in response:");
+		println!("{}", completion.message_choices[0].message.content);
+		println!("{:?}", completion.usage);
+	}
+}
+
 #[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
 pub struct GptModel {
 	name: String,
 	friendly_name: String,
 	input_cost: u32,
 	output_cost: u32,
-	api_version: u32,
+	/// In nanodollars per token, for input the API had cached. The same as `input_cost` if absent.
+	cached_input_cost: Option<u32>,
+	/// In nanodollars per request
+	#[serde(default)]
+	request_cost: u32,
+	/// In nanodollars per web search, for models that search the web on every request
+	#[serde(default)]
+	search_cost: u32,
+	/// Whether the model takes a temperature. Reasoning models typically don't.
+	#[serde(default = "supports_temperature_by_default")]
+	supports_temperature: bool,
+	/// The reasoning effort to ask for, for models that take one
+	reasoning_effort: Option<String>,
+	/// The verbosity to ask for, for models that take one
+	verbosity: Option<String>,
+	/// Whether the model searches the web
+	#[serde(default)]
+	web_search: bool,
+	/// The most tokens a completion may have, or the context length if absent
+	max_output_tokens: Option<u32>,
+	/// How many tokens replies may have for users who haven't set their own
+	default_output_tokens: Option<u32>,
+	/// Which API the model is reached through
+	#[serde(default)]
+	provider: ProviderKind,
+	/// Where the API is, if not at the provider's usual address
+	base_url: Option<String>,
+	#[serde(default)]
+	api_key: ApiKeySource,
+	/// Whether the model can take images as input
+	#[serde(default)]
+	supports_images: bool,
+	/// Used to estimate the cost of requests before sending them
+	#[serde(default)]
+	tokenizer: Tokenizer,
+	/// How many tokens the model can take in and put out in total
+	context_length: Option<u32>,
 }
 
 impl GptModel {
@@ -202,17 +448,61 @@ impl GptModel {
 	pub fn friendly_name(&self) -> &str {
 		&self.friendly_name
 	}
-	/// Get the cost of a query in nanodollars.
-	pub fn get_cost(&self, tokens: TokenUsage) -> u32 {
-		self.input_cost * tokens.prompt_tokens + self.output_cost * tokens.completion_tokens
+	/// Get the cost of a query in nanodollars. Reasoning tokens are part of the completion tokens, so they cost the same. Saturates rather than wrapping, so an absurd request is too expensive rather than cheap.
+	pub fn get_cost(&self, tokens: TokenUsage) -> u64 {
+		let cached_tokens = tokens
+			.prompt_tokens_details
+			.cached_tokens
+			.min(tokens.prompt_tokens);
+		[
+			(self.input_cost, tokens.prompt_tokens - cached_tokens),
+			(self.cached_input_cost(), cached_tokens),
+			(self.output_cost, tokens.completion_tokens),
+			(self.request_cost, tokens.requests),
+			(self.search_cost, self.search_calls(tokens)),
+		]
+		.into_iter()
+		.fold(0u64, |cost, (price, count)| {
+			cost.saturating_add(price as u64 * count as u64)
+		})
+	}
+	fn cached_input_cost(&self) -> u32 {
+		self.cached_input_cost.unwrap_or(self.input_cost)
+	}
+	/// How many times the model searched the web. Models that search do so on every request.
+	pub fn search_calls(&self, tokens: TokenUsage) -> u32 {
+		if self.web_search {
+			tokens.requests
+		} else {
+			0
+		}
 	}
 	/// Get a description of the cost of this model.
 	pub fn get_cost_description(&self) -> String {
-		format!(
+		let mut description = format!(
 			"{}$ per 1M input tokens, {}$ per 1M output tokens",
 			self.input_cost as f32 / 1000.0,
 			self.output_cost as f32 / 1000.0
-		)
+		);
+		if self.cached_input_cost() != self.input_cost {
+			description.push_str(&format!(
+				", {}$ per 1M cached input tokens",
+				self.cached_input_cost() as f32 / 1000.0
+			));
+		}
+		if self.request_cost != 0 {
+			description.push_str(&format!(
+				", {}$ per request",
+				self.request_cost as f32 / 1_000_000_000.0
+			));
+		}
+		if self.web_search && self.search_cost != 0 {
+			description.push_str(&format!(
+				", {}$ per search",
+				self.search_cost as f32 / 1_000_000_000.0
+			));
+		}
+		description
 	}
 	/// Get a brief description of the cost of this model.
 	pub fn get_brief_cost_description(&self) -> String {
@@ -222,15 +512,96 @@ impl GptModel {
 			self.output_cost as f32 / 1000.0
 		)
 	}
-	pub fn api_version(&self) -> u32 {
-		self.api_version
+	pub fn provider(&self) -> &'static dyn Provider {
+		self.provider.provider()
+	}
+	/// The base URL of the API, like `https://api.openai.com/v1`. Whether there is one is enforced on creating `Config`.
+	pub fn base_url(&self) -> &str {
+		self.base_url
+			.as_deref()
+			.or(self.provider().default_base_url())
+			.unwrap()
+	}
+	pub fn has_base_url(&self) -> bool {
+		self.base_url.is_some() || self.provider().default_base_url().is_some()
+	}
+	pub fn supports_images(&self) -> bool {
+		self.supports_images
+	}
+	pub fn tokenizer(&self) -> Tokenizer {
+		self.tokenizer
+	}
+	pub fn context_length(&self) -> u32 {
+		self.context_length.unwrap_or(DEFAULT_CONTEXT_LENGTH)
+	}
+	pub fn supports_temperature(&self) -> bool {
+		self.supports_temperature
+	}
+	/// The highest temperature the model's API takes.
+	pub fn max_temperature(&self) -> f32 {
+		self.provider().max_temperature()
+	}
+	/// The temperature to send, if the model takes one.
+	pub fn temperature(&self, sampling: Sampling) -> Option<f32> {
+		self.supports_temperature.then(|| {
+			sampling
+				.temperature
+				.unwrap_or(TEMPERATURE)
+				.min(self.max_temperature())
+		})
+	}
+	/// The most tokens a completion may have, which can't be more than fits in the context window.
+	pub fn max_output_tokens(&self) -> u32 {
+		self.max_output_tokens
+			.unwrap_or(u32::MAX)
+			.min(self.context_length())
 	}
+	/// The length of replies for users who haven't set their own, and of summaries.
+	pub fn default_reply_tokens(&self) -> u32 {
+		self.default_output_tokens
+			.unwrap_or(DEFAULT_OUTPUT_TOKENS)
+			.min(self.max_output_tokens())
+	}
+	/// The length of replies with the user's settings, which can't be more than the model puts out.
+	pub fn reply_tokens(&self, sampling: Sampling) -> u32 {
+		sampling
+			.max_tokens
+			.unwrap_or(self.default_reply_tokens())
+			.min(self.max_output_tokens())
+	}
+	/// Estimate the most a request could cost in nanodollars, if the completion used all the tokens it's allowed.
+	pub fn get_worst_case_cost(
+		&self,
+		history: &[ChatMessage],
+		tools: &[ToolDefinition],
+		sampling: Sampling,
+	) -> u64 {
+		self.get_cost(TokenUsage {
+			prompt_tokens: self.tokenizer.estimate_prompt_tokens(history, tools),
+			completion_tokens: self.reply_tokens(sampling),
+			requests: 1,
+			..Default::default()
+		})
+	}
+}
+
+fn supports_temperature_by_default() -> bool {
+	true
+}
+
+/// How a user wants their replies generated. What isn't set falls back to the defaults.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Sampling {
+	pub temperature: Option<f32>,
+	/// The maximum length of replies, in tokens
+	pub max_tokens: Option<u32>,
 }
 
 /// A role of a message sender, can be:
 /// - `System`, for starting system message, that sets the tone of model
 /// - `Assistant`, for messages sent by GPT
 /// - `User`, for messages sent by user
+/// - `Tool`, for the results of tools GPT called
 #[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Serialize, Deserialize, Eq, Ord)]
 #[serde(rename_all = "lowercase")]
 pub enum Role {
@@ -240,40 +611,154 @@ pub enum Role {
 	Assistant,
 	/// A message sent by the user
 	User,
+	/// The result of a tool call
+	Tool,
 }
 
 /// Container for the sent/received GPT messages
-#[derive(Debug, Clone, PartialEq, PartialOrd, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, PartialOrd, Deserialize)]
 pub struct ChatMessage {
 	/// Role of message sender
 	pub role: Role,
 	/// Actual content of the message
+	#[serde(default, deserialize_with = "deserialize_nullable_string")]
 	pub content: String,
+	/// URLs of attached images, only on user messages. They're sent as parts of the content, after the text.
+	#[serde(skip)]
+	pub images: Vec<String>,
+	/// The tools GPT wants to call, only on assistant messages
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub tool_calls: Vec<ToolCall>,
+	/// Which tool call this is the result of, only on tool messages
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub tool_call_id: Option<String>,
 }
 
 impl ChatMessage {
-	pub fn system(content: String) -> Self {
+	fn new(role: Role, content: String) -> Self {
 		Self {
-			role: Role::System,
+			role,
 			content,
+			images: Vec::new(),
+			tool_calls: Vec::new(),
+			tool_call_id: None,
 		}
 	}
+	pub fn system(content: String) -> Self {
+		Self::new(Role::System, content)
+	}
 	pub fn assistant(content: String) -> Self {
+		Self::new(Role::Assistant, content)
+	}
+	pub fn user(content: String) -> Self {
+		Self::new(Role::User, content)
+	}
+	pub fn user_with_images(content: String, images: Vec<String>) -> Self {
 		Self {
-			role: Role::Assistant,
-			content,
+			images,
+			..Self::user(content)
 		}
 	}
-	pub fn user(content: String) -> Self {
+	pub fn tool(tool_call_id: String, content: String) -> Self {
 		Self {
-			role: Role::User,
-			content,
+			tool_call_id: Some(tool_call_id),
+			..Self::new(Role::Tool, content)
 		}
 	}
 }
 
+/// Written by hand, because with images, the content becomes a list of parts.
+impl Serialize for ChatMessage {
+	fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+	where
+		S: Serializer,
+	{
+		let mut message = serializer.serialize_struct("ChatMessage", 4)?;
+		message.serialize_field("role", &self.role)?;
+		if self.images.is_empty() {
+			message.serialize_field("content", &self.content)?;
+		} else {
+			let text = (!self.content.is_empty()).then_some(ContentPart::Text {
+				text: &self.content,
+			});
+			let images = self.images.iter().map(|url| ContentPart::ImageUrl {
+				image_url: ImageUrl { url },
+			});
+			message.serialize_field(
+				"content",
+				&text.into_iter().chain(images).collect::<Vec<_>>(),
+			)?;
+		}
+		if self.tool_calls.is_empty() {
+			message.skip_field("tool_calls")?;
+		} else {
+			message.serialize_field("tool_calls", &self.tool_calls)?;
+		}
+		match &self.tool_call_id {
+			Some(tool_call_id) => message.serialize_field("tool_call_id", tool_call_id)?,
+			None => message.skip_field("tool_call_id")?,
+		}
+		message.end()
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentPart<'a> {
+	Text { text: &'a str },
+	ImageUrl { image_url: ImageUrl<'a> },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+struct ImageUrl<'a> {
+	url: &'a str,
+}
+
+/// The API sends `null` content for messages that only call tools.
+fn deserialize_nullable_string<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+	D: serde::Deserializer<'de>,
+{
+	Ok(Option::<String>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+/// A request from GPT to run one of the tools it was offered
+#[derive(Debug, Clone, PartialEq, PartialOrd, Serialize, Deserialize)]
+pub struct ToolCall {
+	/// Used to match the result to the call
+	pub id: String,
+	/// Always `function`
+	#[serde(rename = "type")]
+	pub call_type: String,
+	pub function: FunctionCall,
+}
+
+#[derive(Debug, Clone, PartialEq, PartialOrd, Serialize, Deserialize)]
+pub struct FunctionCall {
+	pub name: String,
+	/// The arguments as a JSON object, though GPT might not produce valid JSON
+	pub arguments: String,
+}
+
+/// A tool as offered to GPT
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ToolDefinition {
+	/// Always `function`
+	#[serde(rename = "type")]
+	pub tool_type: &'static str,
+	pub function: FunctionDefinition,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FunctionDefinition {
+	pub name: &'static str,
+	pub description: &'static str,
+	/// A JSON schema of the arguments
+	pub parameters: serde_json::Value,
+}
+
 /// A request struct sent to the API to request a message completion
-#[derive(Debug, Clone, PartialEq, PartialOrd, Serialize)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct CompletionRequest<'a> {
 	/// The model to be used, currently `gpt-3.5-turbo`, but may change in future
 	pub model: &'a str,
@@ -285,33 +770,62 @@ pub struct CompletionRequest<'a> {
 	/// The maximum number of tokens to generate in the chat completion
 	pub max_completion_tokens: u32,
 	#[serde(skip_serializing_if = "Option::is_none")]
-	verbosity: Option<&'static str>,
+	pub verbosity: Option<&'a str>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub reasoning_effort: Option<&'a str>,
+	/// Present for models that search the web
 	#[serde(skip_serializing_if = "Option::is_none")]
-	reasoning_effort: Option<&'static str>,
+	pub web_search_options: Option<WebSearchOptions>,
+	/// Whether to send the completion back piece by piece, as server-sent events
+	#[serde(skip_serializing_if = "std::ops::Not::not")]
+	pub stream: bool,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub stream_options: Option<StreamOptions>,
+	/// The tools GPT may call instead of answering directly
+	#[serde(skip_serializing_if = "<[_]>::is_empty")]
+	pub tools: &'a [ToolDefinition],
 }
 
-impl<'a> CompletionRequest<'a> {
-	pub fn new(model: &'a str, api_version: u32) -> Self {
-		let is_new_api = api_version == 2;
-		let is_search_api = api_version == 3;
+/// Only needs to be present, the defaults are fine.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Serialize)]
+pub struct WebSearchOptions {}
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Serialize)]
+pub struct StreamOptions {
+	/// Makes the API send one more chunk at the end with the token usage of the whole completion
+	pub include_usage: bool,
+}
 
+impl<'a> CompletionRequest<'a> {
+	pub fn new(model: &'a GptModel, sampling: Sampling) -> Self {
 		Self {
-			model,
+			model: model.name(),
 			messages: &[],
-			temperature: (!is_new_api && !is_search_api).then_some(TEMPERATURE),
-			max_completion_tokens: if is_new_api {
-				MAX_TOKENS * 4
-			} else {
-				MAX_TOKENS
-			},
-			verbosity: is_new_api.then_some("low"),
-			reasoning_effort: is_new_api.then_some("minimal"),
+			temperature: model.temperature(sampling),
+			max_completion_tokens: model.reply_tokens(sampling),
+			verbosity: model.verbosity.as_deref(),
+			reasoning_effort: model.reasoning_effort.as_deref(),
+			web_search_options: model.web_search.then_some(WebSearchOptions {}),
+			stream: false,
+			stream_options: None,
+			tools: &[],
 		}
 	}
 	pub fn with_messages(mut self, messages: &'a [ChatMessage]) -> Self {
 		self.messages = messages;
 		self
 	}
+	pub fn with_tools(mut self, tools: &'a [ToolDefinition]) -> Self {
+		self.tools = tools;
+		self
+	}
+	pub fn with_streaming(mut self) -> Self {
+		self.stream = true;
+		self.stream_options = Some(StreamOptions {
+			include_usage: true,
+		});
+		self
+	}
 }
 
 /// Represents a response from the API
@@ -335,6 +849,9 @@ pub struct CompletionError {
 	/// The type of error. Example: `server_error`
 	#[serde(rename = "type")]
 	pub error_type: String,
+	/// A more specific error code, if any. Example: `context_length_exceeded`
+	#[serde(default)]
+	pub code: Option<String>,
 }
 
 /// A response struct received from the API after requesting a message completion
@@ -368,7 +885,7 @@ pub struct MessageChoice {
 }
 
 /// The token usage of a specific response
-#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Deserialize)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, PartialOrd, Deserialize)]
 pub struct TokenUsage {
 	/// Tokens spent on the prompt message (including previous messages)
 	pub prompt_tokens: u32,
@@ -377,12 +894,32 @@ pub struct TokenUsage {
 	/// Total amount of tokens used (`prompt_tokens + completion_tokens`)
 	pub total_tokens: u32,
 	/// "Breakdown of tokens used in a completion."
+	#[serde(default)]
 	pub completion_tokens_details: CompletionTokenDetails,
 	/// "Breakdown of tokens used in the prompt."
+	#[serde(default)]
 	pub prompt_tokens_details: PromptTokenDetails,
+	/// How many requests the usage is from. The API doesn't send this, it's set to 1 on receiving a response.
+	#[serde(skip)]
+	pub requests: u32,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Deserialize)]
+/// Saturates rather than wrapping, like `GptModel::get_cost`.
+impl std::ops::AddAssign for TokenUsage {
+	fn add_assign(&mut self, other: Self) {
+		self.prompt_tokens = self.prompt_tokens.saturating_add(other.prompt_tokens);
+		self.completion_tokens = self
+			.completion_tokens
+			.saturating_add(other.completion_tokens);
+		self.total_tokens = self.total_tokens.saturating_add(other.total_tokens);
+		self.completion_tokens_details += other.completion_tokens_details;
+		self.prompt_tokens_details += other.prompt_tokens_details;
+		self.requests = self.requests.saturating_add(other.requests);
+	}
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, PartialOrd, Deserialize)]
+#[serde(default)]
 pub struct PromptTokenDetails {
 	/// "Cached tokens present in the prompt."
 	pub cached_tokens: u32,
@@ -390,7 +927,15 @@ pub struct PromptTokenDetails {
 	pub audio_tokens: u32,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Deserialize)]
+impl std::ops::AddAssign for PromptTokenDetails {
+	fn add_assign(&mut self, other: Self) {
+		self.cached_tokens = self.cached_tokens.saturating_add(other.cached_tokens);
+		self.audio_tokens = self.audio_tokens.saturating_add(other.audio_tokens);
+	}
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, PartialOrd, Deserialize)]
+#[serde(default)]
 pub struct CompletionTokenDetails {
 	/// "Tokens generated by the model for reasoning."
 	pub reasoning_tokens: u32,
@@ -402,6 +947,158 @@ pub struct CompletionTokenDetails {
 	pub rejected_prediction_tokens: u32,
 }
 
+impl std::ops::AddAssign for CompletionTokenDetails {
+	fn add_assign(&mut self, other: Self) {
+		self.reasoning_tokens = self.reasoning_tokens.saturating_add(other.reasoning_tokens);
+		self.audio_tokens = self.audio_tokens.saturating_add(other.audio_tokens);
+		self.accepted_prediction_tokens = self
+			.accepted_prediction_tokens
+			.saturating_add(other.accepted_prediction_tokens);
+		self.rejected_prediction_tokens = self
+			.rejected_prediction_tokens
+			.saturating_add(other.rejected_prediction_tokens);
+	}
+}
+
+/// A completion that is still arriving from the API as server-sent events.
+pub struct CompletionStream {
+	response: reqwest::Response,
+	provider: &'static dyn Provider,
+	/// Bytes received that do not form a complete event yet
+	buffer: Vec<u8>,
+	partial: PartialCompletion,
+	/// For providers that may not send the token usage: the tokenizer and estimated prompt tokens to estimate it with instead
+	prompt_estimate: Option<(Tokenizer, u32)>,
+}
+
+/// Everything received so far of a completion that is being streamed.
+#[derive(Debug, Clone, Default)]
+pub struct PartialCompletion {
+	pub message_id: Option<String>,
+	pub created_timestamp: Option<u64>,
+	pub model: String,
+	pub content: String,
+	pub finish_reason: Option<String>,
+	/// Tool calls so far, the last of which may still be incomplete
+	pub tool_calls: Vec<ToolCall>,
+	pub usage: Option<TokenUsage>,
+	/// Whether the API said it is done
+	pub done: bool,
+}
+
+impl CompletionStream {
+	fn new(
+		response: reqwest::Response,
+		provider: &'static dyn Provider,
+		prompt_estimate: Option<(Tokenizer, u32)>,
+	) -> Self {
+		Self {
+			response,
+			provider,
+			buffer: Vec::new(),
+			partial: PartialCompletion::default(),
+			prompt_estimate,
+		}
+	}
+	/// Waits for more of the completion to arrive. Returns `Ok(true)` if anything arrived, and `Ok(false)` once the API has said it is done.
+	///
+	/// An error means the stream died before it was done, or finished without reporting the token usage when the provider should have. Whatever arrived before that is still available through `content`.
+	pub async fn advance(&mut self) -> Result<bool, GptError> {
+		loop {
+			if self.partial.done {
+				if self.partial.usage.is_none() {
+					let Some((tokenizer, prompt_tokens)) = self.prompt_estimate else {
+						return Err(GptError::MalformedBody(String::from(
+							"No token usage received.",
+						)));
+					};
+					let completion = ChatMessage {
+						tool_calls: self.partial.tool_calls.clone(),
+						..ChatMessage::assistant(self.partial.content.clone())
+					};
+					let completion_tokens = tokenizer.estimate_message_tokens(&completion);
+					self.partial.usage = Some(TokenUsage {
+						prompt_tokens,
+						completion_tokens,
+						total_tokens: prompt_tokens.saturating_add(completion_tokens),
+						..Default::default()
+					});
+				}
+				return Ok(false);
+			}
+			if let Some(data) = take_event(&mut self.buffer) {
+				// Empty data is from comments and keep-alives.
+				if !data.is_empty()
+					&& self
+						.provider
+						.handle_stream_event(&data, &mut self.partial)?
+				{
+					return Ok(true);
+				}
+				continue;
+			}
+			match tokio::time::timeout(CHUNK_TIMEOUT, self.response.chunk()).await {
+				Ok(Ok(Some(chunk))) => self.buffer.extend_from_slice(&chunk),
+				Ok(Ok(None)) => return Err(GptError::Interrupted),
+				Ok(Err(error)) => return Err(GptError::Transport(error)),
+				Err(_) => return Err(GptError::TimedOut),
+			}
+		}
+	}
+	/// The text of the completion so far.
+	pub fn content(&self) -> &str {
+		&self.partial.content
+	}
+	/// Turns the finished stream into a regular response. Only meaningful once `advance` has returned `Ok(false)`.
+	pub fn into_response(self) -> CompletionResponse {
+		let partial = self.partial;
+		let completion = CompletionResponse {
+			message_id: partial.message_id,
+			created_timestamp: partial.created_timestamp,
+			model: partial.model,
+			usage: TokenUsage {
+				requests: 1,
+				..partial.usage.unwrap_or_default()
+			},
+			message_choices: vec![MessageChoice {
+				message: ChatMessage {
+					tool_calls: partial.tool_calls,
+					..ChatMessage::assistant(partial.content)
+				},
+				finish_reason: partial
+					.finish_reason
+					.unwrap_or_else(|| String::from("null")),
+				index: 0,
+			}],
+		};
+		warn_about_fancy_tokens(&completion);
+		completion
+	}
+}
+
+/// Removes the first complete server-sent event from the buffer, and returns its data.
+fn take_event(buffer: &mut Vec<u8>) -> Option<String> {
+	let (end, separator_length) = buffer
+		.windows(2)
+		.position(|window| window == b"\n\n")
+		.map(|position| (position, 2))
+		.or_else(|| {
+			buffer
+				.windows(4)
+				.position(|window| window == b"\r\n\r\n")
+				.map(|position| (position, 4))
+		})?;
+	let event: Vec<u8> = buffer.drain(..end + separator_length).collect();
+	let event = String::from_utf8_lossy(&event[..end]);
+	let data = event
+		.lines()
+		.filter_map(|line| line.strip_prefix("data:"))
+		.map(|data| data.strip_prefix(' ').unwrap_or(data))
+		.collect::<Vec<_>>()
+		.join("\n");
+	Some(data)
+}
+
 #[extend::ext]
 impl reqwest::Response {
 	async fn json_and_text(self) -> (ServerResponse, String) {
@@ -426,14 +1123,223 @@ impl reqwest::Response {
 
 		(response, text.to_string())
 	}
-	async fn json_or_raw<T: serde::de::DeserializeOwned>(self) -> Result<T, String> {
+	async fn parse_or_raw(self, provider: &dyn Provider) -> Result<ServerResponse, GptError> {
 		let status_code = self.status();
-		let full = self
-			.bytes()
-			.await
-			.map_err(|_| String::from("Problem getting response body as Bytes"))?;
+		let full = self.bytes().await.map_err(GptError::Transport)?;
+
+		provider.parse_response(&full).map_err(|err| {
+			GptError::MalformedBody(format!(
+				"Error: {err}, status code: {status_code}, response: {full:?}"
+			))
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use serde_json::json;
+
+	use super::{
+		take_event, CompletionTokenDetails, GptModel, PartialCompletion, PromptTokenDetails,
+		Sampling, TokenUsage,
+	};
+	use crate::{gpt_error::GptError, providers::ProviderKind};
+
+	/// A model costing 1 nanodollar per input token and 4 per output token, with the other settings added.
+	fn model(settings: serde_json::Value) -> GptModel {
+		let mut model = json!({
+			"name": "gpt-test",
+			"friendly_name": "GPT Test",
+			"input_cost": 1,
+			"output_cost": 4,
+		});
+		model
+			.as_object_mut()
+			.unwrap()
+			.extend(settings.as_object().unwrap().clone());
+		serde_json::from_value(model).unwrap()
+	}
+
+	/// Feeds the events to the OpenAI provider, as if they came in one stream.
+	fn stream(events: &[&str]) -> Result<PartialCompletion, GptError> {
+		let provider = ProviderKind::Openai.provider();
+		let mut partial = PartialCompletion::default();
+		for data in events {
+			provider.handle_stream_event(data, &mut partial)?;
+		}
+		Ok(partial)
+	}
+
+	#[test]
+	fn events_are_taken_once_complete() {
+		let mut buffer = b"data: {\"a\":".to_vec();
+		assert_eq!(take_event(&mut buffer), None);
+		buffer.extend_from_slice(b"1}\n\ndata: [DONE]\n");
+		assert_eq!(take_event(&mut buffer).as_deref(), Some("{\"a\":1}"));
+		assert_eq!(take_event(&mut buffer), None);
+		buffer.extend_from_slice(b"\n");
+		assert_eq!(take_event(&mut buffer).as_deref(), Some("[DONE]"));
+		assert!(buffer.is_empty());
+	}
+
+	#[test]
+	fn event_data_lines_are_joined_and_other_fields_ignored() {
+		let mut buffer =
+			b": keep-alive\r\n\r\nevent: message\r\ndata:first\r\ndata: second\r\n\r\n".to_vec();
+		assert_eq!(take_event(&mut buffer).as_deref(), Some(""));
+		assert_eq!(take_event(&mut buffer).as_deref(), Some("first\nsecond"));
+		assert_eq!(take_event(&mut buffer), None);
+	}
+
+	#[test]
+	fn openai_chunks_add_up_to_a_completion() {
+		let partial = stream(&[
+			r#"{"id":"chatcmpl-1","created":1700000000,"model":"gpt-test","choices":[{"index":0,"delta":{"role":"assistant","content":"Hel"}}]}"#,
+			r#"{"id":"chatcmpl-1","created":1700000000,"model":"gpt-test","choices":[{"index":0,"delta":{"content":"lo!"},"finish_reason":"stop"}]}"#,
+			r#"{"id":"chatcmpl-1","created":1700000000,"model":"gpt-test","choices":[],"usage":{"prompt_tokens":10,"completion_tokens":2,"total_tokens":12}}"#,
+			"[DONE]",
+		])
+		.unwrap();
+		assert_eq!(partial.content, "Hello!");
+		assert_eq!(partial.message_id.as_deref(), Some("chatcmpl-1"));
+		assert_eq!(partial.finish_reason.as_deref(), Some("stop"));
+		let usage = partial.usage.unwrap();
+		assert_eq!((usage.prompt_tokens, usage.completion_tokens), (10, 2));
+		assert!(partial.done);
+	}
+
+	#[test]
+	fn openai_tool_call_arguments_arrive_in_pieces() {
+		let partial = stream(&[
+			r#"{"model":"gpt-test","choices":[{"index":0,"delta":{"tool_calls":[{"index":0,"id":"call_1","type":"function","function":{"name":"calculate","arguments":""}}]}}]}"#,
+			r#"{"model":"gpt-test","choices":[{"index":0,"delta":{"tool_calls":[{"index":0,"function":{"arguments":"{\"expression\":"}}]}}]}"#,
+			r#"{"model":"gpt-test","choices":[{"index":0,"delta":{"tool_calls":[{"index":0,"function":{"arguments":"\"1+1\"}"}}]}}]}"#,
+			r#"{"model":"gpt-test","choices":[{"index":0,"delta":{"tool_calls":[{"index":1,"id":"call_2","type":"function","function":{"name":"current_time","arguments":"{}"}}]},"finish_reason":"tool_calls"}]}"#,
+		])
+		.unwrap();
+		assert_eq!(partial.tool_calls.len(), 2);
+		assert_eq!(partial.tool_calls[0].id, "call_1");
+		assert_eq!(partial.tool_calls[0].function.name, "calculate");
+		assert_eq!(
+			partial.tool_calls[0].function.arguments,
+			r#"{"expression":"1+1"}"#
+		);
+		assert_eq!(partial.tool_calls[1].function.name, "current_time");
+		assert_eq!(partial.finish_reason.as_deref(), Some("tool_calls"));
+		assert!(!partial.done);
+	}
+
+	#[test]
+	fn openai_stream_errors_are_classified() {
+		let error = stream(&[
+			r#"{"error":{"message":"The server had an error.","type":"server_error","param":null,"code":null}}"#,
+		])
+		.unwrap_err();
+		assert_eq!(error.user_message(), "Boop bloop, server error.");
+
+		let error = stream(&["not json"]).unwrap_err();
+		assert!(matches!(error, GptError::MalformedBody(_)), "{error}");
+	}
+
+	#[test]
+	fn cached_input_is_cheaper_and_reasoning_is_output() {
+		let model = model(json!({ "cached_input_cost": 0 }));
+		let usage = TokenUsage {
+			prompt_tokens: 1000,
+			completion_tokens: 300,
+			total_tokens: 1300,
+			prompt_tokens_details: PromptTokenDetails {
+				cached_tokens: 600,
+				..Default::default()
+			},
+			completion_tokens_details: CompletionTokenDetails {
+				reasoning_tokens: 200,
+				..Default::default()
+			},
+			requests: 1,
+		};
+		assert_eq!(model.get_cost(usage), 400 + 300 * 4);
+
+		// More cached tokens than prompt tokens can't make the prompt cost less than nothing.
+		let overcached = TokenUsage {
+			prompt_tokens_details: PromptTokenDetails {
+				cached_tokens: 5000,
+				..Default::default()
+			},
+			..usage
+		};
+		assert_eq!(model.get_cost(overcached), 300 * 4);
+	}
+
+	#[test]
+	fn requests_and_searches_are_charged_each() {
+		let usage = TokenUsage {
+			prompt_tokens: 100,
+			completion_tokens: 10,
+			requests: 3,
+			..Default::default()
+		};
+		let searching = model(json!({
+			"request_cost": 1000,
+			"search_cost": 10_000,
+			"web_search": true,
+		}));
+		assert_eq!(searching.search_calls(usage), 3);
+		assert_eq!(searching.get_cost(usage), 100 + 40 + 3 * 1000 + 3 * 10_000);
+
+		let not_searching = model(json!({ "request_cost": 1000, "search_cost": 10_000 }));
+		assert_eq!(not_searching.search_calls(usage), 0);
+		assert_eq!(not_searching.get_cost(usage), 100 + 40 + 3 * 1000);
+	}
+
+	#[test]
+	fn huge_usage_saturates() {
+		let model = model(json!({
+			"input_cost": u32::MAX,
+			"output_cost": u32::MAX,
+			"request_cost": u32::MAX,
+		}));
+		let usage = TokenUsage {
+			prompt_tokens: u32::MAX,
+			completion_tokens: u32::MAX,
+			requests: u32::MAX,
+			..Default::default()
+		};
+		assert_eq!(model.get_cost(usage), u64::MAX);
+	}
+
+	#[test]
+	fn adding_huge_usage_saturates() {
+		let mut usage = TokenUsage {
+			prompt_tokens: u32::MAX - 1,
+			requests: 1,
+			..Default::default()
+		};
+		usage += TokenUsage {
+			prompt_tokens: 10,
+			completion_tokens: 10,
+			requests: 1,
+			..Default::default()
+		};
+		assert_eq!(usage.prompt_tokens, u32::MAX);
+		assert_eq!((usage.completion_tokens, usage.requests), (10, 2));
+	}
 
-		serenity::json::from_slice(&full)
-			.map_err(|err| format!("Error: {err}, status code: {status_code}, response: {full:?}"))
+	#[test]
+	fn reply_length_is_capped_by_the_model() {
+		let model = model(json!({
+			"max_output_tokens": 1000,
+			"default_output_tokens": 200,
+			"context_length": 800,
+		}));
+		assert_eq!(model.max_output_tokens(), 800);
+		assert_eq!(model.default_reply_tokens(), 200);
+		let sampling = |max_tokens| Sampling {
+			max_tokens,
+			..Default::default()
+		};
+		assert_eq!(model.reply_tokens(sampling(None)), 200);
+		assert_eq!(model.reply_tokens(sampling(Some(500))), 500);
+		assert_eq!(model.reply_tokens(sampling(Some(5000))), 800);
 	}
 }
@@ -0,0 +1,313 @@
+use std::{fmt::Display, time::Duration};
+
+use chrono::{DateTime, Utc};
+use reqwest::{header::HeaderMap, StatusCode};
+
+use crate::gpt::CompletionError;
+
+/// How many times a request is attempted in total before giving up.
+const MAX_ATTEMPTS: u32 = 4;
+/// The delay before the first retry, doubled for every retry after.
+const BASE_RETRY_DELAY: Duration = Duration::from_secs(1);
+/// If the API asks to wait longer than this, it's not worth keeping the user waiting.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Everything that can go wrong getting a completion.
+#[derive(Debug)]
+pub enum GptError {
+	/// The request could not be sent, or the response could not be received
+	Transport(reqwest::Error),
+	/// Too many requests or tokens in a short time
+	RateLimited { retry_after: Option<Duration> },
+	/// The account is out of credit
+	QuotaExhausted,
+	/// The conversation is too long for the model
+	ContextLengthExceeded,
+	/// Any other error reported by the API
+	Api {
+		/// Absent for errors sent in the middle of a stream
+		status: Option<StatusCode>,
+		error: Option<CompletionError>,
+		retry_after: Option<Duration>,
+	},
+	/// The response was not in the expected format
+	MalformedBody(String),
+	/// A stream ended before the API said it was done
+	Interrupted,
+	/// The API took too long to start responding, or stopped sending in the middle of a stream
+	TimedOut,
+}
+
+impl GptError {
+	/// Classifies an error reported by the API, either through the status code, the error body or both.
+	pub fn from_api_error(
+		status: Option<StatusCode>,
+		error: Option<CompletionError>,
+		retry_after: Option<Duration>,
+	) -> Self {
+		let error_type = error.as_ref().map(|error| error.error_type.as_str());
+		let code = error.as_ref().and_then(|error| error.code.as_deref());
+		let message = error
+			.as_ref()
+			.map(|error| error.message.to_lowercase())
+			.unwrap_or_default();
+		if error_type == Some("insufficient_quota") || code == Some("insufficient_quota") {
+			Self::QuotaExhausted
+		} else if code == Some("context_length_exceeded")
+			|| message.contains("context length")
+			|| message.contains("prompt is too long")
+		{
+			Self::ContextLengthExceeded
+		} else if status == Some(StatusCode::TOO_MANY_REQUESTS)
+			|| matches!(
+				error_type,
+				Some("requests" | "tokens" | "rate_limit_error" | "rate_limit_exceeded")
+			) {
+			Self::RateLimited { retry_after }
+		} else {
+			Self::Api {
+				status,
+				error,
+				retry_after,
+			}
+		}
+	}
+	/// Classifies an unsuccessful response, using its headers to find out how long to wait before retrying.
+	pub fn from_response_parts(
+		status: StatusCode,
+		headers: &HeaderMap,
+		error: Option<CompletionError>,
+	) -> Self {
+		Self::from_api_error(Some(status), error, retry_after_from_headers(headers))
+	}
+	/// How long to wait before making attempt number `attempt + 1`, or `None` if it should not be retried.
+	///
+	/// Requests that timed out aren't retried, since the API may already be working on them, and charging for them.
+	pub fn retry_delay(&self, attempt: u32) -> Option<Duration> {
+		if attempt >= MAX_ATTEMPTS {
+			return None;
+		}
+		let backoff = BASE_RETRY_DELAY * 2u32.pow(attempt - 1);
+		let delay = match self {
+			Self::Transport(error) if error.is_connect() => backoff,
+			Self::RateLimited { retry_after } => retry_after.unwrap_or(backoff),
+			Self::Api { retry_after, .. } if self.is_server_error() => {
+				retry_after.unwrap_or(backoff)
+			}
+			_ => return None,
+		};
+		(delay <= MAX_RETRY_DELAY).then_some(delay)
+	}
+	fn is_server_error(&self) -> bool {
+		let Self::Api { status, error, .. } = self else {
+			return false;
+		};
+		status.is_some_and(|status| status.is_server_error())
+			|| error.as_ref().is_some_and(|error| {
+				matches!(
+					error.error_type.as_str(),
+					"server_error" | "api_error" | "overloaded_error"
+				)
+			})
+	}
+	/// The text to show the user when this error ends their request.
+	pub fn user_message(&self) -> &'static str {
+		match self {
+			Self::Transport(_) => "Boop beep, problem sending request.",
+			Self::RateLimited { .. } => "Beep bloop, rate-limited. Try again in a bit.",
+			Self::QuotaExhausted => "Boop bloop, out of credit.",
+			Self::ContextLengthExceeded => {
+				"Boop bloop, this conversation is too long for the model."
+			}
+			Self::Api { .. } if self.is_server_error() => "Boop bloop, server error.",
+			Self::Api { .. } | Self::MalformedBody(_) => "Boop bloop, unknown error",
+			Self::Interrupted => "Boop bloop, the response got cut off.",
+			Self::TimedOut => "Boop bloop, the response took too long.",
+		}
+	}
+}
+
+impl Display for GptError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::Transport(error) => write!(f, "transport error: {error}"),
+			Self::RateLimited { retry_after } => {
+				write!(f, "rate-limited, retry after {retry_after:?}")
+			}
+			Self::QuotaExhausted => f.write_str("quota exhausted"),
+			Self::ContextLengthExceeded => f.write_str("context length exceeded"),
+			Self::Api { status, error, .. } => {
+				write!(f, "API error")?;
+				if let Some(status) = status {
+					write!(f, ", status code {status}")?;
+				}
+				if let Some(error) = error {
+					write!(f, ": {}, {}", error.message, error.error_type)?;
+				}
+				Ok(())
+			}
+			Self::MalformedBody(details) => write!(f, "malformed response: {details}"),
+			Self::Interrupted => f.write_str("stream ended before it was done"),
+			Self::TimedOut => f.write_str("timed out"),
+		}
+	}
+}
+
+/// Finds out how long the API wants us to wait, from `retry-after` if present, or else from OpenAI's `x-ratelimit-*` headers.
+fn retry_after_from_headers(headers: &HeaderMap) -> Option<Duration> {
+	let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+	if let Some(milliseconds) = header("retry-after-ms").and_then(|value| value.parse().ok()) {
+		return Some(Duration::from_millis(milliseconds));
+	}
+	if let Some(value) = header("retry-after") {
+		if let Ok(seconds) = value.parse::<f32>() {
+			// Too long to represent is as good as forever.
+			return Some(Duration::try_from_secs_f32(seconds.max(0.0)).unwrap_or(Duration::MAX));
+		}
+		if let Ok(date) = DateTime::parse_from_rfc2822(value) {
+			return Some(
+				(date.with_timezone(&Utc) - Utc::now())
+					.to_std()
+					.unwrap_or_default(),
+			);
+		}
+	}
+	// Wait for whichever limit ran out, or for both if it's unclear which did.
+	let exhausted = |kind: &str| {
+		header(&format!("x-ratelimit-remaining-{kind}")).is_none_or(|remaining| remaining == "0")
+	};
+	["requests", "tokens"]
+		.into_iter()
+		.filter(|kind| exhausted(kind))
+		.filter_map(|kind| header(&format!("x-ratelimit-reset-{kind}")))
+		.filter_map(parse_reset_duration)
+		.max()
+}
+
+/// Parses durations in the format of OpenAI's rate limit headers, like `"6m0s"`, `"1.5s"` or `"20ms"`.
+fn parse_reset_duration(text: &str) -> Option<Duration> {
+	let mut total = 0.0;
+	let mut rest = text.trim();
+	while !rest.is_empty() {
+		let number_end = rest.find(|char: char| !char.is_ascii_digit() && char != '.')?;
+		let number: f32 = rest[..number_end].parse().ok()?;
+		rest = &rest[number_end..];
+		let unit_end = rest
+			.find(|char: char| char.is_ascii_digit())
+			.unwrap_or(rest.len());
+		let multiplier = match &rest[..unit_end] {
+			"h" => 3600.0,
+			"m" => 60.0,
+			"s" => 1.0,
+			"ms" => 0.001,
+			_ => return None,
+		};
+		total += number * multiplier;
+		rest = &rest[unit_end..];
+	}
+	Duration::try_from_secs_f32(total).ok()
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use reqwest::header::{HeaderMap, HeaderValue};
+
+	use super::{parse_reset_duration, retry_after_from_headers, GptError};
+
+	fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+		pairs
+			.iter()
+			.map(|(name, value)| (*name, HeaderValue::from_str(value).unwrap()))
+			.fold(HeaderMap::new(), |mut headers, (name, value)| {
+				headers.insert(name, value);
+				headers
+			})
+	}
+
+	#[test]
+	fn reset_durations_are_parsed() {
+		assert_eq!(parse_reset_duration("6m0s"), Some(Duration::from_secs(360)));
+		assert_eq!(
+			parse_reset_duration("1.5s"),
+			Some(Duration::from_millis(1500))
+		);
+		assert_eq!(
+			parse_reset_duration("1h2m3s"),
+			Some(Duration::from_secs(3723))
+		);
+		let milliseconds = parse_reset_duration("20ms").unwrap();
+		assert_eq!(milliseconds.as_millis(), 20, "{milliseconds:?}");
+		assert_eq!(parse_reset_duration(""), Some(Duration::ZERO));
+		assert_eq!(parse_reset_duration("5"), None);
+		assert_eq!(parse_reset_duration("5 days"), None);
+		assert_eq!(parse_reset_duration("s"), None);
+		assert_eq!(parse_reset_duration(&format!("{}h", "9".repeat(50))), None);
+	}
+
+	#[test]
+	fn retry_after_prefers_the_standard_headers() {
+		assert_eq!(
+			retry_after_from_headers(&headers(&[("retry-after-ms", "250"), ("retry-after", "3")])),
+			Some(Duration::from_millis(250))
+		);
+		assert_eq!(
+			retry_after_from_headers(&headers(&[("retry-after", "3")])),
+			Some(Duration::from_secs(3))
+		);
+		assert_eq!(
+			retry_after_from_headers(&headers(&[("retry-after", "1e30")])),
+			Some(Duration::MAX)
+		);
+		let date = (chrono::Utc::now() + chrono::Duration::seconds(60)).to_rfc2822();
+		let until_date = retry_after_from_headers(&headers(&[("retry-after", &date)])).unwrap();
+		assert!(
+			(Duration::from_secs(58)..=Duration::from_secs(60)).contains(&until_date),
+			"{until_date:?}"
+		);
+		assert_eq!(retry_after_from_headers(&HeaderMap::new()), None);
+	}
+
+	#[test]
+	fn retry_after_waits_for_the_exhausted_rate_limit() {
+		let tokens_exhausted = headers(&[
+			("x-ratelimit-remaining-requests", "10"),
+			("x-ratelimit-reset-requests", "1s"),
+			("x-ratelimit-remaining-tokens", "0"),
+			("x-ratelimit-reset-tokens", "6m0s"),
+		]);
+		assert_eq!(
+			retry_after_from_headers(&tokens_exhausted),
+			Some(Duration::from_secs(360))
+		);
+		let unclear = headers(&[
+			("x-ratelimit-reset-requests", "1s"),
+			("x-ratelimit-reset-tokens", "2s"),
+		]);
+		assert_eq!(
+			retry_after_from_headers(&unclear),
+			Some(Duration::from_secs(2))
+		);
+	}
+
+	#[test]
+	fn retries_back_off_and_give_up_on_long_waits() {
+		let rate_limited = GptError::RateLimited { retry_after: None };
+		assert_eq!(rate_limited.retry_delay(1), Some(Duration::from_secs(1)));
+		assert_eq!(rate_limited.retry_delay(3), Some(Duration::from_secs(4)));
+		assert_eq!(rate_limited.retry_delay(4), None);
+
+		let told_to_wait = GptError::RateLimited {
+			retry_after: Some(Duration::from_secs(5)),
+		};
+		assert_eq!(told_to_wait.retry_delay(1), Some(Duration::from_secs(5)));
+		let told_to_wait_long = GptError::RateLimited {
+			retry_after: Some(Duration::from_secs(360)),
+		};
+		assert_eq!(told_to_wait_long.retry_delay(1), None);
+
+		assert_eq!(GptError::TimedOut.retry_delay(1), None);
+		assert_eq!(GptError::QuotaExhausted.retry_delay(1), None);
+	}
+}
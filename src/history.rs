@@ -0,0 +1,303 @@
+//! Fitting the history of a conversation into a model's context window.
+
+use sqlx::{query, Pool, Sqlite};
+
+use crate::{
+	conversations::MessageIds,
+	gpt::{ChatMessage, Gpt, GptModel, Sampling, TokenUsage},
+	gpt_error::GptError,
+};
+
+const SUMMARY_SYSTEM_MESSAGE: &str = "Summarize the conversation between a user and an AI assistant that the user gives you. Keep it brief, but keep any facts, names and decisions that later messages might refer back to. Reply with only the summary.";
+
+/// Starts a user message with the name of who sent it, so the model can tell people apart.
+pub fn attribute(mut message: ChatMessage, name: Option<&str>) -> ChatMessage {
+	if let Some(name) = name {
+		message.content = format!("{name}: {}", message.content);
+	}
+	message
+}
+
+/// The most summarizing exchanges of the given estimated tokens could cost, with the earlier summary it builds on and the summary it makes.
+fn worst_case_summary_cost(model: &GptModel, exchange_tokens: &[u32]) -> u64 {
+	let transcript_tokens = exchange_tokens
+		.iter()
+		.sum::<u32>()
+		.min(model.context_length());
+	model.get_cost(TokenUsage {
+		prompt_tokens: transcript_tokens
+			+ model.default_reply_tokens()
+			+ model.tokenizer().count(SUMMARY_SYSTEM_MESSAGE),
+		completion_tokens: model.default_reply_tokens(),
+		requests: 1,
+		..Default::default()
+	})
+}
+
+/// One input and output stored in the database.
+pub struct Exchange {
+	message: i64,
+	input: ChatMessage,
+	/// Who sent the input, unless it's from before names were stored
+	author_name: Option<String>,
+	output: ChatMessage,
+	/// A summary of the conversation up to and including this exchange, if one was ever needed
+	summary: Option<String>,
+}
+
+impl Exchange {
+	pub fn clear_images(&mut self) {
+		self.input.images.clear();
+	}
+}
+
+/// How much of a conversation had to be left out to fit in the model's context window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trimmed {
+	Nothing,
+	/// This many of the earliest exchanges were left out.
+	Dropped(usize),
+	/// This many of the earliest exchanges were replaced by a summary.
+	Summarized(usize),
+}
+
+impl Trimmed {
+	/// A note for the footer of the reply, if anything was left out.
+	pub fn note(self) -> Option<String> {
+		let (count, what) = match self {
+			Self::Nothing => return None,
+			Self::Dropped(count) => (count, "left out"),
+			Self::Summarized(count) => (count, "summarized"),
+		};
+		let turns = if count == 1 { "turn" } else { "turns" };
+		Some(format!("({count} earlier {turns} {what})"))
+	}
+}
+
+/// Gets the exchanges leading up to and including `parent`, newest first, leaving out deleted ones. There are none if `parent` itself was deleted.
+pub async fn get_exchanges_from_database(
+	executor: &Pool<Sqlite>,
+	parent: MessageIds,
+) -> Vec<Exchange> {
+	let (guild_id, channel_id, message_id) = parent.as_i64s();
+	query!(
+		"
+		WITH RECURSIVE chain (
+			message_n,
+			next,
+			input_n,
+			output_n,
+			attachments_n,
+			summary_n,
+			deleted_n,
+			author_name_n
+		)
+		AS (
+			SELECT message,
+				parent,
+				input,
+				output,
+				attachments,
+				summary,
+				deleted,
+				author_name
+			FROM conversations
+			WHERE message = ? AND channel = ? AND guild IS ? AND NOT deleted
+			UNION ALL
+			SELECT message,
+				parent,
+				input,
+				output,
+				attachments,
+				summary,
+				deleted,
+				author_name
+			FROM chain,
+				conversations
+			WHERE message = next
+		)
+		SELECT message_n AS message,
+			input_n AS input,
+			output_n AS output,
+			attachments_n AS attachments,
+			summary_n AS summary,
+			author_name_n AS author_name
+		FROM chain
+		WHERE NOT deleted_n;
+		",
+		message_id,
+		channel_id,
+		guild_id
+	)
+	.fetch_all(executor)
+	.await
+	.unwrap()
+	.into_iter()
+	.map(|record| {
+		let images = record
+			.attachments
+			.and_then(|attachments| serde_json::from_str(&attachments).ok())
+			.unwrap_or_default();
+		Exchange {
+			message: record.message,
+			input: ChatMessage::user_with_images(record.input, images),
+			author_name: record.author_name,
+			output: ChatMessage::assistant(record.output),
+			summary: record.summary,
+		}
+	})
+	.collect()
+}
+
+async fn store_summary(executor: &Pool<Sqlite>, message: i64, summary: &str) {
+	query!(
+		"
+		UPDATE conversations
+		SET summary = ?
+		WHERE message = ?
+		",
+		summary,
+		message,
+	)
+	.execute(executor)
+	.await
+	.unwrap();
+}
+
+impl Gpt {
+	/// Turns the exchanges, newest first, into history in order, keeping as many of the newest ones as fit in the model's context window next to `reserved_tokens`. Depending on config, the ones that don't fit are replaced by a summary.
+	///
+	/// A new summary is only made if `can_afford` says the user can pay for it, at most the given cost, and for the reply after it, with the given tokens of history. Otherwise they're just left out, so nobody pays for a summary and then gets refused.
+	///
+	/// Returns the history, what was left out, and the tokens spent on summarizing.
+	pub async fn fit_history(
+		&self,
+		executor: &Pool<Sqlite>,
+		exchanges: Vec<Exchange>,
+		model: &GptModel,
+		api_key: Option<&str>,
+		reserved_tokens: u32,
+		can_afford: impl Fn(u32, u64) -> bool,
+	) -> (Vec<ChatMessage>, Trimmed, TokenUsage) {
+		let tokenizer = model.tokenizer();
+		let costs = exchanges
+			.iter()
+			.map(|exchange| {
+				tokenizer.estimate_message_tokens(&exchange.input)
+					+ tokenizer.estimate_message_tokens(&exchange.output)
+			})
+			.collect::<Vec<_>>();
+		let fitting = |budget: u32| {
+			costs
+				.iter()
+				.scan(0, |total, cost| {
+					*total += cost;
+					(*total <= budget).then_some(())
+				})
+				.count()
+		};
+		let budget = model.context_length().saturating_sub(reserved_tokens);
+		let mut kept = fitting(budget);
+		let mut trimmed = Trimmed::Nothing;
+		let mut summary = None;
+		let mut usage = TokenUsage::default();
+		if kept < exchanges.len() {
+			trimmed = Trimmed::Dropped(exchanges.len() - kept);
+		}
+		if kept < exchanges.len() && self.summarize_old_messages() {
+			// Make room for the summary, which is at most as long as a default reply.
+			let kept_with_summary = fitting(budget.saturating_sub(model.default_reply_tokens()));
+			let dropped = &exchanges[kept_with_summary..];
+			let history_tokens =
+				costs[..kept_with_summary].iter().sum::<u32>() + model.default_reply_tokens();
+			let summary_cost = worst_case_summary_cost(model, &costs[kept_with_summary..]);
+			let result = match &dropped[0].summary {
+				Some(summary) => Some(Ok((summary.clone(), TokenUsage::default()))),
+				None if can_afford(history_tokens, summary_cost) => {
+					Some(self.summarize(dropped, model, api_key).await)
+				}
+				None => None,
+			};
+			match result {
+				Some(Ok((text, summary_usage))) => {
+					if dropped[0].summary.is_none() {
+						store_summary(executor, dropped[0].message, &text).await;
+					}
+					kept = kept_with_summary;
+					trimmed = Trimmed::Summarized(exchanges.len() - kept);
+					summary = Some(text);
+					usage = summary_usage;
+				}
+				Some(Err(error)) => eprintln!("Could not summarize conversation: {error}"),
+				None => (),
+			}
+		}
+		let summary = summary.map(|summary| {
+			ChatMessage::system(format!("Summary of the earlier conversation: {summary}"))
+		});
+		let history = summary
+			.into_iter()
+			.chain(exchanges.into_iter().take(kept).rev().flat_map(|exchange| {
+				[
+					attribute(exchange.input, exchange.author_name.as_deref()),
+					exchange.output,
+				]
+			}))
+			.collect();
+		(history, trimmed, usage)
+	}
+
+	/// Summarizes the exchanges, newest first, building on the newest earlier summary among them. As many exchanges are included as fit in the model's context window.
+	async fn summarize(
+		&self,
+		exchanges: &[Exchange],
+		model: &GptModel,
+		api_key: Option<&str>,
+	) -> Result<(String, TokenUsage), GptError> {
+		let tokenizer = model.tokenizer();
+		// The earlier summary is at most as long as a reply too.
+		let mut budget = model.context_length().saturating_sub(
+			2 * model.default_reply_tokens() + tokenizer.count(SUMMARY_SYSTEM_MESSAGE),
+		);
+		let mut parts = Vec::new();
+		let mut earlier_summary = None;
+		for exchange in exchanges {
+			if let Some(summary) = &exchange.summary {
+				earlier_summary = Some(summary);
+				break;
+			}
+			let part = format!(
+				"{}: {}\nAssistant: {}",
+				exchange.author_name.as_deref().unwrap_or("User"),
+				exchange.input.content,
+				exchange.output.content
+			);
+			let tokens = tokenizer.count(&part);
+			if tokens > budget {
+				break;
+			}
+			budget -= tokens;
+			parts.push(part);
+		}
+		let transcript = earlier_summary
+			.map(|summary| format!("Summary of what came before: {summary}"))
+			.into_iter()
+			.chain(parts.into_iter().rev())
+			.collect::<Vec<_>>()
+			.join("\n\n");
+		let response = self
+			.send(
+				&[
+					ChatMessage::system(SUMMARY_SYSTEM_MESSAGE.to_string()),
+					ChatMessage::user(transcript),
+				],
+				model,
+				api_key,
+				&[],
+				Sampling::default(),
+			)
+			.await?;
+		let summary = response.message_choices[0].message.content.clone();
+		Ok((summary, response.usage))
+	}
+}
@@ -0,0 +1,159 @@
+use serde::{Deserialize, Serialize};
+use serenity::{
+	all::{CommandInteraction, CommandOptionType},
+	builder::{
+		CreateAttachment, CreateCommand, CreateCommandOption, CreateInteractionResponseFollowup,
+	},
+	client::Context,
+};
+use sqlx::{Pool, Sqlite};
+
+use crate::{
+	allowances::{allowance_and_max, spend_allowance_on_image, unaffordable_message},
+	gpt::Gpt,
+	util::interaction_followup,
+};
+
+/// A slash command that generates an image from the user's description.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ImageCommand {
+	name: String,
+	emoji: String,
+	description: String,
+	argument: String,
+	argument_description: String,
+	/// The image model, like `gpt-image-1`
+	model: String,
+	/// Like `1024x1024`, or the model's default if absent
+	size: Option<String>,
+	/// Like `low` or `hd`, depending on the model, or the model's default if absent
+	quality: Option<String>,
+	/// In nanodollars per image
+	cost: u32,
+}
+
+impl ImageCommand {
+	pub fn name(&self) -> &str {
+		&self.name
+	}
+	pub fn create(&self) -> CreateCommand {
+		CreateCommand::new(&self.name)
+			.description(&self.description)
+			.add_option(
+				CreateCommandOption::new(
+					CommandOptionType::String,
+					&self.argument,
+					&self.argument_description,
+				)
+				.required(true),
+			)
+	}
+	pub async fn handle(
+		&self,
+		context: Context,
+		interaction: CommandInteraction,
+		gpt: &Gpt,
+		executor: &Pool<Sqlite>,
+	) -> Result<(), ()> {
+		let Some(prompt) = interaction
+			.data
+			.options
+			.first()
+			.and_then(|option| option.value.as_str())
+		else {
+			return Err(());
+		};
+
+		interaction.defer(&context).await.map_err(|_| ())?;
+
+		let user = interaction.user.id;
+		let custom_api_key = gpt.user_api_key(user);
+
+		let (allowance, max_allowance) = allowance_and_max(
+			executor,
+			user,
+			gpt.daily_allowance(),
+			gpt.accrual_days(),
+			custom_api_key.is_some(),
+		)
+		.await;
+		if allowance.is_out() {
+			let content = format!(
+				"You are out of allowance. ({}/{})",
+				allowance, max_allowance
+			);
+			let _ = interaction_followup(context, interaction, content, true, false).await;
+			return Ok(());
+		}
+		if !allowance.can_afford(self.cost as u64, gpt.overspend_margin()) {
+			let content = unaffordable_message(self.cost as u64, &allowance);
+			let _ = interaction_followup(context, interaction, content, true, false).await;
+			return Ok(());
+		}
+
+		let request = ImageRequest {
+			model: &self.model,
+			prompt,
+			n: 1,
+			size: self.size.as_deref(),
+			quality: self.quality.as_deref(),
+		};
+		let api_key = custom_api_key.unwrap_or(gpt.default_api_key());
+		let image = match gpt.generate_image(&request, Some(api_key)).await {
+			Ok(image) => image,
+			Err(error) => {
+				let _ =
+					interaction_followup(context, interaction, error.user_message(), true, false)
+						.await;
+				return Ok(());
+			}
+		};
+
+		let (allowance, cost) = spend_allowance_on_image(
+			executor,
+			user,
+			&self.model,
+			self.cost,
+			gpt.daily_allowance(),
+			gpt.accrual_days(),
+			custom_api_key.is_some(),
+		)
+		.await;
+
+		let _ = interaction
+			.create_followup(
+				&context.http,
+				CreateInteractionResponseFollowup::new()
+					.content(format!("{} (-{}, {})", self.emoji, cost, allowance))
+					.add_file(CreateAttachment::bytes(image, "image.png")),
+			)
+			.await;
+		Ok(())
+	}
+}
+
+/// A request to OpenAI's images API
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ImageRequest<'a> {
+	pub model: &'a str,
+	pub prompt: &'a str,
+	/// How many images to generate
+	pub n: u32,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub size: Option<&'a str>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub quality: Option<&'a str>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ImageResponse {
+	pub data: Vec<GeneratedImage>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct GeneratedImage {
+	/// The image file, base64-encoded
+	pub b64_json: Option<String>,
+	/// A link to the image, for models that don't send the image itself
+	pub url: Option<String>,
+}
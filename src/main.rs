@@ -7,15 +7,33 @@ use database::init_database;
 use discord_client::DiscordEventHandler;
 use gpt::Gpt;
 use serenity::{http::Http, prelude::GatewayIntents};
+use user_data::prune_regularly;
 
 mod allowances;
+mod ambient_channels;
+mod channel_summary;
+mod chat;
 mod config;
+mod conversation_info;
+mod conversation_search;
 mod conversations;
 mod database;
 mod discord_client;
 mod gpt;
+mod gpt_error;
+mod history;
+mod image_generation;
+mod memories;
+#[cfg(test)]
+mod mock_openai;
 mod one_off_response;
+mod providers;
 mod response_styles;
+#[cfg(test)]
+mod tests;
+mod token_estimation;
+mod tools;
+mod user_data;
 mod user_settings;
 mod util;
 
@@ -31,7 +49,7 @@ async fn main() {
 	let config = Config::from_file("./config.toml");
 	let custom_api_keys = CustomApiKeys::from_file("./custom_api_keys.toml");
 
-	let gpt = Gpt::new(openai_api_key, None, config, custom_api_keys).unwrap();
+	let gpt = Gpt::new(openai_api_key, config, custom_api_keys).unwrap();
 
 	let my_id = Http::new(&discord_token)
 		.get_current_user()
@@ -39,10 +57,19 @@ async fn main() {
 		.unwrap()
 		.id;
 
+	if let Some(retention_days) = gpt.retention_days() {
+		tokio::spawn(prune_regularly(db_pool.clone(), retention_days));
+	}
+
 	let handler = DiscordEventHandler::new(db_pool, gpt, my_id);
 	let mut client = serenity::Client::builder(
 		&discord_token,
-		GatewayIntents::GUILDS | GatewayIntents::GUILD_MESSAGES | GatewayIntents::MESSAGE_CONTENT,
+		GatewayIntents::GUILDS
+			| GatewayIntents::GUILD_MESSAGES
+			| GatewayIntents::GUILD_MESSAGE_REACTIONS
+			| GatewayIntents::DIRECT_MESSAGES
+			| GatewayIntents::DIRECT_MESSAGE_REACTIONS
+			| GatewayIntents::MESSAGE_CONTENT,
 	)
 	.event_handler(handler)
 	.await
@@ -0,0 +1,450 @@
+//! Facts the bot remembers about a user across conversations, if they turn memory on.
+
+use std::{collections::HashSet, fmt::Write};
+
+use serenity::{
+	all::{CommandInteraction, CommandOptionType, CommandType, ResolvedTarget, UserId},
+	builder::{CreateCommand, CreateCommandOption},
+	prelude::Context,
+};
+use sqlx::{query, Pool, Sqlite};
+
+use crate::{
+	allowances::{allowance_and_max, spend_allowance, unaffordable_message},
+	gpt::{ChatMessage, Gpt, Sampling},
+	user_settings::get_model_setting,
+	util::{interaction_followup, interaction_reply},
+};
+
+/// The most memories a user can have
+const MAX_MEMORIES: u32 = 30;
+/// The longest a memory can be, in characters
+const MAX_MEMORY_LENGTH: u16 = 300;
+/// The most memories told to the model at once, the ones most relevant to the prompt
+const MAX_RECALLED_MEMORIES: usize = 10;
+
+const EXTRACTION_SYSTEM_MESSAGE: &str = "Pick out the facts about the user worth remembering in later conversations from the message they give you, like their preferences, plans or things about their life. Reply with one short fact per line, about the user in the third person, and nothing else. Reply with nothing if there are none.";
+const EMOJI: &str = "🧠";
+
+/// Whether the user turned memory on, so their memories are told to the model.
+pub async fn is_memory_enabled(executor: &Pool<Sqlite>, user: UserId) -> bool {
+	let user_id = user.get() as i64;
+	query!("SELECT memory FROM user_settings WHERE user = ?", user_id)
+		.fetch_optional(executor)
+		.await
+		.unwrap()
+		.is_some_and(|record| record.memory)
+}
+
+async fn set_memory_enabled(executor: &Pool<Sqlite>, user: UserId, enabled: bool) {
+	let user_id = user.get() as i64;
+	query!(
+		"
+		INSERT INTO
+			user_settings (user, memory)
+		VALUES
+			(?, ?)
+		ON CONFLICT (user)
+			DO UPDATE SET
+				memory = excluded.memory
+		",
+		user_id,
+		enabled,
+	)
+	.execute(executor)
+	.await
+	.unwrap();
+}
+
+/// The user's memories, oldest first.
+async fn get_memories(executor: &Pool<Sqlite>, user: UserId) -> Vec<String> {
+	let user_id = user.get() as i64;
+	query!(
+		"SELECT memory FROM memories WHERE user = ? ORDER BY id",
+		user_id
+	)
+	.fetch_all(executor)
+	.await
+	.unwrap()
+	.into_iter()
+	.map(|record| record.memory)
+	.collect()
+}
+
+/// Stores as many of the memories as there is room for, and returns how many that was.
+async fn add_memories(executor: &Pool<Sqlite>, user: UserId, memories: &[String]) -> usize {
+	let user_id = user.get() as i64;
+	let count = query!(
+		"SELECT COUNT(*) AS count FROM memories WHERE user = ?",
+		user_id
+	)
+	.fetch_one(executor)
+	.await
+	.unwrap()
+	.count as usize;
+	let room = (MAX_MEMORIES as usize).saturating_sub(count);
+	let memories = &memories[..memories.len().min(room)];
+	for memory in memories {
+		query!(
+			"INSERT INTO memories (user, memory) VALUES (?, ?)",
+			user_id,
+			memory
+		)
+		.execute(executor)
+		.await
+		.unwrap();
+	}
+	memories.len()
+}
+
+/// Deletes the memory with the given number in the list, counting from 1. Returns whether there was one.
+async fn forget_memory(executor: &Pool<Sqlite>, user: UserId, number: u32) -> bool {
+	let user_id = user.get() as i64;
+	let offset = number as i64 - 1;
+	query!(
+		"
+		DELETE FROM memories
+		WHERE id = (
+			SELECT id
+			FROM memories
+			WHERE user = ?
+			ORDER BY id
+			LIMIT 1 OFFSET ?
+		)
+		",
+		user_id,
+		offset,
+	)
+	.execute(executor)
+	.await
+	.unwrap()
+	.rows_affected()
+		> 0
+}
+
+/// Deletes all of the user's memories and turns memory off.
+async fn forget_everything(executor: &Pool<Sqlite>, user: UserId) {
+	let user_id = user.get() as i64;
+	query!("DELETE FROM memories WHERE user = ?", user_id)
+		.execute(executor)
+		.await
+		.unwrap();
+	set_memory_enabled(executor, user, false).await;
+}
+
+fn words(text: &str) -> HashSet<String> {
+	text.split(|character: char| !character.is_alphanumeric())
+		.filter(|word| word.chars().count() > 3)
+		.map(str::to_lowercase)
+		.collect()
+}
+
+/// What to add to the system message about the user, if they turned memory on and have any memories. When there are too many, the ones sharing the most words with the prompt are picked, then the newest.
+pub async fn recall_memories(
+	executor: &Pool<Sqlite>,
+	user: UserId,
+	name: &str,
+	prompt: &str,
+) -> Option<String> {
+	if !is_memory_enabled(executor, user).await {
+		return None;
+	}
+	let mut memories = get_memories(executor, user).await;
+	if memories.is_empty() {
+		return None;
+	}
+	if memories.len() > MAX_RECALLED_MEMORIES {
+		let prompt_words = words(prompt);
+		memories.reverse();
+		memories.sort_by_cached_key(|memory| {
+			std::cmp::Reverse(words(memory).intersection(&prompt_words).count())
+		});
+		memories.truncate(MAX_RECALLED_MEMORIES);
+	}
+	let mut note = format!("What you remember about {name} from earlier conversations:");
+	for memory in memories {
+		write!(note, "\n- {memory}").unwrap();
+	}
+	Some(note)
+}
+
+fn disabled_note(enabled: bool) -> &'static str {
+	if enabled {
+		""
+	} else {
+		" Memory is off, so I won't use it until you turn it on with /memory."
+	}
+}
+
+impl Gpt {
+	/// Has the model pick out facts about the user from their message, stores them, and charges the user for it.
+	///
+	/// An OK result is the reply to show the user. An error is a message for the user.
+	pub async fn extract_memories(
+		&self,
+		executor: &Pool<Sqlite>,
+		user: UserId,
+		text: &str,
+	) -> Result<String, String> {
+		let model = get_model_setting(executor, user)
+			.await
+			.and_then(|name| self.get_model_by_name(&name))
+			.unwrap_or(self.default_model());
+		let custom_api_key = self.custom_api_key(user, model);
+
+		let (allowance, max_allowance) = allowance_and_max(
+			executor,
+			user,
+			self.daily_allowance(),
+			self.accrual_days(),
+			custom_api_key.is_some(),
+		)
+		.await;
+		if allowance.is_out() {
+			return Err(format!(
+				"You are out of allowance. ({}/{})",
+				allowance, max_allowance
+			));
+		}
+
+		let history = [
+			ChatMessage::system(EXTRACTION_SYSTEM_MESSAGE.to_string()),
+			ChatMessage::user(text.to_string()),
+		];
+		let worst_case_cost = model.get_worst_case_cost(&history, &[], Sampling::default());
+		if !allowance.can_afford(worst_case_cost, self.overspend_margin()) {
+			return Err(unaffordable_message(worst_case_cost, &allowance));
+		}
+
+		let api_key = custom_api_key.or(self.api_key(model));
+		let response = self
+			.send(&history, model, api_key, &[], Sampling::default())
+			.await
+			.map_err(|error| error.user_message().to_string())?;
+		let (allowance, cost) = spend_allowance(
+			executor,
+			user,
+			response.usage,
+			model,
+			self.daily_allowance(),
+			self.accrual_days(),
+			custom_api_key.is_some(),
+		)
+		.await;
+
+		let facts = response.message_choices[0]
+			.message
+			.content
+			.lines()
+			.map(|line| line.trim().trim_start_matches(['-', '*']).trim())
+			.filter(|line| !line.is_empty())
+			.map(|line| line.chars().take(MAX_MEMORY_LENGTH as usize).collect())
+			.collect::<Vec<String>>();
+		let stored = add_memories(executor, user, &facts).await;
+		let mut output = if facts.is_empty() {
+			format!("{EMOJI} I didn't find anything to remember there.")
+		} else {
+			let mut output = format!("{EMOJI} I'll remember that:");
+			for fact in &facts[..stored] {
+				write!(output, "\n- {fact}").unwrap();
+			}
+			if stored < facts.len() {
+				write!(
+					output,
+					"\nI ran out of room for {} more. You can have at most {MAX_MEMORIES} memories.",
+					facts.len() - stored
+				)
+				.unwrap();
+			}
+			output
+		};
+		write!(output, " (-{cost}, {allowance})").unwrap();
+		if stored > 0 {
+			output.push_str(disabled_note(is_memory_enabled(executor, user).await));
+		}
+		Ok(output)
+	}
+}
+
+/// Remembers a fact the user gives.
+pub async fn command_remember(
+	context: Context,
+	interaction: CommandInteraction,
+	executor: &Pool<Sqlite>,
+) -> Result<(), ()> {
+	let fact = interaction
+		.data
+		.options
+		.first()
+		.and_then(|option| option.value.as_str())
+		.ok_or(())?
+		.trim()
+		.to_string();
+	let user = interaction.user.id;
+	let output = if add_memories(executor, user, &[fact]).await == 0 {
+		format!("You already have {MAX_MEMORIES} memories. Use /forget to make room.")
+	} else {
+		format!(
+			"I'll remember that.{}",
+			disabled_note(is_memory_enabled(executor, user).await)
+		)
+	};
+	let _ = interaction_reply(context, interaction, output, true).await;
+	Ok(())
+}
+
+pub fn register_remember() -> CreateCommand {
+	CreateCommand::new("remember")
+		.description("Tells me something to remember about you in new conversations.")
+		.add_option(
+			CreateCommandOption::new(
+				CommandOptionType::String,
+				"fact",
+				"What to remember, like \"I'm vegetarian\"",
+			)
+			.max_length(MAX_MEMORY_LENGTH)
+			.required(true),
+		)
+}
+
+/// Has the model pick out what to remember from one of the user's own messages.
+pub async fn command_remember_message(
+	context: Context,
+	interaction: CommandInteraction,
+	executor: &Pool<Sqlite>,
+	gpt: &Gpt,
+) -> Result<(), ()> {
+	let Some(ResolvedTarget::Message(message)) = interaction.data.target() else {
+		return Err(());
+	};
+	if message.author.id != interaction.user.id {
+		let _ = interaction_reply(
+			context,
+			interaction,
+			"I can only remember things from your own messages.",
+			true,
+		)
+		.await;
+		return Ok(());
+	}
+	let text = message.content.clone();
+
+	interaction
+		.defer_ephemeral(&context)
+		.await
+		.map_err(|_| ())?;
+
+	let output = match gpt
+		.extract_memories(executor, interaction.user.id, &text)
+		.await
+	{
+		Ok(output) => output,
+		Err(error) => error,
+	};
+	let _ = interaction_followup(context, interaction, output, true, false).await;
+	Ok(())
+}
+
+pub fn register_remember_message() -> CreateCommand {
+	CreateCommand::new("Remember this").kind(CommandType::Message)
+}
+
+/// Lists the user's memories, and turns memory on or off.
+pub async fn command_memory(
+	context: Context,
+	interaction: CommandInteraction,
+	executor: &Pool<Sqlite>,
+) -> Result<(), ()> {
+	let user = interaction.user.id;
+	let enabled = match interaction
+		.data
+		.options
+		.first()
+		.and_then(|option| option.value.as_bool())
+	{
+		Some(enabled) => {
+			set_memory_enabled(executor, user, enabled).await;
+			enabled
+		}
+		None => is_memory_enabled(executor, user).await,
+	};
+	let mut output = if enabled {
+		String::from("Memory is on, so I tell new conversations what I remember about you.")
+	} else {
+		String::from("Memory is off, so I don't tell conversations anything I remember.")
+	};
+	let memories = get_memories(executor, user).await;
+	if memories.is_empty() {
+		output
+			.push_str("\nI don't remember anything about you. Use /remember to tell me something.");
+	} else {
+		write!(
+			output,
+			"\n\n**Memories** ({}/{MAX_MEMORIES})",
+			memories.len()
+		)
+		.unwrap();
+		for (index, memory) in memories.iter().enumerate() {
+			write!(output, "\n{}. {memory}", index + 1).unwrap();
+		}
+	}
+	let _ = interaction_reply(context, interaction, output, true).await;
+	Ok(())
+}
+
+pub fn register_memory() -> CreateCommand {
+	CreateCommand::new("memory")
+		.description("Lists what I remember about you, and turns memory on or off.")
+		.add_option(
+			CreateCommandOption::new(
+				CommandOptionType::Boolean,
+				"enabled",
+				"Whether I tell new conversations what I remember about you",
+			)
+			.required(false),
+		)
+}
+
+/// Forgets one of the user's memories, or all of them.
+pub async fn command_forget(
+	context: Context,
+	interaction: CommandInteraction,
+	executor: &Pool<Sqlite>,
+) -> Result<(), ()> {
+	let user = interaction.user.id;
+	let output = match interaction
+		.data
+		.options
+		.first()
+		.and_then(|option| option.value.as_i64())
+	{
+		Some(number) => {
+			if forget_memory(executor, user, number as u32).await {
+				format!("Forgot memory {number}.")
+			} else {
+				format!("There's no memory {number}. Use /memory to see the list.")
+			}
+		}
+		None => {
+			forget_everything(executor, user).await;
+			String::from("Forgot everything about you, and turned memory off.")
+		}
+	};
+	let _ = interaction_reply(context, interaction, output, true).await;
+	Ok(())
+}
+
+pub fn register_forget() -> CreateCommand {
+	CreateCommand::new("forget")
+		.description("Forgets something I remember about you, or everything.")
+		.add_option(
+			CreateCommandOption::new(
+				CommandOptionType::Integer,
+				"number",
+				"The number of the memory in /memory. Leave out to forget everything.",
+			)
+			.min_int_value(1)
+			.max_int_value(MAX_MEMORIES as u64)
+			.required(false),
+		)
+}
@@ -0,0 +1,271 @@
+//! A fake chat completions API for tests, running in the same process. It answers requests with responses scripted in advance, and remembers the requests so tests can look at them.
+
+use std::{
+	collections::VecDeque,
+	sync::{Arc, Mutex},
+};
+
+use serde_json::{json, Value};
+use tokio::{
+	io::{AsyncReadExt, AsyncWriteExt},
+	net::{TcpListener, TcpStream},
+	task::JoinHandle,
+};
+
+/// What the fake API answers a request with.
+#[derive(Debug, Clone)]
+pub enum MockResponse {
+	/// A completion with this content and token usage, streamed if the request asked for it
+	Reply {
+		content: String,
+		prompt_tokens: u32,
+		completion_tokens: u32,
+	},
+	/// A completion with this content and no token usage, like from servers that ignore `stream_options`
+	ReplyWithoutUsage { content: String },
+	/// A completion calling one tool with these arguments, with this token usage
+	ToolCall {
+		name: String,
+		arguments: String,
+		prompt_tokens: u32,
+		completion_tokens: u32,
+	},
+	/// An error response with this status code and error body
+	Error {
+		status: u16,
+		error_type: String,
+		message: String,
+	},
+}
+
+impl MockResponse {
+	pub fn reply(content: &str, prompt_tokens: u32, completion_tokens: u32) -> Self {
+		Self::Reply {
+			content: content.to_string(),
+			prompt_tokens,
+			completion_tokens,
+		}
+	}
+	pub fn reply_without_usage(content: &str) -> Self {
+		Self::ReplyWithoutUsage {
+			content: content.to_string(),
+		}
+	}
+	pub fn tool_call(
+		name: &str,
+		arguments: &str,
+		prompt_tokens: u32,
+		completion_tokens: u32,
+	) -> Self {
+		Self::ToolCall {
+			name: name.to_string(),
+			arguments: arguments.to_string(),
+			prompt_tokens,
+			completion_tokens,
+		}
+	}
+	pub fn error(status: u16, error_type: &str, message: &str) -> Self {
+		Self::Error {
+			status,
+			error_type: error_type.to_string(),
+			message: message.to_string(),
+		}
+	}
+}
+
+#[derive(Default)]
+struct State {
+	responses: VecDeque<MockResponse>,
+	requests: Vec<Value>,
+}
+
+pub struct MockOpenAi {
+	base_url: String,
+	state: Arc<Mutex<State>>,
+	server: JoinHandle<()>,
+}
+
+impl MockOpenAi {
+	/// Starts listening on a free local port.
+	pub async fn start() -> Self {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let base_url = format!("http://{}/v1", listener.local_addr().unwrap());
+		let state = Arc::new(Mutex::new(State::default()));
+		let server_state = state.clone();
+		let server = tokio::spawn(async move {
+			while let Ok((stream, _)) = listener.accept().await {
+				tokio::spawn(handle_connection(stream, server_state.clone()));
+			}
+		});
+		Self {
+			base_url,
+			state,
+			server,
+		}
+	}
+	/// What to use as a model's `base_url`.
+	pub fn base_url(&self) -> &str {
+		&self.base_url
+	}
+	/// Adds a response to answer the next unanswered request with.
+	pub fn push(&self, response: MockResponse) {
+		self.state.lock().unwrap().responses.push_back(response);
+	}
+	/// The bodies of all the requests received so far.
+	pub fn requests(&self) -> Vec<Value> {
+		self.state.lock().unwrap().requests.clone()
+	}
+}
+
+impl Drop for MockOpenAi {
+	fn drop(&mut self) {
+		self.server.abort();
+	}
+}
+
+/// Answers one request, then closes the connection.
+async fn handle_connection(mut stream: TcpStream, state: Arc<Mutex<State>>) {
+	let Some(body) = read_request_body(&mut stream).await else {
+		return;
+	};
+	let request: Value = serde_json::from_slice(&body).unwrap_or(Value::Null);
+	let streaming = request["stream"].as_bool().unwrap_or(false);
+	let model = request["model"].as_str().unwrap_or_default().to_string();
+	let response = {
+		let mut state = state.lock().unwrap();
+		state.requests.push(request);
+		state.responses.pop_front()
+	};
+	let completion = match response {
+		Some(MockResponse::Reply {
+			content,
+			prompt_tokens,
+			completion_tokens,
+		}) => Ok((
+			json!({ "role": "assistant", "content": content }),
+			"stop",
+			Some(usage(prompt_tokens, completion_tokens)),
+		)),
+		Some(MockResponse::ReplyWithoutUsage { content }) => Ok((
+			json!({ "role": "assistant", "content": content }),
+			"stop",
+			None,
+		)),
+		Some(MockResponse::ToolCall {
+			name,
+			arguments,
+			prompt_tokens,
+			completion_tokens,
+		}) => {
+			let tool_calls = json!([{ "index": 0, "id": "call_mock", "type": "function", "function": { "name": name, "arguments": arguments } }]);
+			Ok((
+				json!({ "role": "assistant", "content": null, "tool_calls": tool_calls }),
+				"tool_calls",
+				Some(usage(prompt_tokens, completion_tokens)),
+			))
+		}
+		Some(MockResponse::Error {
+			status,
+			error_type,
+			message,
+		}) => Err((status, error_type, message)),
+		None => Err((
+			400,
+			String::from("invalid_request_error"),
+			String::from("No response scripted."),
+		)),
+	};
+	let (status, content_type, body) = match completion {
+		Ok((message, finish_reason, usage)) => {
+			if streaming {
+				let mut chunks = vec![
+					json!({
+						"id": "chatcmpl-mock",
+						"created": 0,
+						"model": model,
+						"choices": [{ "index": 0, "delta": message, "finish_reason": null }],
+					}),
+					json!({
+						"id": "chatcmpl-mock",
+						"created": 0,
+						"model": model,
+						"choices": [{ "index": 0, "delta": {}, "finish_reason": finish_reason }],
+					}),
+				];
+				if let Some(usage) = usage {
+					chunks.push(json!({
+						"id": "chatcmpl-mock",
+						"created": 0,
+						"model": model,
+						"choices": [],
+						"usage": usage,
+					}));
+				}
+				let events = chunks
+					.iter()
+					.map(|chunk| format!("data: {chunk}\n\n"))
+					.chain(std::iter::once(String::from("data: [DONE]\n\n")))
+					.collect::<String>();
+				(200, "text/event-stream", events)
+			} else {
+				let completion = json!({
+					"id": "chatcmpl-mock",
+					"created": 0,
+					"model": model,
+					"choices": [{ "index": 0, "message": message, "finish_reason": finish_reason }],
+					"usage": usage,
+				});
+				(200, "application/json", completion.to_string())
+			}
+		}
+		Err((status, error_type, message)) => {
+			let error =
+				json!({ "error": { "message": message, "type": error_type, "code": null } });
+			(status, "application/json", error.to_string())
+		}
+	};
+	let response = format!(
+		"HTTP/1.1 {status} Mock\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+		body.len()
+	);
+	let _ = stream.write_all(response.as_bytes()).await;
+	let _ = stream.shutdown().await;
+}
+
+fn usage(prompt_tokens: u32, completion_tokens: u32) -> Value {
+	json!({
+		"prompt_tokens": prompt_tokens,
+		"completion_tokens": completion_tokens,
+		"total_tokens": prompt_tokens + completion_tokens,
+	})
+}
+
+/// Reads the head of an HTTP request, then as much of the body as its `Content-Length` says.
+async fn read_request_body(stream: &mut TcpStream) -> Option<Vec<u8>> {
+	let mut buffer = Vec::new();
+	let mut chunk = [0; 4096];
+	let head_end = loop {
+		if let Some(position) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+			break position + 4;
+		}
+		let read = stream.read(&mut chunk).await.ok()?;
+		if read == 0 {
+			return None;
+		}
+		buffer.extend_from_slice(&chunk[..read]);
+	};
+	let head = String::from_utf8_lossy(&buffer[..head_end]).to_lowercase();
+	let content_length = head
+		.lines()
+		.find_map(|line| line.strip_prefix("content-length:"))
+		.and_then(|length| length.trim().parse::<usize>().ok())
+		.unwrap_or(0);
+	while buffer.len() < head_end + content_length {
+		let read = stream.read(&mut chunk).await.ok()?;
+		if read == 0 {
+			return None;
+		}
+		buffer.extend_from_slice(&chunk[..read]);
+	}
+	Some(buffer.split_off(head_end))
+}
@@ -7,9 +7,10 @@ use serenity::{
 use sqlx::{Pool, Sqlite};
 
 use crate::{
-	allowances::{allowance_and_max, spend_allowance},
-	gpt::{ChatMessage, Gpt},
-	user_settings::get_model_setting,
+	allowances::{allowance_and_max, spend_allowance, unaffordable_message},
+	gpt::{ChatMessage, Gpt, TokenUsage},
+	tools::{self, run_tool_calls, MAX_TOOL_ROUNDS},
+	user_settings::{get_model_setting, get_sampling_settings},
 	util::{format_chat_message, interaction_followup},
 };
 
@@ -22,12 +23,18 @@ pub struct OneOffCommand {
 	argument_description: String,
 	system_message: String,
 	model_override: Option<String>,
+	#[serde(default)]
+	tools: Vec<String>,
 }
 
 impl OneOffCommand {
 	pub fn name(&self) -> &str {
 		&self.name
 	}
+	/// The names of the tools GPT may call for this command.
+	pub fn tools(&self) -> &[String] {
+		&self.tools
+	}
 	pub fn create(&self) -> CreateCommand {
 		CreateCommand::new(&self.name)
 			.description(&self.description)
@@ -47,16 +54,7 @@ impl OneOffCommand {
 		gpt: &Gpt,
 		executor: &Pool<Sqlite>,
 	) -> Result<(), ()> {
-		single_text_input_with_system_message(
-			context,
-			interaction,
-			gpt,
-			executor,
-			&self.emoji,
-			&self.system_message,
-			self.model_override.as_deref(),
-		)
-		.await
+		single_text_input_with_system_message(context, interaction, gpt, executor, self).await
 	}
 }
 
@@ -66,19 +64,27 @@ impl Gpt {
 		&self,
 		executor: &Pool<Sqlite>,
 		user: UserId,
-		system_message: &str,
-		emoji: &str,
+		command: &OneOffCommand,
 		input: &str,
-		model_override: Option<&str>,
 	) -> Result<String, String> {
-		let custom_authorization_header = self.custom_authorization_header(user);
+		let model = match command.model_override.as_deref() {
+			Some(name) => self
+				.get_model_by_name(name)
+				.expect("The model override model was not present"),
+			None => get_model_setting(executor, user)
+				.await
+				.and_then(|name| self.get_model_by_name(&name))
+				.unwrap_or(self.default_model()),
+		};
+
+		let custom_api_key = self.custom_api_key(user, model);
 
 		let (allowance, max_allowance) = allowance_and_max(
 			executor,
 			user,
 			self.daily_allowance(),
 			self.accrual_days(),
-			custom_authorization_header.is_some(),
+			custom_api_key.is_some(),
 		)
 		.await;
 		if allowance.is_out() {
@@ -88,48 +94,73 @@ impl Gpt {
 			));
 		}
 
-		let model = match model_override {
-			Some(name) => self
-				.get_model_by_name(name)
-				.expect("The model override model was not present"),
-			None => get_model_setting(executor, user)
-				.await
-				.and_then(|name| self.get_model_by_name(&name))
-				.unwrap_or(self.default_model()),
-		};
+		let api_key = custom_api_key.or(self.api_key(model));
+		let sampling = get_sampling_settings(executor, user).await;
+		let tools = tools::definitions(command.tools());
 
-		let authorization_header =
-			custom_authorization_header.unwrap_or(self.authorization_header());
+		let mut history = vec![
+			ChatMessage::system(command.system_message.clone()),
+			ChatMessage::user(input.to_string()),
+		];
+		let worst_case_cost = model.get_worst_case_cost(&history, &tools, sampling);
+		if !allowance.can_afford(worst_case_cost, self.overspend_margin()) {
+			return Err(unaffordable_message(worst_case_cost, &allowance));
+		}
 
-		let response = self
-			.send(
-				&[
-					ChatMessage::system(system_message.to_string()),
-					ChatMessage::user(input.to_string()),
-				],
-				model.name(),
-				model.api_version(),
-				authorization_header,
-			)
-			.await?;
+		let mut usage = TokenUsage::default();
+		let mut round = 1;
+		let result = loop {
+			let tools = if round < MAX_TOOL_ROUNDS {
+				tools.as_slice()
+			} else {
+				&[]
+			};
+			// Each round after the first sends the whole history again, so it's only sent if the rounds before and it could still be afforded together.
+			if round > 1 {
+				let worst_case_cost = model
+					.get_cost(usage)
+					.saturating_add(model.get_worst_case_cost(&history, tools, sampling));
+				if !allowance.can_afford(worst_case_cost, self.overspend_margin()) {
+					break Err(unaffordable_message(worst_case_cost, &allowance));
+				}
+			}
+			let response = match self.send(&history, model, api_key, tools, sampling).await {
+				Ok(response) => response,
+				Err(error) => break Err(error.user_message().to_string()),
+			};
+			usage += response.usage;
+			let message = &response.message_choices[0].message;
+			if message.tool_calls.is_empty() {
+				break Ok(response);
+			}
+			history.push(message.clone());
+			history.extend(run_tool_calls(&message.tool_calls, command.tools()));
+			round += 1;
+		};
 
+		// The rounds before an error were still paid for.
+		if usage.requests == 0 {
+			return Err(result.err().unwrap_or_default());
+		}
 		let (allowance, cost) = spend_allowance(
 			executor,
 			user,
-			response.usage,
+			usage,
 			model,
 			self.daily_allowance(),
 			self.accrual_days(),
-			custom_authorization_header.is_some(),
+			custom_api_key.is_some(),
 		)
 		.await;
+		let response = result?;
 
 		Ok(format_chat_message(
 			&response.message_choices[0],
-			emoji,
+			&command.emoji,
 			cost,
 			allowance,
 			(self.default_model() != model).then_some(model),
+			None,
 		))
 	}
 }
@@ -139,9 +170,7 @@ async fn single_text_input_with_system_message(
 	interaction: CommandInteraction,
 	gpt: &Gpt,
 	executor: &Pool<Sqlite>,
-	emoji: &str,
-	system_message: &str,
-	model_override: Option<&str>,
+	command: &OneOffCommand,
 ) -> Result<(), ()> {
 	let Some(input) = interaction
 		.data
@@ -155,14 +184,7 @@ async fn single_text_input_with_system_message(
 	interaction.defer(&context).await.map_err(|_| ())?;
 
 	let response = match gpt
-		.one_off(
-			executor,
-			interaction.user.id,
-			system_message,
-			emoji,
-			input,
-			model_override,
-		)
+		.one_off(executor, interaction.user.id, command, input)
 		.await
 	{
 		Ok(response) => response,
@@ -0,0 +1,852 @@
+//! The different APIs a model can be reached through. Everything is translated to and from the OpenAI chat completions format, which is what the rest of the bot speaks.
+
+use reqwest::{header::AUTHORIZATION, Client, RequestBuilder};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+	gpt::{
+		ChatMessage, CompletionError, CompletionRequest, CompletionResponse, FunctionCall,
+		MessageChoice, PartialCompletion, PromptTokenDetails, Role, ServerResponse, StreamOptions,
+		TokenUsage, ToolCall, ToolDefinition,
+	},
+	gpt_error::GptError,
+};
+
+/// Which API a model is reached through, as written in the config.
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ProviderKind {
+	/// OpenAI's own chat completions API
+	#[default]
+	Openai,
+	/// Anthropic's Messages API
+	Anthropic,
+	/// Any server that imitates the OpenAI chat completions API, like llama.cpp, Ollama or vLLM
+	OpenaiCompatible,
+}
+
+impl ProviderKind {
+	pub fn provider(self) -> &'static dyn Provider {
+		match self {
+			Self::Openai => &OpenAiChat,
+			Self::Anthropic => &Anthropic,
+			Self::OpenaiCompatible => &OpenAiCompatible,
+		}
+	}
+}
+
+/// Translates between the bot and one specific API.
+pub trait Provider: Sync {
+	/// The base URL to use when the model doesn't specify one, if there is a sensible one.
+	fn default_base_url(&self) -> Option<&'static str>;
+	/// The highest temperature the API takes.
+	fn max_temperature(&self) -> f32 {
+		2.0
+	}
+	/// Builds the HTTP request for a completion, including authentication.
+	fn build_request(
+		&self,
+		client: &Client,
+		base_url: &str,
+		api_key: Option<&str>,
+		request: &CompletionRequest,
+	) -> RequestBuilder;
+	/// Parses the body of a response that was not streamed.
+	fn parse_response(&self, body: &[u8]) -> Result<ServerResponse, serde_json::Error>;
+	/// Processes the data of one server-sent event of a streamed response. Returns whether anything new arrived.
+	fn handle_stream_event(
+		&self,
+		data: &str,
+		partial: &mut PartialCompletion,
+	) -> Result<bool, GptError>;
+	/// Whether a streamed response always ends with its token usage. If not, the usage is estimated instead.
+	fn streams_usage(&self) -> bool {
+		true
+	}
+}
+
+pub struct OpenAiChat;
+
+impl Provider for OpenAiChat {
+	fn default_base_url(&self) -> Option<&'static str> {
+		Some("https://api.openai.com/v1")
+	}
+	fn build_request(
+		&self,
+		client: &Client,
+		base_url: &str,
+		api_key: Option<&str>,
+		request: &CompletionRequest,
+	) -> RequestBuilder {
+		with_bearer(client.post(format!("{base_url}/chat/completions")), api_key).json(request)
+	}
+	fn parse_response(&self, body: &[u8]) -> Result<ServerResponse, serde_json::Error> {
+		serde_json::from_slice(body)
+	}
+	fn handle_stream_event(
+		&self,
+		data: &str,
+		partial: &mut PartialCompletion,
+	) -> Result<bool, GptError> {
+		if data == "[DONE]" {
+			partial.done = true;
+			return Ok(false);
+		}
+		let chunk = match serde_json::from_str(data) {
+			Ok(StreamEvent::Chunk(chunk)) => chunk,
+			Ok(StreamEvent::Error { error }) => {
+				return Err(GptError::from_api_error(None, Some(error), None))
+			}
+			Err(error) => {
+				return Err(GptError::MalformedBody(format!(
+					"Error: {error}, event: {data}"
+				)))
+			}
+		};
+		partial.message_id = partial.message_id.take().or(chunk.id);
+		partial.created_timestamp = partial.created_timestamp.or(chunk.created);
+		partial.model = chunk.model;
+		if chunk.usage.is_some() {
+			partial.usage = chunk.usage;
+		}
+		for choice in chunk.choices.into_iter().filter(|choice| choice.index == 0) {
+			if let Some(content) = choice.delta.content {
+				partial.content.push_str(&content);
+			}
+			for delta in choice.delta.tool_calls {
+				// The first delta of each tool call has its ID and name, the rest only have more of the arguments.
+				if delta.index >= partial.tool_calls.len() {
+					partial.tool_calls.push(ToolCall {
+						id: delta.id.unwrap_or_default(),
+						call_type: String::from("function"),
+						function: FunctionCall {
+							name: delta.function.name.unwrap_or_default(),
+							arguments: String::new(),
+						},
+					});
+				}
+				if let (Some(tool_call), Some(arguments)) = (
+					partial.tool_calls.get_mut(delta.index),
+					delta.function.arguments,
+				) {
+					tool_call.function.arguments.push_str(&arguments);
+				}
+			}
+			if choice.finish_reason.is_some() {
+				partial.finish_reason = choice.finish_reason;
+			}
+		}
+		Ok(true)
+	}
+}
+
+/// Servers that imitate OpenAI tend to lag behind on newer parameters, so this only sends the basics.
+pub struct OpenAiCompatible;
+
+impl Provider for OpenAiCompatible {
+	fn default_base_url(&self) -> Option<&'static str> {
+		None
+	}
+	fn build_request(
+		&self,
+		client: &Client,
+		base_url: &str,
+		api_key: Option<&str>,
+		request: &CompletionRequest,
+	) -> RequestBuilder {
+		let request = CompatibleRequest {
+			model: request.model,
+			messages: request.messages,
+			temperature: request.temperature,
+			max_tokens: request.max_completion_tokens,
+			stream: request.stream,
+			stream_options: request.stream_options,
+			tools: request.tools,
+		};
+		with_bearer(client.post(format!("{base_url}/chat/completions")), api_key).json(&request)
+	}
+	fn parse_response(&self, body: &[u8]) -> Result<ServerResponse, serde_json::Error> {
+		OpenAiChat.parse_response(body)
+	}
+	fn handle_stream_event(
+		&self,
+		data: &str,
+		partial: &mut PartialCompletion,
+	) -> Result<bool, GptError> {
+		OpenAiChat.handle_stream_event(data, partial)
+	}
+	/// Many of them ignore `stream_options`.
+	fn streams_usage(&self) -> bool {
+		false
+	}
+}
+
+pub struct Anthropic;
+
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+
+impl Provider for Anthropic {
+	fn default_base_url(&self) -> Option<&'static str> {
+		Some("https://api.anthropic.com/v1")
+	}
+	fn max_temperature(&self) -> f32 {
+		1.0
+	}
+	fn build_request(
+		&self,
+		client: &Client,
+		base_url: &str,
+		api_key: Option<&str>,
+		request: &CompletionRequest,
+	) -> RequestBuilder {
+		let system = request
+			.messages
+			.iter()
+			.filter(|message| message.role == Role::System)
+			.map(|message| message.content.as_str())
+			.collect::<Vec<_>>();
+		let mut messages: Vec<MessagesRequestMessage> = Vec::new();
+		for message in request
+			.messages
+			.iter()
+			.filter(|message| message.role != Role::System)
+		{
+			let (role, mut content) = match message.role {
+				Role::Assistant => {
+					let text = (!message.content.is_empty()).then_some(RequestContentBlock::Text {
+						text: &message.content,
+					});
+					let tool_uses =
+						message
+							.tool_calls
+							.iter()
+							.map(|tool_call| RequestContentBlock::ToolUse {
+								id: &tool_call.id,
+								name: &tool_call.function.name,
+								input: serde_json::from_str(&tool_call.function.arguments)
+									.unwrap_or_else(|_| Value::Object(Default::default())),
+							});
+					("assistant", text.into_iter().chain(tool_uses).collect())
+				}
+				Role::Tool => (
+					"user",
+					vec![RequestContentBlock::ToolResult {
+						tool_use_id: message.tool_call_id.as_deref().unwrap_or_default(),
+						content: &message.content,
+					}],
+				),
+				Role::User | Role::System => {
+					// Anthropic recommends putting images before the text.
+					let images = message.images.iter().map(|url| RequestContentBlock::Image {
+						source: ImageSource::from_url(url),
+					});
+					let text = (!message.content.is_empty()).then_some(RequestContentBlock::Text {
+						text: &message.content,
+					});
+					("user", images.chain(text).collect())
+				}
+			};
+			// Anthropic wants the roles to alternate, so results of multiple tool calls go into one message.
+			match messages.last_mut() {
+				Some(last) if last.role == role => last.content.append(&mut content),
+				_ => messages.push(MessagesRequestMessage { role, content }),
+			}
+		}
+		let request = MessagesRequest {
+			model: request.model,
+			system: (!system.is_empty()).then(|| system.join("\n\n")),
+			messages,
+			max_tokens: request.max_completion_tokens,
+			temperature: request.temperature,
+			stream: request.stream,
+			tools: request
+				.tools
+				.iter()
+				.map(|tool| MessagesTool {
+					name: tool.function.name,
+					description: tool.function.description,
+					input_schema: &tool.function.parameters,
+				})
+				.collect(),
+		};
+		let mut builder = client
+			.post(format!("{base_url}/messages"))
+			.header("anthropic-version", ANTHROPIC_VERSION);
+		if let Some(api_key) = api_key {
+			builder = builder.header("x-api-key", api_key);
+		}
+		builder.json(&request)
+	}
+	fn parse_response(&self, body: &[u8]) -> Result<ServerResponse, serde_json::Error> {
+		Ok(match serde_json::from_slice(body)? {
+			MessagesResponse::Error { error } => ServerResponse::Error { error },
+			MessagesResponse::Message(message) => {
+				let mut content = String::new();
+				let mut tool_calls = Vec::new();
+				for block in message.content {
+					match block {
+						ContentBlock::Text { text } => content.push_str(&text),
+						ContentBlock::ToolUse { id, name, input } => {
+							tool_calls.push(tool_call_from_tool_use(id, name, input.to_string()))
+						}
+						ContentBlock::Other => (),
+					}
+				}
+				ServerResponse::Completion(CompletionResponse {
+					message_id: Some(message.id),
+					created_timestamp: None,
+					model: message.model,
+					usage: message.usage.into(),
+					message_choices: vec![MessageChoice {
+						message: ChatMessage {
+							tool_calls,
+							..ChatMessage::assistant(content)
+						},
+						finish_reason: finish_reason_from_stop_reason(
+							message.stop_reason.as_deref(),
+						),
+						index: 0,
+					}],
+				})
+			}
+		})
+	}
+	fn handle_stream_event(
+		&self,
+		data: &str,
+		partial: &mut PartialCompletion,
+	) -> Result<bool, GptError> {
+		let event = serde_json::from_str(data)
+			.map_err(|error| GptError::MalformedBody(format!("Error: {error}, event: {data}")))?;
+		match event {
+			MessagesStreamEvent::MessageStart { message } => {
+				partial.message_id = Some(message.id);
+				partial.model = message.model;
+				partial.usage = Some(message.usage.into());
+			}
+			MessagesStreamEvent::ContentBlockStart { content_block } => match content_block {
+				// The input arrives in later deltas.
+				ContentBlock::ToolUse { id, name, .. } => partial
+					.tool_calls
+					.push(tool_call_from_tool_use(id, name, String::new())),
+				_ => return Ok(false),
+			},
+			MessagesStreamEvent::ContentBlockDelta { delta } => match delta {
+				ContentDelta::TextDelta { text } => partial.content.push_str(&text),
+				// Content blocks arrive one at a time, so this is always about the latest tool call.
+				ContentDelta::InputJsonDelta { partial_json } => {
+					if let Some(tool_call) = partial.tool_calls.last_mut() {
+						tool_call.function.arguments.push_str(&partial_json);
+					}
+				}
+				ContentDelta::Other => return Ok(false),
+			},
+			MessagesStreamEvent::MessageDelta { delta, usage } => {
+				partial.finish_reason =
+					Some(finish_reason_from_stop_reason(delta.stop_reason.as_deref()));
+				if let Some(existing) = &mut partial.usage {
+					existing.completion_tokens = usage.output_tokens;
+					existing.total_tokens = existing.prompt_tokens + usage.output_tokens;
+				}
+			}
+			MessagesStreamEvent::MessageStop => {
+				// A tool without parameters gets no input deltas at all.
+				for tool_call in &mut partial.tool_calls {
+					if tool_call.function.arguments.is_empty() {
+						tool_call.function.arguments = String::from("{}");
+					}
+				}
+				partial.done = true;
+				return Ok(false);
+			}
+			MessagesStreamEvent::Error { error } => {
+				return Err(GptError::from_api_error(None, Some(error), None))
+			}
+			MessagesStreamEvent::Other => return Ok(false),
+		}
+		Ok(true)
+	}
+}
+
+fn tool_call_from_tool_use(id: String, name: String, arguments: String) -> ToolCall {
+	ToolCall {
+		id,
+		call_type: String::from("function"),
+		function: FunctionCall { name, arguments },
+	}
+}
+
+fn with_bearer(builder: RequestBuilder, api_key: Option<&str>) -> RequestBuilder {
+	match api_key {
+		Some(api_key) => builder.header(AUTHORIZATION, format!("Bearer {api_key}")),
+		None => builder,
+	}
+}
+
+/// Translates Anthropic's stop reasons to OpenAI's finish reasons.
+fn finish_reason_from_stop_reason(stop_reason: Option<&str>) -> String {
+	let finish_reason = match stop_reason {
+		Some("end_turn" | "stop_sequence" | "pause_turn") => "stop",
+		Some("max_tokens") => "length",
+		Some("refusal") => "content_filter",
+		Some("tool_use") => "tool_calls",
+		Some(reason) => reason,
+		None => "null",
+	};
+	String::from(finish_reason)
+}
+
+/// One event of a streamed completion in the OpenAI format
+#[derive(Debug, Clone, PartialEq, PartialOrd, Deserialize)]
+#[serde(untagged)]
+enum StreamEvent {
+	Error { error: CompletionError },
+	Chunk(CompletionChunk),
+}
+
+/// A piece of a streamed completion in the OpenAI format
+#[derive(Debug, Clone, PartialEq, PartialOrd, Deserialize)]
+struct CompletionChunk {
+	id: Option<String>,
+	created: Option<u64>,
+	model: String,
+	#[serde(default)]
+	choices: Vec<ChunkChoice>,
+	/// Only present on the last chunk
+	usage: Option<TokenUsage>,
+}
+
+#[derive(Debug, Clone, PartialEq, PartialOrd, Deserialize)]
+struct ChunkChoice {
+	delta: ChunkDelta,
+	finish_reason: Option<String>,
+	index: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, PartialOrd, Deserialize)]
+struct ChunkDelta {
+	content: Option<String>,
+	#[serde(default)]
+	tool_calls: Vec<ToolCallDelta>,
+}
+
+#[derive(Debug, Clone, PartialEq, PartialOrd, Deserialize)]
+struct ToolCallDelta {
+	index: usize,
+	id: Option<String>,
+	#[serde(default)]
+	function: FunctionCallDelta,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, PartialOrd, Deserialize)]
+struct FunctionCallDelta {
+	name: Option<String>,
+	arguments: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+struct CompatibleRequest<'a> {
+	model: &'a str,
+	messages: &'a [ChatMessage],
+	#[serde(skip_serializing_if = "Option::is_none")]
+	temperature: Option<f32>,
+	max_tokens: u32,
+	#[serde(skip_serializing_if = "std::ops::Not::not")]
+	stream: bool,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	stream_options: Option<StreamOptions>,
+	#[serde(skip_serializing_if = "<[_]>::is_empty")]
+	tools: &'a [ToolDefinition],
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+struct MessagesRequest<'a> {
+	model: &'a str,
+	/// Anthropic takes the system message separately from the conversation
+	#[serde(skip_serializing_if = "Option::is_none")]
+	system: Option<String>,
+	messages: Vec<MessagesRequestMessage<'a>>,
+	max_tokens: u32,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	temperature: Option<f32>,
+	#[serde(skip_serializing_if = "std::ops::Not::not")]
+	stream: bool,
+	#[serde(skip_serializing_if = "Vec::is_empty")]
+	tools: Vec<MessagesTool<'a>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+struct MessagesRequestMessage<'a> {
+	role: &'static str,
+	content: Vec<RequestContentBlock<'a>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum RequestContentBlock<'a> {
+	Text {
+		text: &'a str,
+	},
+	ToolUse {
+		id: &'a str,
+		name: &'a str,
+		input: Value,
+	},
+	ToolResult {
+		tool_use_id: &'a str,
+		content: &'a str,
+	},
+	Image {
+		source: ImageSource<'a>,
+	},
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ImageSource<'a> {
+	Base64 { media_type: &'a str, data: &'a str },
+	Url { url: &'a str },
+}
+
+impl<'a> ImageSource<'a> {
+	/// Anthropic takes data URLs apart.
+	fn from_url(url: &'a str) -> Self {
+		match url
+			.strip_prefix("data:")
+			.and_then(|rest| rest.split_once(";base64,"))
+		{
+			Some((media_type, data)) => Self::Base64 { media_type, data },
+			None => Self::Url { url },
+		}
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+struct MessagesTool<'a> {
+	name: &'a str,
+	description: &'a str,
+	input_schema: &'a Value,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+enum MessagesResponse {
+	Error { error: CompletionError },
+	Message(MessagesMessage),
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+struct MessagesMessage {
+	id: String,
+	model: String,
+	#[serde(default)]
+	content: Vec<ContentBlock>,
+	stop_reason: Option<String>,
+	usage: MessagesUsage,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentBlock {
+	Text {
+		text: String,
+	},
+	ToolUse {
+		id: String,
+		name: String,
+		input: Value,
+	},
+	#[serde(other)]
+	Other,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Deserialize)]
+struct MessagesUsage {
+	#[serde(default)]
+	input_tokens: u32,
+	#[serde(default)]
+	output_tokens: u32,
+	cache_creation_input_tokens: Option<u32>,
+	cache_read_input_tokens: Option<u32>,
+}
+
+impl From<MessagesUsage> for TokenUsage {
+	/// Anthropic counts cached input separately from the rest, while OpenAI counts it as part of the prompt.
+	fn from(usage: MessagesUsage) -> Self {
+		let cached_tokens = usage.cache_read_input_tokens.unwrap_or(0);
+		let prompt_tokens =
+			usage.input_tokens + usage.cache_creation_input_tokens.unwrap_or(0) + cached_tokens;
+		Self {
+			prompt_tokens,
+			completion_tokens: usage.output_tokens,
+			total_tokens: prompt_tokens + usage.output_tokens,
+			prompt_tokens_details: PromptTokenDetails {
+				cached_tokens,
+				..Default::default()
+			},
+			..Default::default()
+		}
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum MessagesStreamEvent {
+	MessageStart {
+		message: MessagesMessage,
+	},
+	ContentBlockStart {
+		content_block: ContentBlock,
+	},
+	ContentBlockDelta {
+		delta: ContentDelta,
+	},
+	MessageDelta {
+		delta: MessageDeltaBody,
+		usage: MessageDeltaUsage,
+	},
+	MessageStop,
+	Error {
+		error: CompletionError,
+	},
+	/// Pings and the end of content blocks
+	#[serde(other)]
+	Other,
+}
+
+#[derive(Debug, Clone, PartialEq, PartialOrd, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentDelta {
+	TextDelta {
+		text: String,
+	},
+	InputJsonDelta {
+		partial_json: String,
+	},
+	#[serde(other)]
+	Other,
+}
+
+#[derive(Debug, Clone, PartialEq, PartialOrd, Deserialize)]
+struct MessageDeltaBody {
+	stop_reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Deserialize)]
+struct MessageDeltaUsage {
+	output_tokens: u32,
+}
+
+#[cfg(test)]
+mod tests {
+	use reqwest::Client;
+	use serde_json::{json, Value};
+
+	use super::{Anthropic, Provider};
+	use crate::{
+		gpt::{
+			ChatMessage, CompletionRequest, FunctionCall, GptModel, PartialCompletion, Sampling,
+			ServerResponse, ToolCall,
+		},
+		tools,
+	};
+
+	fn claude() -> GptModel {
+		serde_json::from_value(json!({
+			"name": "claude-test",
+			"friendly_name": "Claude Test",
+			"input_cost": 3_000,
+			"output_cost": 15_000,
+			"provider": "anthropic",
+		}))
+		.unwrap()
+	}
+
+	fn tool_call(id: &str, name: &str, arguments: &str) -> ToolCall {
+		ToolCall {
+			id: id.to_string(),
+			call_type: String::from("function"),
+			function: FunctionCall {
+				name: name.to_string(),
+				arguments: arguments.to_string(),
+			},
+		}
+	}
+
+	#[test]
+	fn anthropic_requests_take_the_system_message_apart_and_group_tool_results() {
+		let model = claude();
+		let messages = [
+			ChatMessage::system(String::from("You are being tested.")),
+			ChatMessage::system(String::from("Summary of the earlier conversation: hi.")),
+			ChatMessage::user_with_images(
+				String::from("What's this?"),
+				vec![
+					String::from("data:image/png;base64,AAAA"),
+					String::from("https://example.com/cat.png"),
+				],
+			),
+			ChatMessage {
+				tool_calls: vec![
+					tool_call("call_1", "calculate", r#"{"expression":"1+1"}"#),
+					tool_call("call_2", "current_time", ""),
+				],
+				..ChatMessage::assistant(String::new())
+			},
+			ChatMessage::tool(String::from("call_1"), String::from("2")),
+			ChatMessage::tool(String::from("call_2"), String::from("noon")),
+		];
+		let tools = tools::definitions(&[String::from("calculate")]);
+		let request = CompletionRequest::new(&model, Sampling::default())
+			.with_messages(&messages)
+			.with_tools(&tools);
+		let request = Anthropic
+			.build_request(
+				&Client::new(),
+				"https://api.anthropic.com/v1",
+				Some("key"),
+				&request,
+			)
+			.build()
+			.unwrap();
+
+		assert_eq!(
+			request.url().as_str(),
+			"https://api.anthropic.com/v1/messages"
+		);
+		assert_eq!(request.headers()["x-api-key"], "key");
+		assert!(request.headers().contains_key("anthropic-version"));
+		let body: Value =
+			serde_json::from_slice(request.body().unwrap().as_bytes().unwrap()).unwrap();
+		assert_eq!(
+			body["system"],
+			"You are being tested.\n\nSummary of the earlier conversation: hi."
+		);
+		assert_eq!(body["max_tokens"], 400);
+		assert_eq!(body["tools"][0]["name"], "calculate");
+		assert_eq!(body["tools"][0]["input_schema"]["type"], "object");
+		assert_eq!(
+			body["messages"],
+			json!([
+				{
+					"role": "user",
+					"content": [
+						{ "type": "image", "source": { "type": "base64", "media_type": "image/png", "data": "AAAA" } },
+						{ "type": "image", "source": { "type": "url", "url": "https://example.com/cat.png" } },
+						{ "type": "text", "text": "What's this?" },
+					],
+				},
+				{
+					"role": "assistant",
+					"content": [
+						{ "type": "tool_use", "id": "call_1", "name": "calculate", "input": { "expression": "1+1" } },
+						{ "type": "tool_use", "id": "call_2", "name": "current_time", "input": {} },
+					],
+				},
+				{
+					"role": "user",
+					"content": [
+						{ "type": "tool_result", "tool_use_id": "call_1", "content": "2" },
+						{ "type": "tool_result", "tool_use_id": "call_2", "content": "noon" },
+					],
+				},
+			])
+		);
+	}
+
+	#[test]
+	fn anthropic_responses_become_completions() {
+		let body = json!({
+			"id": "msg_1",
+			"type": "message",
+			"role": "assistant",
+			"model": "claude-test",
+			"content": [
+				{ "type": "thinking", "thinking": "Hmm." },
+				{ "type": "text", "text": "Let me " },
+				{ "type": "text", "text": "check." },
+				{ "type": "tool_use", "id": "toolu_1", "name": "calculate", "input": { "expression": "2*3" } },
+			],
+			"stop_reason": "tool_use",
+			"usage": {
+				"input_tokens": 100,
+				"output_tokens": 20,
+				"cache_creation_input_tokens": 30,
+				"cache_read_input_tokens": 50,
+			},
+		});
+		let ServerResponse::Completion(completion) = Anthropic
+			.parse_response(body.to_string().as_bytes())
+			.unwrap()
+		else {
+			panic!("Expected a completion.");
+		};
+		let choice = &completion.message_choices[0];
+		assert_eq!(choice.message.content, "Let me check.");
+		assert_eq!(choice.finish_reason, "tool_calls");
+		assert_eq!(choice.message.tool_calls[0].id, "toolu_1");
+		assert_eq!(
+			serde_json::from_str::<Value>(&choice.message.tool_calls[0].function.arguments)
+				.unwrap(),
+			json!({ "expression": "2*3" })
+		);
+		// Cached input is part of the prompt, like OpenAI counts it.
+		assert_eq!(completion.usage.prompt_tokens, 180);
+		assert_eq!(completion.usage.prompt_tokens_details.cached_tokens, 50);
+		assert_eq!(completion.usage.completion_tokens, 20);
+
+		let error = json!({
+			"type": "error",
+			"error": { "type": "overloaded_error", "message": "Overloaded" },
+		});
+		assert!(matches!(
+			Anthropic
+				.parse_response(error.to_string().as_bytes())
+				.unwrap(),
+			ServerResponse::Error { .. }
+		));
+	}
+
+	#[test]
+	fn anthropic_stream_events_add_up_to_a_completion() {
+		let events = [
+			json!({ "type": "message_start", "message": { "id": "msg_1", "model": "claude-test", "content": [], "stop_reason": null, "usage": { "input_tokens": 100, "output_tokens": 1 } } }),
+			json!({ "type": "content_block_start", "index": 0, "content_block": { "type": "text", "text": "" } }),
+			json!({ "type": "ping" }),
+			json!({ "type": "content_block_delta", "index": 0, "delta": { "type": "text_delta", "text": "Hel" } }),
+			json!({ "type": "content_block_delta", "index": 0, "delta": { "type": "text_delta", "text": "lo" } }),
+			json!({ "type": "content_block_start", "index": 1, "content_block": { "type": "tool_use", "id": "toolu_1", "name": "calculate", "input": {} } }),
+			json!({ "type": "content_block_delta", "index": 1, "delta": { "type": "input_json_delta", "partial_json": "{\"expression\":" } }),
+			json!({ "type": "content_block_delta", "index": 1, "delta": { "type": "input_json_delta", "partial_json": "\"1+1\"}" } }),
+			json!({ "type": "content_block_start", "index": 2, "content_block": { "type": "tool_use", "id": "toolu_2", "name": "current_time", "input": {} } }),
+			json!({ "type": "message_delta", "delta": { "stop_reason": "tool_use" }, "usage": { "output_tokens": 25 } }),
+			json!({ "type": "message_stop" }),
+		];
+		let mut partial = PartialCompletion::default();
+		let arrived = events
+			.iter()
+			.map(|event| {
+				Anthropic
+					.handle_stream_event(&event.to_string(), &mut partial)
+					.unwrap()
+			})
+			.collect::<Vec<_>>();
+		assert_eq!(
+			arrived,
+			[true, false, false, true, true, true, true, true, true, true, false]
+		);
+		assert!(partial.done);
+		assert_eq!(partial.content, "Hello");
+		assert_eq!(partial.message_id.as_deref(), Some("msg_1"));
+		assert_eq!(partial.finish_reason.as_deref(), Some("tool_calls"));
+		assert_eq!(
+			partial.tool_calls[0].function.arguments,
+			r#"{"expression":"1+1"}"#
+		);
+		// A tool without parameters gets no input at all.
+		assert_eq!(partial.tool_calls[1].function.arguments, "{}");
+		let usage = partial.usage.unwrap();
+		assert_eq!((usage.prompt_tokens, usage.completion_tokens), (100, 25));
+	}
+}
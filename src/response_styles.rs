@@ -37,6 +37,13 @@ impl Personality<'_> {
 			Self::Custom(m) => m,
 		}
 	}
+	/// Get the names of the tools GPT may call with this personality.
+	pub fn tools(&self) -> &[String] {
+		match self {
+			Self::Preset(p) => p.tools(),
+			Self::Custom(_) => &[],
+		}
+	}
 }
 
 #[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
@@ -44,6 +51,8 @@ pub struct PersonalityPreset {
 	name: String,
 	emoji: String,
 	system_message: String,
+	#[serde(default)]
+	tools: Vec<String>,
 }
 
 impl PersonalityPreset {
@@ -59,6 +68,10 @@ impl PersonalityPreset {
 	pub fn system_message(&self) -> &str {
 		&self.system_message
 	}
+	/// Get the names of the tools GPT may call with this preset.
+	pub fn tools(&self) -> &[String] {
+		&self.tools
+	}
 }
 
 /// If the string is like `"custom(whatever)"`, returns `Some("whatever")`, otherwise `None`.
@@ -0,0 +1,1331 @@
+//! Conversations driven from start to finish, against the fake API in `mock_openai` and an in-memory database.
+
+use std::{
+	str::FromStr,
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		Mutex,
+	},
+};
+
+use serenity::{
+	all::{ChannelId, GuildId, MessageId, UserId},
+	async_trait,
+	prelude::SerenityError,
+};
+use sqlx::{
+	migrate, query,
+	sqlite::{SqliteConnectOptions, SqlitePoolOptions},
+	Pool, Sqlite,
+};
+
+use crate::{
+	allowances::Allowance,
+	ambient_channels::is_on_cooldown,
+	chat::{Chat, IncomingMessage},
+	config::{Config, CustomApiKeys},
+	conversation_search::search_turns,
+	conversations::{
+		delete_turns, get_recent_reply, get_reply_author, get_thread_conversation, MessageIds,
+		SPEAKERS_NOTE,
+	},
+	gpt::Gpt,
+	mock_openai::{MockOpenAi, MockResponse},
+	user_data::{delete_user_data, export_user_data, prune_conversations},
+};
+
+const GUILD: GuildId = GuildId::new(1);
+const CHANNEL: ChannelId = ChannelId::new(2);
+const USER: UserId = UserId::new(3);
+const OTHER_USER: UserId = UserId::new(4);
+
+/// What each test user goes by in conversations.
+fn name_of(user: UserId) -> String {
+	if user == USER {
+		String::from("Alice")
+	} else {
+		String::from("Bob")
+	}
+}
+
+const DAILY_ALLOWANCE: u32 = 2_500_000;
+const ACCRUAL_DAYS: f32 = 4.0;
+
+/// A message the bot sent.
+#[derive(Debug, Clone)]
+struct SentMessage {
+	ids: MessageIds,
+	/// The message it replied to, unless it was sent without replying
+	reply_to: Option<MessageIds>,
+	content: String,
+	/// Who the regenerate button is for, once the reply is finished
+	regenerable_by: Option<UserId>,
+}
+
+/// Keeps what the bot sends instead of sending it to Discord.
+struct FakeChat {
+	next_id: AtomicU64,
+	sent: Mutex<Vec<SentMessage>>,
+	/// The names of the threads started, by the IDs of the messages they were started from
+	threads: Mutex<Vec<(MessageIds, String)>>,
+}
+
+impl FakeChat {
+	fn new() -> Self {
+		Self {
+			next_id: AtomicU64::new(1_000),
+			sent: Mutex::new(Vec::new()),
+			threads: Mutex::new(Vec::new()),
+		}
+	}
+	fn sent(&self) -> Vec<SentMessage> {
+		self.sent.lock().unwrap().clone()
+	}
+}
+
+#[async_trait]
+impl Chat for FakeChat {
+	async fn reply(&self, to: MessageIds, content: String) -> Result<MessageIds, SerenityError> {
+		let id = MessageId::new(self.next_id.fetch_add(1, Ordering::Relaxed));
+		let ids = MessageIds::new(to.guild_id, to.channel_id, id);
+		self.sent.lock().unwrap().push(SentMessage {
+			ids,
+			reply_to: Some(to),
+			content,
+			regenerable_by: None,
+		});
+		Ok(ids)
+	}
+	async fn send(&self, channel: ChannelId, content: String) -> Result<MessageId, SerenityError> {
+		let id = MessageId::new(self.next_id.fetch_add(1, Ordering::Relaxed));
+		self.sent.lock().unwrap().push(SentMessage {
+			ids: MessageIds::new(Some(GUILD), channel, id),
+			reply_to: None,
+			content,
+			regenerable_by: None,
+		});
+		Ok(id)
+	}
+	async fn start_thread(
+		&self,
+		from: MessageIds,
+		name: String,
+	) -> Result<ChannelId, SerenityError> {
+		self.threads.lock().unwrap().push((from, name));
+		// Like on Discord, a thread started from a message has the same ID as the message.
+		Ok(ChannelId::new(from.message_id.get()))
+	}
+	async fn edit(&self, message: MessageIds, content: String) -> Result<(), SerenityError> {
+		let mut sent = self.sent.lock().unwrap();
+		let sent_message = sent
+			.iter_mut()
+			.find(|sent_message| sent_message.ids.message_id == message.message_id)
+			.expect("Edited a message that was never sent.");
+		sent_message.content = content;
+		Ok(())
+	}
+	async fn finish(
+		&self,
+		message: MessageIds,
+		content: String,
+		asker: UserId,
+	) -> Result<(), SerenityError> {
+		self.edit(message, content).await?;
+		let mut sent = self.sent.lock().unwrap();
+		let sent_message = sent
+			.iter_mut()
+			.find(|sent_message| sent_message.ids.message_id == message.message_id)
+			.unwrap();
+		sent_message.regenerable_by = Some(asker);
+		Ok(())
+	}
+}
+
+struct Harness {
+	api: MockOpenAi,
+	gpt: Gpt,
+	database: Pool<Sqlite>,
+	chat: FakeChat,
+}
+
+impl Harness {
+	async fn new() -> Self {
+		let api = MockOpenAi::start().await;
+		let config = Config::from_toml(&format!(
+			r#"
+			daily_allowance = {DAILY_ALLOWANCE}
+			accrual_days = {ACCRUAL_DAYS}
+			summarize_old_messages = true
+			models = [
+				{{ name = "gpt-test", friendly_name = "GPT Test", input_cost = 100, output_cost = 400, base_url = "{0}" }},
+				{{ name = "gpt-test-big", friendly_name = "GPT Test Big", input_cost = 1000, output_cost = 4000, base_url = "{0}" }},
+				{{ name = "gpt-test-local", friendly_name = "GPT Test Local", input_cost = 10, output_cost = 40, provider = "openai_compatible", base_url = "{0}" }},
+			]
+			personalities = [
+				{{ name = "Tester", emoji = "🧪", system_message = "You are being tested." }},
+				{{ name = "Pirate", emoji = "🏴‍☠️", system_message = "You are a pirate." }},
+				{{ name = "Calculator", emoji = "🧮", system_message = "You calculate.", tools = ["calculate"] }},
+			]
+			"#,
+			api.base_url()
+		));
+		let gpt = Gpt::new("test-key", config, CustomApiKeys::default()).unwrap();
+		// Every connection to an in-memory database gets its own, so there can only be one.
+		let database = SqlitePoolOptions::new()
+			.max_connections(1)
+			.idle_timeout(None)
+			.max_lifetime(None)
+			.connect_with(SqliteConnectOptions::from_str("sqlite::memory:").unwrap())
+			.await
+			.unwrap();
+		migrate!("./migrations").run(&database).await.unwrap();
+		Self {
+			api,
+			gpt,
+			database,
+			chat: FakeChat::new(),
+		}
+	}
+	/// Sends a message from `USER` with the given ID, and returns the IDs of the bot's reply, if any.
+	async fn send(&self, id: u64, input: &str, parent: Option<MessageIds>) -> Option<MessageIds> {
+		self.send_as(USER, id, input, parent).await
+	}
+	/// Sends a message from someone with the given ID, and returns the IDs of the bot's reply, if any.
+	async fn send_as(
+		&self,
+		author: UserId,
+		id: u64,
+		input: &str,
+		parent: Option<MessageIds>,
+	) -> Option<MessageIds> {
+		let message = IncomingMessage {
+			ids: MessageIds::new(Some(GUILD), CHANNEL, MessageId::new(id)),
+			author,
+			author_name: name_of(author),
+		};
+		let sent_before = self.chat.sent().len();
+		self.gpt
+			.query(
+				&self.database,
+				&self.chat,
+				input.to_string(),
+				Vec::new(),
+				message,
+				parent,
+			)
+			.await;
+		self.chat
+			.sent()
+			.get(sent_before)
+			.map(|sent_message| sent_message.ids)
+	}
+	/// Presses the regenerate button on a reply as `USER`, and returns the IDs of the new reply, if any.
+	async fn regenerate(&self, reply: MessageIds) -> Option<MessageIds> {
+		let sent_before = self.chat.sent().len();
+		self.gpt
+			.regenerate(&self.database, &self.chat, USER, name_of(USER), reply)
+			.await;
+		self.chat
+			.sent()
+			.get(sent_before)
+			.map(|sent_message| sent_message.ids)
+	}
+	/// Edits the message with the given ID, which `reply` was the reply to, and returns the IDs of the new reply, if any.
+	async fn edit(&self, id: u64, input: &str, reply: MessageIds) -> Option<MessageIds> {
+		let message = IncomingMessage {
+			ids: MessageIds::new(Some(GUILD), CHANNEL, MessageId::new(id)),
+			author: USER,
+			author_name: name_of(USER),
+		};
+		let sent_before = self.chat.sent().len();
+		self.gpt
+			.rerun(
+				&self.database,
+				&self.chat,
+				input.to_string(),
+				Vec::new(),
+				message,
+				reply,
+			)
+			.await;
+		self.chat
+			.sent()
+			.get(sent_before)
+			.map(|sent_message| sent_message.ids)
+	}
+	/// The last thing the bot sent.
+	fn last_sent(&self) -> SentMessage {
+		self.chat.sent().last().unwrap().clone()
+	}
+	/// How many times the user was charged, and how much in total.
+	async fn spending(&self) -> (i32, i64) {
+		let user_id = USER.get() as i64;
+		let record = query!(
+			"
+			SELECT COUNT(*) AS count, SUM(cost) AS cost
+			FROM spending
+			WHERE user = ?
+			",
+			user_id
+		)
+		.fetch_one(&self.database)
+		.await
+		.unwrap();
+		(record.count, record.cost.unwrap_or(0))
+	}
+	/// Which model the last request to the API was for.
+	fn last_requested_model(&self) -> String {
+		let requests = self.api.requests();
+		requests.last().unwrap()["model"]
+			.as_str()
+			.unwrap()
+			.to_string()
+	}
+	async fn set_model_and_policy(&self, user: UserId, model: &str, policy: &str) {
+		let user_id = user.get() as i64;
+		query!(
+			"
+			INSERT INTO user_settings (user, model, model_policy)
+			VALUES (?, ?, ?)
+			",
+			user_id,
+			model,
+			policy,
+		)
+		.execute(&self.database)
+		.await
+		.unwrap();
+	}
+	async fn conversation_count(&self) -> i32 {
+		query!("SELECT COUNT(*) AS count FROM conversations")
+			.fetch_one(&self.database)
+			.await
+			.unwrap()
+			.count
+	}
+}
+
+#[tokio::test]
+async fn replies_chain_into_one_conversation() {
+	let harness = Harness::new().await;
+
+	harness.api.push(MockResponse::reply("Hello!", 20, 5));
+	let first = harness.send(100, "Hi", None).await.unwrap();
+	assert_eq!(first.channel_id, CHANNEL);
+	let sent = harness.last_sent();
+	assert_eq!(sent.reply_to.unwrap().message_id, MessageId::new(100));
+	assert!(sent.content.starts_with("🧪 Hello!"), "{}", sent.content);
+
+	harness
+		.api
+		.push(MockResponse::reply("Fine, thanks.", 30, 5));
+	let second = harness
+		.send(101, "How are you?", Some(first))
+		.await
+		.unwrap();
+
+	harness.api.push(MockResponse::reply("Goodbye!", 40, 5));
+	let third = harness.send(102, "Bye", Some(second)).await.unwrap();
+
+	let rows = query!(
+		"
+		SELECT message, parent, channel, guild, input, output, system_message
+		FROM conversations
+		ORDER BY message
+		"
+	)
+	.fetch_all(&harness.database)
+	.await
+	.unwrap();
+	assert_eq!(rows.len(), 3);
+	let [first_row, second_row, third_row] = &rows[..] else {
+		unreachable!()
+	};
+	assert_eq!(first_row.message, first.message_id.get() as i64);
+	assert_eq!(first_row.parent, None);
+	assert_eq!(first_row.input, "Hi");
+	assert_eq!(first_row.output, "Hello!");
+	assert_eq!(first_row.channel, CHANNEL.get() as i64);
+	assert_eq!(first_row.guild, Some(GUILD.get() as i64));
+	assert_eq!(first_row.system_message.as_deref(), Some("Tester"));
+	assert_eq!(second_row.message, second.message_id.get() as i64);
+	assert_eq!(second_row.parent, Some(first_row.message));
+	assert_eq!(third_row.message, third.message_id.get() as i64);
+	assert_eq!(third_row.parent, Some(second_row.message));
+	assert_eq!(third_row.output, "Goodbye!");
+
+	// The last request has the whole conversation in order.
+	let requests = harness.api.requests();
+	assert_eq!(requests.len(), 3);
+	let contents = requests[2]["messages"]
+		.as_array()
+		.unwrap()
+		.iter()
+		.map(|message| message["content"].as_str().unwrap())
+		.collect::<Vec<_>>();
+	assert_eq!(
+		contents,
+		[
+			&format!("You are being tested.\n\n{SPEAKERS_NOTE}"),
+			"Alice: Hi",
+			"Hello!",
+			"Alice: How are you?",
+			"Fine, thanks.",
+			"Alice: Bye"
+		]
+	);
+}
+
+#[tokio::test]
+async fn allowance_is_debited_once_per_reply() {
+	let harness = Harness::new().await;
+
+	harness.api.push(MockResponse::reply("Hello!", 1_000, 500));
+	harness.send(100, "Hi", None).await.unwrap();
+
+	let expected_cost = 1_000 * 100 + 500 * 400;
+	assert_eq!(harness.spending().await, (1, expected_cost));
+	let allowance = Allowance::check(&harness.database, USER, DAILY_ALLOWANCE, ACCRUAL_DAYS).await;
+	let Allowance::Nanodollars(allowance) = allowance else {
+		panic!("Expected an allowance that has been spent from, got {allowance:?}.");
+	};
+	let expected_allowance = (DAILY_ALLOWANCE as f32 * ACCRUAL_DAYS) as i64 - expected_cost;
+	// A little accrues back while the test runs.
+	assert!(
+		(expected_allowance..expected_allowance + 1_000).contains(&allowance),
+		"{allowance}"
+	);
+	assert!(harness.last_sent().content.contains("(-0.3 m$"));
+}
+
+#[tokio::test]
+async fn turns_record_who_asked_and_what_it_cost() {
+	let harness = Harness::new().await;
+
+	harness.api.push(MockResponse::reply("Hello!", 1_000, 500));
+	let reply = harness.send(100, "Hi", None).await.unwrap();
+
+	let (_, _, message_id) = reply.as_i64s();
+	let record = query!(
+		"
+		SELECT prompt_message, author, model, input_tokens, output_tokens, cost, finish_reason
+		FROM conversations
+		WHERE message = ?
+		",
+		message_id,
+	)
+	.fetch_one(&harness.database)
+	.await
+	.unwrap();
+	assert_eq!(record.prompt_message, Some(100));
+	assert_eq!(record.author, Some(USER.get() as i64));
+	assert_eq!(record.model.as_deref(), Some("gpt-test"));
+	assert_eq!(record.input_tokens, Some(1_000));
+	assert_eq!(record.output_tokens, Some(500));
+	assert_eq!(record.cost, Some(1_000 * 100 + 500 * 400));
+	assert_eq!(record.finish_reason.as_deref(), Some("stop"));
+}
+
+#[tokio::test]
+async fn errors_are_replied_without_storing_or_charging() {
+	let harness = Harness::new().await;
+
+	harness.api.push(MockResponse::error(
+		429,
+		"insufficient_quota",
+		"You exceeded your current quota.",
+	));
+	assert!(harness.send(100, "Hi", None).await.is_some());
+	assert_eq!(harness.last_sent().content, "Boop bloop, out of credit.");
+
+	harness.api.push(MockResponse::error(
+		400,
+		"invalid_request_error",
+		"Something was wrong with the request.",
+	));
+	harness.send(101, "Hi again", None).await.unwrap();
+	let sent = harness.last_sent();
+	assert_eq!(sent.content, "Boop bloop, unknown error");
+	assert_eq!(sent.reply_to.unwrap().message_id, MessageId::new(101));
+
+	assert_eq!(harness.api.requests().len(), 2);
+	assert_eq!(harness.conversation_count().await, 0);
+	assert_eq!(harness.spending().await, (0, 0));
+}
+
+#[tokio::test]
+async fn failed_replies_still_charge_for_summarizing_their_history() {
+	let harness = Harness::new().await;
+	// About 9000 tokens each, so the second turn doesn't fit next to the first in the context window.
+	let long_input = " word".repeat(9_000);
+
+	harness.api.push(MockResponse::reply("Hello!", 10, 5));
+	let first = harness.send(100, &long_input, None).await.unwrap();
+	harness
+		.api
+		.push(MockResponse::reply("They said many words.", 20, 10));
+	harness.api.push(MockResponse::error(
+		400,
+		"invalid_request_error",
+		"Something was wrong with the request.",
+	));
+	harness.send(101, &long_input, Some(first)).await.unwrap();
+	assert_eq!(harness.last_sent().content, "Boop bloop, unknown error");
+
+	assert_eq!(harness.api.requests().len(), 3);
+	assert_eq!(harness.conversation_count().await, 1);
+	let first_cost = 10 * 100 + 5 * 400;
+	let summary_cost = 20 * 100 + 10 * 400;
+	assert_eq!(harness.spending().await, (2, first_cost + summary_cost));
+}
+
+#[tokio::test]
+async fn history_is_only_summarized_when_the_reply_after_it_is_affordable_too() {
+	let harness = Harness::new().await;
+	let long_input = " word".repeat(9_000);
+
+	harness.api.push(MockResponse::reply("Hello!", 10, 5));
+	let first = harness.send(100, &long_input, None).await.unwrap();
+
+	// Half a millidollar left, which with the overspend margin covers the reply, but not summarizing too.
+	let time_to_full = chrono::Utc::now() + chrono::Duration::milliseconds(328_320_000);
+	let user_id = USER.get() as i64;
+	query!(
+		"INSERT OR REPLACE INTO allowances (user, time_to_full) VALUES (?, ?)",
+		user_id,
+		time_to_full,
+	)
+	.execute(&harness.database)
+	.await
+	.unwrap();
+
+	harness.api.push(MockResponse::reply("Fine.", 10, 5));
+	harness.send(101, &long_input, Some(first)).await.unwrap();
+	let requests = harness.api.requests();
+	assert_eq!(requests.len(), 2);
+	assert_eq!(requests[1]["messages"].as_array().unwrap().len(), 2);
+	assert!(harness
+		.last_sent()
+		.content
+		.contains("(1 earlier turn left out)"));
+	assert_eq!(harness.spending().await.0, 2);
+}
+
+#[tokio::test]
+async fn tool_rounds_stop_once_the_next_could_not_be_afforded() {
+	let harness = Harness::new().await;
+	let user_id = USER.get() as i64;
+	query!(
+		"INSERT INTO user_settings (user, system_message) VALUES (?, 'Calculator')",
+		user_id
+	)
+	.execute(&harness.database)
+	.await
+	.unwrap();
+
+	// Each round costs 6 of the 10 millidollars a new user has, so the third round isn't sent even with the overspend margin.
+	for _ in 0..3 {
+		harness.api.push(MockResponse::tool_call(
+			"calculate",
+			r#"{"expression":"1+1"}"#,
+			60_000,
+			0,
+		));
+	}
+	harness.send(100, "What is 1+1?", None).await.unwrap();
+
+	let requests = harness.api.requests();
+	assert_eq!(requests.len(), 2);
+	assert_eq!(requests[1]["messages"].as_array().unwrap().len(), 4);
+	assert!(harness
+		.last_sent()
+		.content
+		.starts_with("This could cost up to"));
+	assert_eq!(harness.conversation_count().await, 0);
+	assert_eq!(harness.spending().await, (1, 2 * 60_000 * 100));
+}
+
+#[tokio::test]
+async fn usage_is_estimated_for_compatible_servers_that_do_not_stream_it() {
+	let harness = Harness::new().await;
+	harness
+		.set_model_and_policy(USER, "gpt-test-local", "replier")
+		.await;
+
+	harness
+		.api
+		.push(MockResponse::reply_without_usage("Hello from afar!"));
+	harness.send(100, "Hi", None).await.unwrap();
+	assert_eq!(harness.last_requested_model(), "gpt-test-local");
+	assert!(harness.last_sent().content.contains("Hello from afar!"));
+
+	assert_eq!(harness.conversation_count().await, 1);
+	let (charges, cost) = harness.spending().await;
+	assert_eq!(charges, 1);
+	assert!(cost > 0, "{cost}");
+}
+
+#[tokio::test]
+async fn replies_to_unknown_messages_are_ignored() {
+	let harness = Harness::new().await;
+
+	let unknown = MessageIds::new(Some(GUILD), CHANNEL, MessageId::new(999));
+	assert!(harness.send(100, "Hi", Some(unknown)).await.is_none());
+	assert!(harness.api.requests().is_empty());
+	assert_eq!(harness.spending().await, (0, 0));
+}
+
+#[tokio::test]
+async fn regenerating_adds_a_sibling_and_charges_again() {
+	let harness = Harness::new().await;
+
+	harness.api.push(MockResponse::reply("Hello!", 20, 5));
+	let first = harness.send(100, "Hi", None).await.unwrap();
+	assert_eq!(harness.last_sent().regenerable_by, Some(USER));
+	harness
+		.api
+		.push(MockResponse::reply("Fine, thanks.", 30, 5));
+	let second = harness
+		.send(101, "How are you?", Some(first))
+		.await
+		.unwrap();
+
+	harness.api.push(MockResponse::reply("Great!", 30, 5));
+	let regenerated = harness.regenerate(second).await.unwrap();
+	let sent = harness.last_sent();
+	assert_eq!(sent.reply_to.unwrap().message_id, MessageId::new(101));
+	assert!(sent.content.starts_with("🧪 Great!"), "{}", sent.content);
+	assert_eq!(sent.regenerable_by, Some(USER));
+
+	// The same history was sent again.
+	let requests = harness.api.requests();
+	assert_eq!(requests[2]["messages"], requests[1]["messages"]);
+
+	let regenerated_id = regenerated.message_id.get() as i64;
+	let row = query!(
+		"
+		SELECT parent, input, output, system_message
+		FROM conversations
+		WHERE message = ?
+		",
+		regenerated_id
+	)
+	.fetch_one(&harness.database)
+	.await
+	.unwrap();
+	assert_eq!(row.parent, Some(first.message_id.get() as i64));
+	assert_eq!(row.input, "How are you?");
+	assert_eq!(row.output, "Great!");
+	assert_eq!(row.system_message.as_deref(), Some("Tester"));
+	assert_eq!(harness.spending().await.0, 3);
+
+	// A first reply can be regenerated too, starting another conversation.
+	harness.api.push(MockResponse::reply("Hey!", 20, 5));
+	let root = harness.regenerate(first).await.unwrap();
+	let root_id = root.message_id.get() as i64;
+	let parent = query!(
+		"SELECT parent FROM conversations WHERE message = ?",
+		root_id
+	)
+	.fetch_one(&harness.database)
+	.await
+	.unwrap()
+	.parent;
+	assert_eq!(parent, None);
+	assert_eq!(harness.conversation_count().await, 4);
+}
+
+#[tokio::test]
+async fn editing_the_prompt_replaces_the_reply() {
+	let harness = Harness::new().await;
+
+	harness.api.push(MockResponse::reply("Hello!", 20, 5));
+	let first = harness.send(100, "Hi", None).await.unwrap();
+	harness.api.push(MockResponse::reply("Paris.", 30, 5));
+	let second = harness
+		.send(101, "What's the capital of Frnace?", Some(first))
+		.await
+		.unwrap();
+
+	let asked = MessageIds::new(Some(GUILD), CHANNEL, MessageId::new(101));
+	let reply = get_recent_reply(&harness.database, asked, 120)
+		.await
+		.unwrap();
+	assert_eq!(reply.message_id, second.message_id);
+
+	harness
+		.api
+		.push(MockResponse::reply("Paris, still.", 30, 5));
+	assert!(harness
+		.edit(101, "What's the capital of France?", reply)
+		.await
+		.is_none());
+	let sent = harness.chat.sent();
+	assert_eq!(sent.len(), 2);
+	assert!(
+		sent[1].content.starts_with("🧪 Paris, still."),
+		"{}",
+		sent[1].content
+	);
+
+	// The edited prompt continues from the same parent.
+	let requests = harness.api.requests();
+	assert_eq!(requests.len(), 3);
+	assert_eq!(
+		requests[2]["messages"][3]["content"],
+		"Alice: What's the capital of France?"
+	);
+	assert_eq!(requests[2]["messages"][1]["content"], "Alice: Hi");
+
+	let second_id = second.message_id.get() as i64;
+	let row = query!(
+		"
+		SELECT parent, input, output
+		FROM conversations
+		WHERE message = ?
+		",
+		second_id
+	)
+	.fetch_one(&harness.database)
+	.await
+	.unwrap();
+	assert_eq!(row.parent, Some(first.message_id.get() as i64));
+	assert_eq!(row.input, "What's the capital of France?");
+	assert_eq!(row.output, "Paris, still.");
+	assert_eq!(harness.conversation_count().await, 2);
+	assert_eq!(harness.spending().await.0, 3);
+
+	// Edits that don't change the prompt are ignored.
+	harness
+		.edit(101, "What's the capital of France?", reply)
+		.await;
+	assert_eq!(harness.api.requests().len(), 3);
+}
+
+#[tokio::test]
+async fn deleted_turns_are_left_out_of_conversations() {
+	let harness = Harness::new().await;
+
+	harness.api.push(MockResponse::reply("Hello!", 20, 5));
+	let first = harness.send(100, "Hi", None).await.unwrap();
+	harness.api.push(MockResponse::reply("Noted.", 30, 5));
+	let second = harness
+		.send(101, "My secret is 42.", Some(first))
+		.await
+		.unwrap();
+	assert_eq!(
+		get_reply_author(&harness.database, second).await,
+		Some(USER)
+	);
+
+	// Deleting the prompt leaves the turn out.
+	delete_turns(&harness.database, CHANNEL, &[MessageId::new(101)], false).await;
+	assert_eq!(get_reply_author(&harness.database, second).await, None);
+
+	// Replies to the deleted reply are ignored.
+	assert!(harness
+		.send(102, "What's my secret?", Some(second))
+		.await
+		.is_none());
+	assert_eq!(harness.api.requests().len(), 2);
+
+	// Replies to an earlier reply don't include it.
+	harness.api.push(MockResponse::reply("Hi again!", 20, 5));
+	harness.send(103, "Hello?", Some(first)).await.unwrap();
+	let requests = harness.api.requests();
+	let contents = requests[2]["messages"]
+		.as_array()
+		.unwrap()
+		.iter()
+		.map(|message| message["content"].as_str().unwrap())
+		.collect::<Vec<_>>();
+	assert_eq!(
+		contents,
+		[
+			&format!("You are being tested.\n\n{SPEAKERS_NOTE}"),
+			"Alice: Hi",
+			"Hello!",
+			"Alice: Hello?"
+		]
+	);
+	assert_eq!(harness.conversation_count().await, 3);
+
+	// Removing takes the row out altogether.
+	delete_turns(&harness.database, CHANNEL, &[first.message_id], true).await;
+	assert_eq!(harness.conversation_count().await, 2);
+}
+
+#[tokio::test]
+async fn each_turn_is_attributed_to_who_sent_it() {
+	let harness = Harness::new().await;
+
+	harness.api.push(MockResponse::reply("Hello!", 10, 5));
+	let first = harness.send(100, "Hi", None).await.unwrap();
+	harness
+		.api
+		.push(MockResponse::reply("Hello to you too!", 10, 5));
+	harness
+		.send_as(OTHER_USER, 101, "Hi from me", Some(first))
+		.await
+		.unwrap();
+
+	let requests = harness.api.requests();
+	let contents = requests[1]["messages"]
+		.as_array()
+		.unwrap()
+		.iter()
+		.map(|message| message["content"].as_str().unwrap())
+		.collect::<Vec<_>>();
+	assert_eq!(
+		contents,
+		[
+			&format!("You are being tested.\n\n{SPEAKERS_NOTE}"),
+			"Alice: Hi",
+			"Hello!",
+			"Bob: Hi from me"
+		]
+	);
+
+	let names = query!("SELECT author_name FROM conversations ORDER BY message")
+		.fetch_all(&harness.database)
+		.await
+		.unwrap()
+		.into_iter()
+		.map(|row| row.author_name)
+		.collect::<Vec<_>>();
+	assert_eq!(names, [Some(name_of(USER)), Some(name_of(OTHER_USER))]);
+}
+
+#[tokio::test]
+async fn model_policy_decides_which_model_replies_continue_with() {
+	let harness = Harness::new().await;
+	harness
+		.set_model_and_policy(USER, "gpt-test-big", "asker-pays-sticky")
+		.await;
+
+	harness.api.push(MockResponse::reply("Hello!", 10, 5));
+	let first = harness.send(100, "Hi", None).await.unwrap();
+	assert_eq!(harness.last_requested_model(), "gpt-test-big");
+
+	// Someone replying uses their own model unless their policy says otherwise.
+	harness
+		.api
+		.push(MockResponse::reply("Hi to you too!", 10, 5));
+	harness
+		.send_as(OTHER_USER, 101, "Hi from me", Some(first))
+		.await
+		.unwrap();
+	assert_eq!(harness.last_requested_model(), "gpt-test");
+
+	harness
+		.set_model_and_policy(OTHER_USER, "gpt-test", "sticky")
+		.await;
+	harness.api.push(MockResponse::reply("Hi again!", 10, 5));
+	harness
+		.send_as(OTHER_USER, 102, "Hi again", Some(first))
+		.await
+		.unwrap();
+	assert_eq!(harness.last_requested_model(), "gpt-test-big");
+
+	// The asker's own follow-ups stay on the model the conversation was on, even after changing theirs.
+	query!("UPDATE user_settings SET model = 'gpt-test' WHERE user = 3")
+		.execute(&harness.database)
+		.await
+		.unwrap();
+	harness.api.push(MockResponse::reply("Still here!", 10, 5));
+	harness
+		.send(103, "Still there?", Some(first))
+		.await
+		.unwrap();
+	assert_eq!(harness.last_requested_model(), "gpt-test-big");
+}
+
+#[tokio::test]
+async fn threads_continue_from_their_latest_turn() {
+	let harness = Harness::new().await;
+
+	harness.api.push(MockResponse::reply("Hello!", 10, 5));
+	let message = IncomingMessage {
+		ids: MessageIds::new(Some(GUILD), CHANNEL, MessageId::new(100)),
+		author: USER,
+		author_name: name_of(USER),
+	};
+	harness
+		.gpt
+		.query_in_new_thread(
+			&harness.database,
+			&harness.chat,
+			String::from("Hi\nHow are you?"),
+			Vec::new(),
+			message,
+		)
+		.await;
+
+	let threads = harness.chat.threads.lock().unwrap().clone();
+	assert_eq!(threads.len(), 1);
+	assert_eq!(threads[0].0.message_id, MessageId::new(100));
+	assert_eq!(threads[0].1, "Hi");
+	let thread = ChannelId::new(100);
+	let first = harness.last_sent();
+	assert_eq!(first.ids.channel_id, thread);
+	assert!(first.reply_to.is_none());
+	let latest = get_thread_conversation(&harness.database, thread).await;
+	assert_eq!(
+		latest.flatten().map(|latest| latest.message_id),
+		Some(first.ids.message_id)
+	);
+	assert_eq!(
+		get_thread_conversation(&harness.database, CHANNEL)
+			.await
+			.map(|_| ()),
+		None
+	);
+
+	// Messages in the thread continue from the latest turn.
+	let message = IncomingMessage {
+		ids: MessageIds::new(Some(GUILD), thread, MessageId::new(101)),
+		author: USER,
+		author_name: name_of(USER),
+	};
+	harness
+		.api
+		.push(MockResponse::reply("Fine, thanks!", 10, 5));
+	harness
+		.gpt
+		.query(
+			&harness.database,
+			&harness.chat,
+			String::from("Good to hear"),
+			Vec::new(),
+			message,
+			latest.flatten(),
+		)
+		.await;
+	let second = harness.last_sent();
+	assert_eq!(second.ids.channel_id, thread);
+	let requests = harness.api.requests();
+	assert_eq!(
+		requests.last().unwrap()["messages"]
+			.as_array()
+			.unwrap()
+			.len(),
+		4
+	);
+	let latest = get_thread_conversation(&harness.database, thread).await;
+	assert_eq!(
+		latest.flatten().map(|latest| latest.message_id),
+		Some(second.ids.message_id)
+	);
+
+	// Deleting the latest reply goes back to the one before.
+	delete_turns(&harness.database, thread, &[second.ids.message_id], false).await;
+	let latest = get_thread_conversation(&harness.database, thread).await;
+	assert_eq!(
+		latest.flatten().map(|latest| latest.message_id),
+		Some(first.ids.message_id)
+	);
+
+	// Deleting the message the thread was started from, even among others, deletes the first turn in the thread.
+	delete_turns(
+		&harness.database,
+		CHANNEL,
+		&[MessageId::new(99), MessageId::new(100)],
+		false,
+	)
+	.await;
+	assert_eq!(get_reply_author(&harness.database, first.ids).await, None);
+	let latest = get_thread_conversation(&harness.database, thread).await;
+	assert_eq!(latest.flatten().map(|latest| latest.message_id), None);
+}
+
+#[tokio::test]
+async fn the_first_reply_in_a_thread_can_be_regenerated() {
+	let harness = Harness::new().await;
+
+	harness.api.push(MockResponse::reply("Hello!", 10, 5));
+	let message = IncomingMessage {
+		ids: MessageIds::new(Some(GUILD), CHANNEL, MessageId::new(100)),
+		author: USER,
+		author_name: name_of(USER),
+	};
+	harness
+		.gpt
+		.query_in_new_thread(
+			&harness.database,
+			&harness.chat,
+			String::from("Hi"),
+			Vec::new(),
+			message,
+		)
+		.await;
+	let thread = ChannelId::new(100);
+	let first = harness.last_sent();
+
+	// The prompt is outside the thread, so the new reply goes in the thread without replying to it.
+	harness.api.push(MockResponse::reply("Hey!", 10, 5));
+	let regenerated = harness.regenerate(first.ids).await.unwrap();
+	let sent = harness.last_sent();
+	assert_eq!(regenerated.channel_id, thread);
+	assert!(sent.reply_to.is_none());
+	assert!(sent.content.starts_with("🧪 Hey!"), "{}", sent.content);
+
+	let regenerated_id = regenerated.message_id.get() as i64;
+	let prompt_message = query!(
+		"SELECT prompt_message FROM conversations WHERE message = ?",
+		regenerated_id
+	)
+	.fetch_one(&harness.database)
+	.await
+	.unwrap()
+	.prompt_message;
+	assert_eq!(prompt_message, Some(100));
+}
+
+#[tokio::test]
+async fn direct_messages_are_stored_without_a_guild() {
+	let harness = Harness::new().await;
+	let direct_messages = ChannelId::new(50);
+	let send = |id: u64, input: &'static str, parent: Option<MessageIds>| {
+		let message = IncomingMessage {
+			ids: MessageIds::new(None, direct_messages, MessageId::new(id)),
+			author: USER,
+			author_name: name_of(USER),
+		};
+		harness.gpt.query(
+			&harness.database,
+			&harness.chat,
+			input.to_string(),
+			Vec::new(),
+			message,
+			parent,
+		)
+	};
+
+	harness.api.push(MockResponse::reply("Hello!", 1_000, 500));
+	send(100, "Hi", None).await;
+	let first = harness.last_sent();
+	assert_eq!(first.ids.guild_id, None);
+	harness
+		.api
+		.push(MockResponse::reply("Fine, thanks!", 1_000, 500));
+	send(101, "How are you?", Some(first.ids)).await;
+	let requests = harness.api.requests();
+	assert_eq!(requests[1]["messages"].as_array().unwrap().len(), 4);
+
+	let guilds = query!("SELECT guild FROM conversations")
+		.fetch_all(&harness.database)
+		.await
+		.unwrap();
+	assert_eq!(guilds.len(), 2);
+	assert!(guilds.iter().all(|record| record.guild.is_none()));
+	assert_eq!(harness.spending().await, (2, 2 * (1_000 * 100 + 500 * 400)));
+	// The same message IDs in a guild are a different conversation.
+	assert!(get_recent_reply(
+		&harness.database,
+		MessageIds::new(Some(GUILD), direct_messages, MessageId::new(100)),
+		120
+	)
+	.await
+	.is_none());
+}
+
+#[tokio::test]
+async fn ambient_channels_override_personality_and_model() {
+	let harness = Harness::new().await;
+	let channel_id = CHANNEL.get() as i64;
+	let guild_id = GUILD.get() as i64;
+	query!(
+		"
+		INSERT INTO ambient_channels (channel, guild, personality, model, cooldown)
+		VALUES (?, ?, 'Pirate', 'gpt-test-big', 30)
+		",
+		channel_id,
+		guild_id,
+	)
+	.execute(&harness.database)
+	.await
+	.unwrap();
+
+	assert!(!is_on_cooldown(&harness.database, CHANNEL, USER, 30).await);
+	harness.api.push(MockResponse::reply("Ahoy!", 10, 5));
+	harness.send(100, "Hi", None).await.unwrap();
+	let requests = harness.api.requests();
+	assert_eq!(requests[0]["model"], "gpt-test-big");
+	assert_eq!(
+		requests[0]["messages"][0]["content"],
+		format!("You are a pirate.\n\n{SPEAKERS_NOTE}")
+	);
+	assert!(is_on_cooldown(&harness.database, CHANNEL, USER, 30).await);
+	assert!(!is_on_cooldown(&harness.database, CHANNEL, OTHER_USER, 30).await);
+
+	// Prompts count even when their reply fails, so failures can't be used to skip the wait.
+	harness.api.push(MockResponse::error(
+		400,
+		"invalid_request_error",
+		"Something was wrong with the request.",
+	));
+	harness.send_as(OTHER_USER, 101, "Hi", None).await.unwrap();
+	assert_eq!(harness.conversation_count().await, 1);
+	assert!(is_on_cooldown(&harness.database, CHANNEL, OTHER_USER, 30).await);
+}
+
+#[tokio::test]
+async fn channel_summaries_are_chunked_and_charged() {
+	let harness = Harness::new().await;
+	// Each message is about 1000 tokens, so they don't all fit in one request.
+	let messages = (0..20)
+		.map(|index| format!("Alice: {index}{}", " word".repeat(1000)))
+		.collect::<Vec<_>>();
+
+	harness
+		.api
+		.push(MockResponse::reply("They said words.", 10, 5));
+	harness
+		.api
+		.push(MockResponse::reply("They kept saying words.", 10, 5));
+	let summary = harness
+		.gpt
+		.summarize_channel(&harness.database, USER, &messages)
+		.await
+		.unwrap();
+	assert!(summary.starts_with("📝 They kept saying words."));
+	assert!(summary.contains("(20 messages)"));
+
+	// The second chunk builds on the summary of the first.
+	let requests = harness.api.requests();
+	assert_eq!(requests.len(), 2);
+	let first = requests[0]["messages"][1]["content"].as_str().unwrap();
+	let second = requests[1]["messages"][1]["content"].as_str().unwrap();
+	assert!(first.starts_with("Alice: 0 word"));
+	assert!(second.starts_with("Summary of what came before: They said words.\n\nAlice: "));
+	assert!(second.ends_with(&messages[19]));
+
+	assert_eq!(harness.spending().await, (1, 2 * (10 * 100 + 5 * 400)));
+}
+
+#[tokio::test]
+async fn remembered_facts_are_told_to_conversations_when_memory_is_on() {
+	let harness = Harness::new().await;
+	let user_id = USER.get() as i64;
+
+	// Fill all but one place, so only the first extracted fact fits.
+	for index in 0..29 {
+		let memory = format!("Filler {index}");
+		query!(
+			"INSERT INTO memories (user, memory) VALUES (?, ?)",
+			user_id,
+			memory
+		)
+		.execute(&harness.database)
+		.await
+		.unwrap();
+	}
+	harness.api.push(MockResponse::reply(
+		"- Alice is vegetarian\n- Alice lives in Oslo",
+		10,
+		5,
+	));
+	let output = harness
+		.gpt
+		.extract_memories(
+			&harness.database,
+			USER,
+			"I don't eat meat, and I live in Oslo.",
+		)
+		.await
+		.unwrap();
+	assert!(output.contains("- Alice is vegetarian"));
+	assert!(output.contains("I ran out of room for 1 more."));
+	assert!(output.contains("Memory is off"));
+	assert_eq!(harness.spending().await.0, 1);
+	let newest = query!("SELECT memory FROM memories ORDER BY id DESC LIMIT 1")
+		.fetch_one(&harness.database)
+		.await
+		.unwrap()
+		.memory;
+	assert_eq!(newest, "Alice is vegetarian");
+
+	// Memory is off by default.
+	harness.api.push(MockResponse::reply("Hello!", 10, 5));
+	harness.send(100, "Hi", None).await.unwrap();
+	let requests = harness.api.requests();
+	let system_message = requests[1]["messages"][0]["content"].as_str().unwrap();
+	assert!(!system_message.contains("What you remember"));
+
+	// With it on, the facts sharing words with the prompt come first.
+	query!(
+		"INSERT INTO user_settings (user, memory) VALUES (?, TRUE)",
+		user_id
+	)
+	.execute(&harness.database)
+	.await
+	.unwrap();
+	harness
+		.api
+		.push(MockResponse::reply("Try the falafel!", 10, 5));
+	harness
+		.send(102, "What should a vegetarian eat?", None)
+		.await
+		.unwrap();
+	let requests = harness.api.requests();
+	let system_message = requests[2]["messages"][0]["content"].as_str().unwrap();
+	assert!(system_message.contains(
+		"What you remember about Alice from earlier conversations:\n- Alice is vegetarian\n- Filler 28"
+	));
+	assert_eq!(system_message.matches("\n- ").count(), 10);
+}
+
+#[tokio::test]
+async fn old_turns_are_pruned_and_conversations_continue_from_what_is_left() {
+	let harness = Harness::new().await;
+
+	harness.api.push(MockResponse::reply("Hello!", 10, 5));
+	let first = harness.send(100, "Hi", None).await.unwrap();
+	harness
+		.api
+		.push(MockResponse::reply("Fine, thanks.", 10, 5));
+	let second = harness
+		.send(101, "How are you?", Some(first))
+		.await
+		.unwrap();
+	let (_, _, first_id) = first.as_i64s();
+	let (_, _, second_id) = second.as_i64s();
+	query!(
+		"UPDATE conversations SET time = datetime('now', '-40 days') WHERE message = ?",
+		first_id
+	)
+	.execute(&harness.database)
+	.await
+	.unwrap();
+	query!(
+		"UPDATE conversations SET summary = 'They said hi.' WHERE message = ?",
+		second_id
+	)
+	.execute(&harness.database)
+	.await
+	.unwrap();
+
+	assert_eq!(prune_conversations(&harness.database, 30).await, 1);
+	assert_eq!(prune_conversations(&harness.database, 30).await, 0);
+	let record = query!(
+		"SELECT parent, summary FROM conversations WHERE message = ?",
+		second_id
+	)
+	.fetch_one(&harness.database)
+	.await
+	.unwrap();
+	assert_eq!(record.parent, None);
+	assert_eq!(record.summary, None);
+
+	// The conversation carries on from the turn that's left.
+	harness.api.push(MockResponse::reply("Bye!", 10, 5));
+	harness.send(102, "Bye", Some(second)).await.unwrap();
+	let requests = harness.api.requests();
+	let contents = requests[2]["messages"]
+		.as_array()
+		.unwrap()
+		.iter()
+		.skip(1)
+		.map(|message| message["content"].as_str().unwrap())
+		.collect::<Vec<_>>();
+	assert_eq!(
+		contents,
+		["Alice: How are you?", "Fine, thanks.", "Alice: Bye"]
+	);
+}
+
+#[tokio::test]
+async fn users_can_export_and_delete_their_data() {
+	let harness = Harness::new().await;
+
+	harness.api.push(MockResponse::reply("Hello!", 10, 5));
+	let first = harness.send(100, "Hi", None).await.unwrap();
+	harness
+		.api
+		.push(MockResponse::reply("Hello to you too!", 10, 5));
+	harness
+		.send_as(OTHER_USER, 101, "Hi from me", Some(first))
+		.await
+		.unwrap();
+	harness
+		.set_model_and_policy(USER, "gpt-test", "sticky")
+		.await;
+
+	let data = export_user_data(&harness.database, USER).await;
+	let conversations = data["conversations"].as_array().unwrap();
+	assert_eq!(conversations.len(), 1);
+	assert_eq!(conversations[0]["input"], "Hi");
+	assert_eq!(conversations[0]["output"], "Hello!");
+	assert_eq!(data["settings"]["model_policy"], "sticky");
+	assert_eq!(data["spending"].as_array().unwrap().len(), 1);
+
+	delete_user_data(&harness.database, USER).await;
+	let data = export_user_data(&harness.database, USER).await;
+	assert_eq!(data["conversations"].as_array().unwrap().len(), 0);
+	assert_eq!(data["settings"], serde_json::Value::Null);
+	assert_eq!(data["spending"].as_array().unwrap().len(), 0);
+
+	// Others' turns stay, starting their own conversations now.
+	let rows = query!("SELECT author, parent FROM conversations")
+		.fetch_all(&harness.database)
+		.await
+		.unwrap();
+	assert_eq!(rows.len(), 1);
+	assert_eq!(rows[0].author, Some(OTHER_USER.get() as i64));
+	assert_eq!(rows[0].parent, None);
+	let data = export_user_data(&harness.database, OTHER_USER).await;
+	assert_eq!(data["spending"].as_array().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn past_turns_can_be_searched_by_their_words() {
+	let harness = Harness::new().await;
+
+	harness.api.push(MockResponse::reply(
+		"Paris is the capital of France.",
+		10,
+		5,
+	));
+	let first = harness
+		.send(100, "What's the capital of France?", None)
+		.await
+		.unwrap();
+	harness.api.push(MockResponse::reply(
+		"Berlin is the capital of Germany.",
+		10,
+		5,
+	));
+	harness
+		.send_as(OTHER_USER, 101, "And of Germany?", Some(first))
+		.await
+		.unwrap();
+	harness.api.push(MockResponse::reply("Rome.", 10, 5));
+	let third = harness
+		.send(102, "And Italy's capital?", None)
+		.await
+		.unwrap();
+
+	// Only the user's own turns, with all the words, and only while they're not deleted.
+	let results = search_turns(&harness.database, USER, "capital").await;
+	assert_eq!(results.len(), 2);
+	let results = search_turns(&harness.database, USER, "Paris capital").await;
+	assert_eq!(results.len(), 1);
+	assert_eq!(results[0].ids.message_id, first.message_id);
+	assert!(results[0].snippet.contains("**capital**"));
+	assert!(search_turns(&harness.database, USER, "Germany")
+		.await
+		.is_empty());
+	delete_turns(&harness.database, CHANNEL, &[third.message_id], true).await;
+	assert_eq!(
+		search_turns(&harness.database, USER, "capital").await.len(),
+		1
+	);
+
+	// Search syntax in the words is taken literally.
+	assert!(search_turns(&harness.database, USER, "\"France OR NEAR(")
+		.await
+		.is_empty());
+	assert!(search_turns(&harness.database, USER, "   ")
+		.await
+		.is_empty());
+}
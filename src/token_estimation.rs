@@ -0,0 +1,64 @@
+//! Counting tokens locally, to know roughly what a request will cost before sending it.
+
+use serde::Deserialize;
+use tiktoken_rs::{cl100k_base_singleton, o200k_base_singleton, CoreBPE};
+
+use crate::gpt::{ChatMessage, ToolDefinition};
+
+/// Every message is wrapped in a few tokens marking where it starts and who it's from.
+const TOKENS_PER_MESSAGE: u32 = 3;
+/// The reply is primed with a few tokens too.
+const TOKENS_PER_REPLY: u32 = 3;
+/// What a large high detail image costs on GPT-4o. Images are scaled and cut into tiles, so their real cost depends on their size, which isn't known here.
+const TOKENS_PER_IMAGE: u32 = 1105;
+
+/// The BPE tables used by a model. Other providers' models don't use either, but these still give a fair estimate.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Tokenizer {
+	/// Used by GPT-3.5 and GPT-4
+	Cl100k,
+	/// Used by GPT-4o and later
+	#[default]
+	O200k,
+}
+
+impl Tokenizer {
+	fn bpe(self) -> &'static CoreBPE {
+		match self {
+			Self::Cl100k => cl100k_base_singleton(),
+			Self::O200k => o200k_base_singleton(),
+		}
+	}
+	pub fn count(self, text: &str) -> u32 {
+		self.bpe().encode_ordinary(text).len() as u32
+	}
+	/// Estimates how many tokens a single message in the history takes up.
+	pub fn estimate_message_tokens(self, message: &ChatMessage) -> u32 {
+		let tool_calls: u32 = message
+			.tool_calls
+			.iter()
+			.map(|tool_call| {
+				self.count(&tool_call.function.name) + self.count(&tool_call.function.arguments)
+			})
+			.sum();
+		TOKENS_PER_MESSAGE
+			+ self.count(&message.content)
+			+ message.images.len() as u32 * TOKENS_PER_IMAGE
+			+ tool_calls
+	}
+	/// Estimates how many tokens the prompt made of the history and the tool definitions will be.
+	pub fn estimate_prompt_tokens(self, history: &[ChatMessage], tools: &[ToolDefinition]) -> u32 {
+		let messages: u32 = history
+			.iter()
+			.map(|message| self.estimate_message_tokens(message))
+			.sum();
+		// The API turns the definitions into text in its own way, but the JSON is about as long.
+		let tools = if tools.is_empty() {
+			0
+		} else {
+			self.count(&serde_json::to_string(tools).unwrap())
+		};
+		messages + tools + TOKENS_PER_REPLY
+	}
+}
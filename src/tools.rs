@@ -0,0 +1,695 @@
+//! Tools GPT can call to get answers it can't reliably come up with itself, like random numbers or exact arithmetic.
+
+use chrono::{FixedOffset, Utc};
+use rand::Rng;
+use serde_json::{json, Value};
+
+use crate::gpt::{ChatMessage, FunctionDefinition, ToolCall, ToolDefinition};
+
+/// How many times at most GPT gets to call tools before it has to answer. The last round is sent without tools.
+pub const MAX_TOOL_ROUNDS: u32 = 5;
+
+pub trait Tool: Sync {
+	/// The name GPT calls the tool by, which is also the name used in the config.
+	fn name(&self) -> &'static str;
+	/// Tells GPT what the tool is for.
+	fn description(&self) -> &'static str;
+	/// A JSON schema of the arguments.
+	fn parameters(&self) -> Value;
+	/// Runs the tool. Errors are passed on to GPT as the result, so it can try again or explain.
+	fn call(&self, arguments: &Value) -> Result<String, String>;
+}
+
+/// Every tool there is. To add one, implement `Tool` for it and list it here.
+const TOOLS: &[&dyn Tool] = &[&DiceRoller, &Calculator, &CurrentTime, &UnitConverter];
+
+pub fn get_tool_by_name(name: &str) -> Option<&'static dyn Tool> {
+	TOOLS.iter().find(|tool| tool.name() == name).copied()
+}
+
+/// The definitions to send to the API for the named tools. Names are checked on creating `Config`.
+pub fn definitions(names: &[String]) -> Vec<ToolDefinition> {
+	names
+		.iter()
+		.filter_map(|name| get_tool_by_name(name))
+		.map(|tool| ToolDefinition {
+			tool_type: "function",
+			function: FunctionDefinition {
+				name: tool.name(),
+				description: tool.description(),
+				parameters: tool.parameters(),
+			},
+		})
+		.collect()
+}
+
+/// Runs each of the tool calls, if the tool is among the enabled ones, and returns the results as messages to add to the history.
+pub fn run_tool_calls(tool_calls: &[ToolCall], enabled: &[String]) -> Vec<ChatMessage> {
+	tool_calls
+		.iter()
+		.map(|tool_call| {
+			let name = &tool_call.function.name;
+			let result = if !enabled.contains(name) {
+				Err(format!("There is no tool called \"{name}\"."))
+			} else {
+				serde_json::from_str(&tool_call.function.arguments)
+					.map_err(|error| format!("The arguments were not valid JSON: {error}"))
+					.and_then(|arguments| get_tool_by_name(name).unwrap().call(&arguments))
+			};
+			let content = result.unwrap_or_else(|error| format!("Error: {error}"));
+			ChatMessage::tool(tool_call.id.clone(), content)
+		})
+		.collect()
+}
+
+fn string_argument<'a>(arguments: &'a Value, name: &str) -> Result<&'a str, String> {
+	arguments
+		.get(name)
+		.and_then(Value::as_str)
+		.ok_or_else(|| format!("Missing string argument \"{name}\"."))
+}
+
+fn number_argument(arguments: &Value, name: &str) -> Result<f64, String> {
+	arguments
+		.get(name)
+		.and_then(Value::as_f64)
+		.ok_or_else(|| format!("Missing number argument \"{name}\"."))
+}
+
+// Dice
+
+struct DiceRoller;
+
+const MAX_DICE: u32 = 100;
+const MAX_SIDES: u32 = 1000;
+
+impl Tool for DiceRoller {
+	fn name(&self) -> &'static str {
+		"roll_dice"
+	}
+	fn description(&self) -> &'static str {
+		"Rolls dice in standard dice notation, like \"2d6+1d4+3\", and returns each roll and the total."
+	}
+	fn parameters(&self) -> Value {
+		json!({
+			"type": "object",
+			"properties": {
+				"dice": { "type": "string", "description": "The dice to roll, like \"d20\" or \"3d6-2\"." }
+			},
+			"required": ["dice"],
+		})
+	}
+	fn call(&self, arguments: &Value) -> Result<String, String> {
+		let notation = string_argument(arguments, "dice")?.replace(' ', "");
+		let mut rng = rand::thread_rng();
+		let mut total: i64 = 0;
+		let mut parts = Vec::new();
+		let mut rest = notation.as_str();
+		let add = |total: i64, sign: i64, number: i64| {
+			sign.checked_mul(number)
+				.and_then(|number| total.checked_add(number))
+				.ok_or_else(|| String::from("Total is too large."))
+		};
+		while !rest.is_empty() {
+			let sign = match rest.as_bytes()[0] {
+				b'-' => -1,
+				_ => 1,
+			};
+			rest = rest.strip_prefix(['+', '-']).unwrap_or(rest);
+			let term_end = rest.find(['+', '-']).unwrap_or(rest.len());
+			let term = &rest[..term_end];
+			rest = &rest[term_end..];
+			let (count, sides) = match term.split_once(['d', 'D']) {
+				Some((count, sides)) => {
+					let count = if count.is_empty() {
+						1
+					} else {
+						count
+							.parse()
+							.map_err(|_| format!("Bad dice count in {term}."))?
+					};
+					let sides: u32 = sides
+						.parse()
+						.map_err(|_| format!("Bad number of sides in {term}."))?;
+					(count, sides)
+				}
+				None => {
+					let number: i64 = term.parse().map_err(|_| format!("Bad term {term}."))?;
+					total = add(total, sign, number)?;
+					parts.push(format!("{}{number}", if sign < 0 { "-" } else { "+" }));
+					continue;
+				}
+			};
+			if count == 0 || count > MAX_DICE || !(2..=MAX_SIDES).contains(&sides) {
+				return Err(format!(
+					"Between 1 and {MAX_DICE} dice with 2 to {MAX_SIDES} sides, please."
+				));
+			}
+			let rolls: Vec<u32> = (0..count).map(|_| rng.gen_range(1..=sides)).collect();
+			total = add(total, sign, rolls.iter().map(|&roll| roll as i64).sum())?;
+			parts.push(format!(
+				"{}{term} {rolls:?}",
+				if sign < 0 { "-" } else { "+" }
+			));
+		}
+		if parts.is_empty() {
+			return Err(String::from("No dice given."));
+		}
+		let parts = parts.join(" ");
+		Ok(format!("{} = {total}", parts.trim_start_matches('+')))
+	}
+}
+
+// Arithmetic
+
+/// How deeply signs, parentheses and powers can be nested, so that long expressions can't overflow the stack
+const MAX_NESTING: u32 = 100;
+
+struct Calculator;
+
+impl Tool for Calculator {
+	fn name(&self) -> &'static str {
+		"calculate"
+	}
+	fn description(&self) -> &'static str {
+		"Evaluates an arithmetic expression exactly. Supports + - * / % ^, parentheses, pi, e, and the functions sqrt, abs, ln, log10, sin, cos, tan, floor, ceil and round. Angles are in radians."
+	}
+	fn parameters(&self) -> Value {
+		json!({
+			"type": "object",
+			"properties": {
+				"expression": { "type": "string", "description": "The expression, like \"(2 + 3) * sqrt(16)\"." }
+			},
+			"required": ["expression"],
+		})
+	}
+	fn call(&self, arguments: &Value) -> Result<String, String> {
+		let expression = string_argument(arguments, "expression")?;
+		let mut parser = ExpressionParser {
+			chars: expression.chars().filter(|c| !c.is_whitespace()).collect(),
+			position: 0,
+			depth: 0,
+		};
+		let value = parser.expression()?;
+		if parser.position != parser.chars.len() {
+			return Err(format!(
+				"Unexpected \"{}\".",
+				parser.chars[parser.position..].iter().collect::<String>()
+			));
+		}
+		if !value.is_finite() {
+			return Err(String::from("The result is not a finite number."));
+		}
+		Ok(value.to_string())
+	}
+}
+
+/// A recursive descent parser that evaluates as it goes.
+struct ExpressionParser {
+	chars: Vec<char>,
+	position: usize,
+	/// How many levels of nesting the parser is in
+	depth: u32,
+}
+
+impl ExpressionParser {
+	fn peek(&self) -> Option<char> {
+		self.chars.get(self.position).copied()
+	}
+	fn eat(&mut self, expected: char) -> bool {
+		let found = self.peek() == Some(expected);
+		if found {
+			self.position += 1;
+		}
+		found
+	}
+	/// Addition and subtraction.
+	fn expression(&mut self) -> Result<f64, String> {
+		let mut value = self.term()?;
+		loop {
+			if self.eat('+') {
+				value += self.term()?;
+			} else if self.eat('-') {
+				value -= self.term()?;
+			} else {
+				return Ok(value);
+			}
+		}
+	}
+	/// Multiplication, division and remainder.
+	fn term(&mut self) -> Result<f64, String> {
+		let mut value = self.unary()?;
+		loop {
+			if self.eat('*') {
+				value *= self.unary()?;
+			} else if self.eat('/') {
+				value /= self.unary()?;
+			} else if self.eat('%') {
+				value %= self.unary()?;
+			} else {
+				return Ok(value);
+			}
+		}
+	}
+	/// Signs. Every kind of nesting goes through here, so this is where its depth is limited.
+	fn unary(&mut self) -> Result<f64, String> {
+		if self.depth == MAX_NESTING {
+			return Err(String::from("The expression is nested too deeply."));
+		}
+		self.depth += 1;
+		let value = if self.eat('-') {
+			self.unary().map(|value| -value)
+		} else if self.eat('+') {
+			self.unary()
+		} else {
+			self.power()
+		};
+		self.depth -= 1;
+		value
+	}
+	/// Exponentiation, which is right-associative.
+	fn power(&mut self) -> Result<f64, String> {
+		let base = self.atom()?;
+		if self.eat('^') {
+			Ok(base.powf(self.unary()?))
+		} else {
+			Ok(base)
+		}
+	}
+	fn atom(&mut self) -> Result<f64, String> {
+		match self.peek() {
+			Some('(') => {
+				self.position += 1;
+				let value = self.expression()?;
+				if !self.eat(')') {
+					return Err(String::from("Missing closing parenthesis."));
+				}
+				Ok(value)
+			}
+			Some(c) if c.is_ascii_digit() || c == '.' => {
+				let start = self.position;
+				while self.peek().is_some_and(|c| c.is_ascii_digit() || c == '.') {
+					self.position += 1;
+				}
+				let number: String = self.chars[start..self.position].iter().collect();
+				number
+					.parse()
+					.map_err(|_| format!("Bad number \"{number}\"."))
+			}
+			Some(c) if c.is_ascii_alphabetic() => {
+				let start = self.position;
+				while self.peek().is_some_and(|c| c.is_ascii_alphanumeric()) {
+					self.position += 1;
+				}
+				let name: String = self.chars[start..self.position].iter().collect();
+				match name.as_str() {
+					"pi" => return Ok(std::f64::consts::PI),
+					"e" => return Ok(std::f64::consts::E),
+					_ => (),
+				}
+				let function: fn(f64) -> f64 = match name.as_str() {
+					"sqrt" => f64::sqrt,
+					"abs" => f64::abs,
+					"ln" => f64::ln,
+					"log10" => f64::log10,
+					"sin" => f64::sin,
+					"cos" => f64::cos,
+					"tan" => f64::tan,
+					"floor" => f64::floor,
+					"ceil" => f64::ceil,
+					"round" => f64::round,
+					_ => return Err(format!("Unknown name \"{name}\".")),
+				};
+				if !self.eat('(') {
+					return Err(format!("Expected \"(\" after {name}."));
+				}
+				let argument = self.expression()?;
+				if !self.eat(')') {
+					return Err(String::from("Missing closing parenthesis."));
+				}
+				Ok(function(argument))
+			}
+			Some(c) => Err(format!("Unexpected \"{c}\".")),
+			None => Err(String::from("Unexpected end of expression.")),
+		}
+	}
+}
+
+// Date and time
+
+struct CurrentTime;
+
+impl Tool for CurrentTime {
+	fn name(&self) -> &'static str {
+		"current_time"
+	}
+	fn description(&self) -> &'static str {
+		"Gets the current date and time, in UTC or at the given offset from UTC."
+	}
+	fn parameters(&self) -> Value {
+		json!({
+			"type": "object",
+			"properties": {
+				"utc_offset_hours": { "type": "number", "description": "Hours ahead of UTC, like 2 or -5.5. Defaults to 0." }
+			},
+		})
+	}
+	fn call(&self, arguments: &Value) -> Result<String, String> {
+		let offset_hours = number_argument(arguments, "utc_offset_hours").unwrap_or(0.0);
+		let offset = FixedOffset::east_opt((offset_hours * 3600.0).round() as i32)
+			.ok_or_else(|| format!("{offset_hours} hours is not a valid offset."))?;
+		let now = Utc::now().with_timezone(&offset);
+		Ok(now.format("%A %Y-%m-%d %H:%M:%S (UTC%:z)").to_string())
+	}
+}
+
+// Units
+
+struct UnitConverter;
+
+/// What a unit measures, since only units measuring the same thing can be converted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Dimension {
+	Length,
+	Mass,
+	Volume,
+	Area,
+	Time,
+	Speed,
+	Temperature,
+}
+
+/// Units with their names and how many of the base unit of their dimension one of them is. Temperatures are handled separately.
+const UNITS: &[(&[&str], Dimension, f64)] = &[
+	(
+		&["m", "meter", "meters", "metre", "metres"],
+		Dimension::Length,
+		1.0,
+	),
+	(
+		&["km", "kilometer", "kilometers", "kilometre", "kilometres"],
+		Dimension::Length,
+		1000.0,
+	),
+	(
+		&[
+			"cm",
+			"centimeter",
+			"centimeters",
+			"centimetre",
+			"centimetres",
+		],
+		Dimension::Length,
+		0.01,
+	),
+	(
+		&[
+			"mm",
+			"millimeter",
+			"millimeters",
+			"millimetre",
+			"millimetres",
+		],
+		Dimension::Length,
+		0.001,
+	),
+	(&["mi", "mile", "miles"], Dimension::Length, 1609.344),
+	(&["yd", "yard", "yards"], Dimension::Length, 0.9144),
+	(&["ft", "foot", "feet"], Dimension::Length, 0.3048),
+	(&["in", "inch", "inches"], Dimension::Length, 0.0254),
+	(
+		&["nmi", "nautical mile", "nautical miles"],
+		Dimension::Length,
+		1852.0,
+	),
+	(&["kg", "kilogram", "kilograms"], Dimension::Mass, 1.0),
+	(&["g", "gram", "grams"], Dimension::Mass, 0.001),
+	(
+		&["mg", "milligram", "milligrams"],
+		Dimension::Mass,
+		0.000_001,
+	),
+	(
+		&["t", "tonne", "tonnes", "metric ton", "metric tons"],
+		Dimension::Mass,
+		1000.0,
+	),
+	(
+		&["lb", "lbs", "pound", "pounds"],
+		Dimension::Mass,
+		0.453_592_37,
+	),
+	(
+		&["oz", "ounce", "ounces"],
+		Dimension::Mass,
+		0.028_349_523_125,
+	),
+	(&["st", "stone", "stones"], Dimension::Mass, 6.350_293_18),
+	(
+		&["l", "liter", "liters", "litre", "litres"],
+		Dimension::Volume,
+		1.0,
+	),
+	(
+		&[
+			"ml",
+			"milliliter",
+			"milliliters",
+			"millilitre",
+			"millilitres",
+		],
+		Dimension::Volume,
+		0.001,
+	),
+	(
+		&[
+			"m3",
+			"cubic meter",
+			"cubic meters",
+			"cubic metre",
+			"cubic metres",
+		],
+		Dimension::Volume,
+		1000.0,
+	),
+	(
+		&["gal", "gallon", "gallons"],
+		Dimension::Volume,
+		3.785_411_784,
+	),
+	(&["qt", "quart", "quarts"], Dimension::Volume, 0.946_352_946),
+	(&["pt", "pint", "pints"], Dimension::Volume, 0.473_176_473),
+	(&["cup", "cups"], Dimension::Volume, 0.236_588_236_5),
+	(
+		&["floz", "fl oz", "fluid ounce", "fluid ounces"],
+		Dimension::Volume,
+		0.029_573_529_562_5,
+	),
+	(
+		&[
+			"m2",
+			"square meter",
+			"square meters",
+			"square metre",
+			"square metres",
+		],
+		Dimension::Area,
+		1.0,
+	),
+	(
+		&[
+			"km2",
+			"square kilometer",
+			"square kilometers",
+			"square kilometre",
+			"square kilometres",
+		],
+		Dimension::Area,
+		1_000_000.0,
+	),
+	(&["ha", "hectare", "hectares"], Dimension::Area, 10_000.0),
+	(&["acre", "acres"], Dimension::Area, 4_046.856_422_4),
+	(
+		&["ft2", "square foot", "square feet"],
+		Dimension::Area,
+		0.092_903_04,
+	),
+	(
+		&["mi2", "square mile", "square miles"],
+		Dimension::Area,
+		2_589_988.110_336,
+	),
+	(&["s", "second", "seconds"], Dimension::Time, 1.0),
+	(&["min", "minute", "minutes"], Dimension::Time, 60.0),
+	(&["h", "hour", "hours"], Dimension::Time, 3600.0),
+	(&["d", "day", "days"], Dimension::Time, 86_400.0),
+	(&["wk", "week", "weeks"], Dimension::Time, 604_800.0),
+	(
+		&["m/s", "meters per second", "metres per second"],
+		Dimension::Speed,
+		1.0,
+	),
+	(
+		&["km/h", "kph", "kilometers per hour", "kilometres per hour"],
+		Dimension::Speed,
+		1.0 / 3.6,
+	),
+	(&["mph", "miles per hour"], Dimension::Speed, 0.447_04),
+	(&["kn", "knot", "knots"], Dimension::Speed, 0.514_444),
+	(&["c", "celsius", "°c"], Dimension::Temperature, 0.0),
+	(&["f", "fahrenheit", "°f"], Dimension::Temperature, 0.0),
+	(&["k", "kelvin"], Dimension::Temperature, 0.0),
+];
+
+fn find_unit(name: &str) -> Option<(&'static str, Dimension, f64)> {
+	let name = name.trim().to_lowercase();
+	UNITS
+		.iter()
+		.find(|(names, _, _)| names.contains(&name.as_str()))
+		.map(|(names, dimension, factor)| (names[0], *dimension, *factor))
+}
+
+fn to_kelvin(value: f64, unit: &str) -> f64 {
+	match unit {
+		"c" => value + 273.15,
+		"f" => (value - 32.0) * 5.0 / 9.0 + 273.15,
+		_ => value,
+	}
+}
+
+fn from_kelvin(value: f64, unit: &str) -> f64 {
+	match unit {
+		"c" => value - 273.15,
+		"f" => (value - 273.15) * 9.0 / 5.0 + 32.0,
+		_ => value,
+	}
+}
+
+impl Tool for UnitConverter {
+	fn name(&self) -> &'static str {
+		"convert_units"
+	}
+	fn description(&self) -> &'static str {
+		"Converts a value between units of length, mass, volume (US customary), area, time, speed or temperature, like km to mi or F to C."
+	}
+	fn parameters(&self) -> Value {
+		json!({
+			"type": "object",
+			"properties": {
+				"value": { "type": "number" },
+				"from": { "type": "string", "description": "The unit to convert from, like \"km\"." },
+				"to": { "type": "string", "description": "The unit to convert to, like \"mi\"." }
+			},
+			"required": ["value", "from", "to"],
+		})
+	}
+	fn call(&self, arguments: &Value) -> Result<String, String> {
+		let value = number_argument(arguments, "value")?;
+		let from_name = string_argument(arguments, "from")?;
+		let to_name = string_argument(arguments, "to")?;
+		let (from, from_dimension, from_factor) =
+			find_unit(from_name).ok_or_else(|| format!("Unknown unit \"{from_name}\"."))?;
+		let (to, to_dimension, to_factor) =
+			find_unit(to_name).ok_or_else(|| format!("Unknown unit \"{to_name}\"."))?;
+		if from_dimension != to_dimension {
+			return Err(format!(
+				"Can't convert {from_dimension:?} to {to_dimension:?}."
+			));
+		}
+		let result = if from_dimension == Dimension::Temperature {
+			from_kelvin(to_kelvin(value, from), to)
+		} else {
+			value * from_factor / to_factor
+		};
+		Ok(format!("{value} {from_name} = {result} {to_name}"))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use serde_json::json;
+
+	use super::{Calculator, DiceRoller, Tool};
+
+	fn roll(dice: &str) -> Result<String, String> {
+		DiceRoller.call(&json!({ "dice": dice }))
+	}
+
+	fn calculate(expression: &str) -> Result<String, String> {
+		Calculator.call(&json!({ "expression": expression }))
+	}
+
+	#[test]
+	fn calculator_follows_precedence() {
+		assert_eq!(calculate("2 + 3 * 4").unwrap(), "14");
+		assert_eq!(calculate("(2 + 3) * 4").unwrap(), "20");
+		assert_eq!(calculate("2 ^ 3 ^ 2").unwrap(), "512");
+		assert_eq!(calculate("-2 ^ 2").unwrap(), "-4");
+		assert_eq!(calculate("7 % 4 - 10 / 4").unwrap(), "0.5");
+		assert_eq!(calculate("sqrt(16) + abs(-1.5)").unwrap(), "5.5");
+		assert_eq!(calculate("round(pi * 100)").unwrap(), "314");
+	}
+
+	#[test]
+	fn calculator_rejects_malformed_expressions() {
+		assert_eq!(
+			calculate("(1 + 2").unwrap_err(),
+			"Missing closing parenthesis."
+		);
+		assert_eq!(calculate("1 + 2)").unwrap_err(), "Unexpected \")\".");
+		assert_eq!(calculate("foo(1)").unwrap_err(), "Unknown name \"foo\".");
+		assert_eq!(
+			calculate("1 +").unwrap_err(),
+			"Unexpected end of expression."
+		);
+		assert_eq!(
+			calculate("1 / 0").unwrap_err(),
+			"The result is not a finite number."
+		);
+	}
+
+	#[test]
+	fn calculator_limits_nesting_instead_of_overflowing_the_stack() {
+		let nested = format!("{}1{}", "(".repeat(99), ")".repeat(99));
+		assert_eq!(calculate(&nested).unwrap(), "1");
+
+		for expression in [
+			"-".repeat(100_000) + "1",
+			"(".repeat(100_000) + "1",
+			"2^".repeat(100_000) + "1",
+			"sqrt(".repeat(100_000) + "1",
+		] {
+			assert_eq!(
+				calculate(&expression).unwrap_err(),
+				"The expression is nested too deeply."
+			);
+		}
+	}
+
+	#[test]
+	fn dice_totals_add_up() {
+		assert_eq!(roll("3 + 4 - 10").unwrap(), "3 +4 -10 = -3");
+		assert_eq!(
+			roll("2d1+1").unwrap_err(),
+			"Between 1 and 100 dice with 2 to 1000 sides, please."
+		);
+		let rolled = roll("d2").unwrap();
+		assert!(rolled == "d2 [1] = 1" || rolled == "d2 [2] = 2", "{rolled}");
+	}
+
+	#[test]
+	fn dice_totals_too_large_are_refused() {
+		assert_eq!(
+			roll("9223372036854775807+1").unwrap_err(),
+			"Total is too large."
+		);
+		assert_eq!(
+			roll("9223372036854775807+d6").unwrap_err(),
+			"Total is too large."
+		);
+		assert_eq!(
+			roll("-9223372036854775807-2").unwrap_err(),
+			"Total is too large."
+		);
+	}
+}
@@ -0,0 +1,333 @@
+//! How long conversations are kept, and what users can do with the data stored about them.
+
+use std::time::Duration;
+
+use serde_json::{json, Value};
+use serenity::{
+	all::{CommandDataOptionValue, CommandInteraction, CommandOptionType, UserId},
+	builder::{
+		CreateAttachment, CreateCommand, CreateCommandOption, CreateInteractionResponse,
+		CreateInteractionResponseMessage,
+	},
+	prelude::Context,
+};
+use sqlx::{query, Pool, Sqlite};
+
+use crate::util::interaction_reply;
+
+/// How often conversations past the retention window are deleted
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Makes each thread continue from its latest turn that's left, after turns were deleted.
+async fn relink_threads(executor: &Pool<Sqlite>) {
+	query!(
+		"
+		UPDATE threads
+		SET latest = (
+			SELECT message
+			FROM conversations
+			WHERE channel = thread AND NOT deleted
+			ORDER BY message DESC
+			LIMIT 1
+		)
+		WHERE latest IS NULL
+		"
+	)
+	.execute(executor)
+	.await
+	.unwrap();
+}
+
+/// Deletes the turns older than `retention_days`, and returns how many there were. Turns continuing from them are left without a parent, so their conversations start there, and summaries that may include them are dropped.
+pub async fn prune_conversations(executor: &Pool<Sqlite>, retention_days: u32) -> u64 {
+	let earliest = format!("-{retention_days} days");
+	query!(
+		"
+		WITH RECURSIVE descendants (message_n)
+		AS (
+			SELECT message
+			FROM conversations
+			WHERE time < datetime('now', ?)
+			UNION
+			SELECT message
+			FROM descendants,
+				conversations
+			WHERE parent = message_n
+		)
+		UPDATE conversations
+		SET summary = NULL
+		WHERE message IN descendants AND summary IS NOT NULL
+		",
+		earliest,
+	)
+	.execute(executor)
+	.await
+	.unwrap();
+	let pruned = query!(
+		"DELETE FROM conversations WHERE time < datetime('now', ?)",
+		earliest
+	)
+	.execute(executor)
+	.await
+	.unwrap()
+	.rows_affected();
+	relink_threads(executor).await;
+	pruned
+}
+
+/// Prunes conversations every `PRUNE_INTERVAL`, forever.
+pub async fn prune_regularly(executor: Pool<Sqlite>, retention_days: u32) {
+	let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+	loop {
+		interval.tick().await;
+		let pruned = prune_conversations(&executor, retention_days).await;
+		if pruned > 0 {
+			println!("Deleted {pruned} turns older than {retention_days} days.");
+		}
+	}
+}
+
+/// Everything stored about the user: the turns they asked for, their settings, memories and spending.
+pub async fn export_user_data(executor: &Pool<Sqlite>, user: UserId) -> Value {
+	let user_id = user.get() as i64;
+	let conversations = query!(
+		"
+		SELECT
+			message,
+			guild,
+			channel,
+			parent,
+			prompt_message,
+			time,
+			input,
+			output,
+			attachments,
+			system_message,
+			model,
+			input_tokens,
+			output_tokens,
+			cost,
+			deleted
+		FROM
+			conversations
+		WHERE
+			author = ?
+		ORDER BY
+			time
+		",
+		user_id,
+	)
+	.fetch_all(executor)
+	.await
+	.unwrap()
+	.into_iter()
+	.map(|record| {
+		json!({
+			"message": record.message.to_string(),
+			"guild": record.guild.map(|guild| guild.to_string()),
+			"channel": record.channel.to_string(),
+			"parent": record.parent.map(|parent| parent.to_string()),
+			"prompt_message": record.prompt_message.map(|prompt| prompt.to_string()),
+			"time": record.time.and_utc().to_rfc3339(),
+			"input": record.input,
+			"output": record.output,
+			"attachments": record.attachments,
+			"personality": record.system_message,
+			"model": record.model,
+			"input_tokens": record.input_tokens,
+			"output_tokens": record.output_tokens,
+			"cost": record.cost,
+			"deleted": record.deleted,
+		})
+	})
+	.collect::<Vec<_>>();
+
+	let settings = query!(
+		"
+		SELECT temperature, max_tokens, model, system_message, model_policy, memory
+		FROM user_settings
+		WHERE user = ?
+		",
+		user_id,
+	)
+	.fetch_optional(executor)
+	.await
+	.unwrap()
+	.map(|record| {
+		json!({
+			"temperature": record.temperature,
+			"max_tokens": record.max_tokens,
+			"model": record.model,
+			"personality": record.system_message,
+			"model_policy": record.model_policy,
+			"memory": record.memory,
+		})
+	});
+
+	let memories = query!(
+		"SELECT memory, time FROM memories WHERE user = ? ORDER BY id",
+		user_id
+	)
+	.fetch_all(executor)
+	.await
+	.unwrap()
+	.into_iter()
+	.map(|record| json!({ "memory": record.memory, "time": record.time.and_utc().to_rfc3339() }))
+	.collect::<Vec<_>>();
+
+	let spending = query!(
+		"
+		SELECT time, model, cost, input_tokens, output_tokens, cached_input_tokens, reasoning_tokens, images, requests, search_calls
+		FROM spending
+		WHERE user = ?
+		ORDER BY time
+		",
+		user_id,
+	)
+	.fetch_all(executor)
+	.await
+	.unwrap()
+	.into_iter()
+	.map(|record| {
+		json!({
+			"time": record.time.and_utc().to_rfc3339(),
+			"model": record.model,
+			"cost": record.cost,
+			"input_tokens": record.input_tokens,
+			"output_tokens": record.output_tokens,
+			"cached_input_tokens": record.cached_input_tokens,
+			"reasoning_tokens": record.reasoning_tokens,
+			"images": record.images,
+			"requests": record.requests,
+			"search_calls": record.search_calls,
+		})
+	})
+	.collect::<Vec<_>>();
+
+	json!({
+		"user": user.to_string(),
+		"conversations": conversations,
+		"settings": settings,
+		"memories": memories,
+		"spending": spending,
+	})
+}
+
+/// Deletes the turns the user asked for, their settings, memories and spending. Their allowance is kept, so that deleting doesn't refill it. Turns others continued from theirs are left without a parent, like when pruning.
+pub async fn delete_user_data(executor: &Pool<Sqlite>, user: UserId) {
+	let user_id = user.get() as i64;
+	query!(
+		"
+		WITH RECURSIVE descendants (message_n)
+		AS (
+			SELECT message
+			FROM conversations
+			WHERE author = ?
+			UNION
+			SELECT message
+			FROM descendants,
+				conversations
+			WHERE parent = message_n
+		)
+		UPDATE conversations
+		SET summary = NULL
+		WHERE message IN descendants AND summary IS NOT NULL
+		",
+		user_id,
+	)
+	.execute(executor)
+	.await
+	.unwrap();
+	query!("DELETE FROM conversations WHERE author = ?", user_id)
+		.execute(executor)
+		.await
+		.unwrap();
+	relink_threads(executor).await;
+	query!("DELETE FROM user_settings WHERE user = ?", user_id)
+		.execute(executor)
+		.await
+		.unwrap();
+	query!("DELETE FROM memories WHERE user = ?", user_id)
+		.execute(executor)
+		.await
+		.unwrap();
+	query!("DELETE FROM spending WHERE user = ?", user_id)
+		.execute(executor)
+		.await
+		.unwrap();
+	query!("DELETE FROM ambient_prompts WHERE user = ?", user_id)
+		.execute(executor)
+		.await
+		.unwrap();
+}
+
+/// Sends the user everything stored about them as a file, or deletes it.
+pub async fn command_my_data(
+	context: Context,
+	interaction: CommandInteraction,
+	executor: &Pool<Sqlite>,
+) -> Result<(), ()> {
+	let subcommand = interaction.data.options.first().ok_or(())?;
+	let user = interaction.user.id;
+	match subcommand.name.as_str() {
+		"export" => {
+			let data = export_user_data(executor, user).await;
+			let file = serde_json::to_vec_pretty(&data).unwrap();
+			let _ = interaction
+				.create_response(
+					&context.http,
+					CreateInteractionResponse::Message(
+						CreateInteractionResponseMessage::new()
+							.content("Here's everything I have stored about you.")
+							.add_file(CreateAttachment::bytes(file, "mydata.json"))
+							.ephemeral(true),
+					),
+				)
+				.await;
+		}
+		"delete" => {
+			let CommandDataOptionValue::SubCommand(options) = &subcommand.value else {
+				return Err(());
+			};
+			let confirmed = options
+				.iter()
+				.find(|option| option.name == "confirm")
+				.and_then(|option| option.value.as_bool())
+				.unwrap_or(false);
+			let output = if confirmed {
+				delete_user_data(executor, user).await;
+				"Deleted your conversations, settings, memories and spending."
+			} else {
+				"Nothing was deleted."
+			};
+			let _ = interaction_reply(context, interaction, output, true).await;
+		}
+		_ => return Err(()),
+	}
+	Ok(())
+}
+
+pub fn register_my_data() -> CreateCommand {
+	CreateCommand::new("mydata")
+		.description("Gets or deletes everything stored about you.")
+		.add_option(CreateCommandOption::new(
+			CommandOptionType::SubCommand,
+			"export",
+			"Sends you everything stored about you, as a JSON file.",
+		))
+		.add_option(
+			CreateCommandOption::new(
+				CommandOptionType::SubCommand,
+				"delete",
+				"Deletes your conversations, settings, memories and spending. Your allowance stays.",
+			)
+			.add_sub_option(
+				CreateCommandOption::new(
+					CommandOptionType::Boolean,
+					"confirm",
+					"Set to True to really delete it all. This can't be undone.",
+				)
+				.required(true),
+			),
+		)
+}
@@ -1,11 +1,17 @@
 use serenity::{
-	all::{CommandInteraction, CommandOptionType, UserId},
+	all::{CommandInteraction, CommandOptionType, GuildId, UserId},
 	builder::{CreateCommand, CreateCommandOption},
 	prelude::Context,
 };
 use sqlx::{query, Pool, Sqlite};
 
-use crate::{gpt::Gpt, response_styles::wrap_custom, util::interaction_reply};
+use crate::{
+	allowances::Allowance,
+	conversations::ModelPolicy,
+	gpt::{Gpt, GptModel, Sampling},
+	response_styles::wrap_custom,
+	util::interaction_reply,
+};
 
 // Model
 
@@ -128,6 +134,379 @@ pub fn register_set_model(gpt: &Gpt) -> CreateCommand {
 		.add_option(model_option)
 }
 
+/// The model the user has set, or the default one.
+async fn get_user_model<'a>(executor: &Pool<Sqlite>, gpt: &'a Gpt, user: UserId) -> &'a GptModel {
+	get_model_setting(executor, user)
+		.await
+		.and_then(|name| gpt.get_model_by_name(&name))
+		.unwrap_or(gpt.default_model())
+}
+
+// Model policy
+
+/// The model policy the user set, or else the one set for the guild, if any.
+pub async fn get_model_policy(
+	executor: &Pool<Sqlite>,
+	user: UserId,
+	guild: Option<GuildId>,
+) -> Option<ModelPolicy> {
+	let user_id = user.get() as i64;
+	let user_policy = query!(
+		"
+		SELECT
+			model_policy
+		FROM
+			user_settings
+		WHERE
+			user = ?
+		",
+		user_id
+	)
+	.fetch_optional(executor)
+	.await
+	.unwrap()
+	.and_then(|record| record.model_policy);
+	let policy = match user_policy {
+		Some(policy) => Some(policy),
+		None => {
+			let guild_id = guild?.get() as i64;
+			query!(
+				"
+				SELECT
+					model_policy
+				FROM
+					guild_settings
+				WHERE
+					guild = ?
+				",
+				guild_id
+			)
+			.fetch_optional(executor)
+			.await
+			.unwrap()
+			.and_then(|record| record.model_policy)
+		}
+	};
+	policy.and_then(|name| ModelPolicy::from_name(&name))
+}
+
+async fn set_user_model_policy(executor: &Pool<Sqlite>, user: UserId, policy: Option<ModelPolicy>) {
+	let user_id = user.get() as i64;
+	let policy = policy.map(ModelPolicy::name);
+	query!(
+		"
+		INSERT INTO
+			user_settings (user, model_policy)
+		VALUES
+			(?, ?)
+		ON CONFLICT (user)
+			DO UPDATE SET
+				model_policy = excluded.model_policy
+		",
+		user_id,
+		policy,
+	)
+	.execute(executor)
+	.await
+	.unwrap();
+}
+
+async fn set_guild_model_policy(
+	executor: &Pool<Sqlite>,
+	guild: GuildId,
+	policy: Option<ModelPolicy>,
+) {
+	let guild_id = guild.get() as i64;
+	let policy = policy.map(ModelPolicy::name);
+	query!(
+		"
+		INSERT INTO
+			guild_settings (guild, model_policy)
+		VALUES
+			(?, ?)
+		ON CONFLICT (guild)
+			DO UPDATE SET
+				model_policy = excluded.model_policy
+		",
+		guild_id,
+		policy,
+	)
+	.execute(executor)
+	.await
+	.unwrap();
+}
+
+/// Set which model replies continuing a conversation use, for the user or for the whole server, or reset it without a policy.
+pub async fn command_set_model_policy(
+	context: Context,
+	interaction: CommandInteraction,
+	executor: &Pool<Sqlite>,
+	gpt: &Gpt,
+) -> Result<(), ()> {
+	let options = &interaction.data.options;
+	let policy = options
+		.iter()
+		.find(|option| option.name == "policy")
+		.and_then(|option| option.value.as_str())
+		.and_then(ModelPolicy::from_name);
+	let for_server = options
+		.iter()
+		.find(|option| option.name == "server")
+		.and_then(|option| option.value.as_bool())
+		.unwrap_or(false);
+	let output = if for_server {
+		let can_manage_server = interaction
+			.member
+			.as_ref()
+			.and_then(|member| member.permissions)
+			.is_some_and(|permissions| permissions.manage_guild());
+		match interaction.guild_id {
+			Some(guild) if can_manage_server => {
+				set_guild_model_policy(executor, guild, policy).await;
+				match policy {
+					Some(policy) => format!(
+						"Model policy for this server set to {}. {}.",
+						policy.name(),
+						policy.description()
+					),
+					None => format!(
+						"Model policy for this server reset to default, which is {}.",
+						gpt.model_policy().name()
+					),
+				}
+			}
+			_ => String::from(
+				"You need the Manage Server permission to set the model policy for the server.",
+			),
+		}
+	} else {
+		set_user_model_policy(executor, interaction.user.id, policy).await;
+		match policy {
+			Some(policy) => format!(
+				"Your model policy set to {}. {}.",
+				policy.name(),
+				policy.description()
+			),
+			None => String::from("Your model policy reset, so the server's applies."),
+		}
+	};
+	let _ = interaction_reply(context, interaction, output, true).await;
+	Ok(())
+}
+
+pub fn register_set_model_policy() -> CreateCommand {
+	let mut policy_option = CreateCommandOption::new(
+		CommandOptionType::String,
+		"policy",
+		"Which model your replies in existing conversations use. Leave out to reset.",
+	)
+	.required(false);
+	for policy in ModelPolicy::ALL {
+		policy_option = policy_option.add_string_choice(
+			format!("{}: {}", policy.name(), policy.description()),
+			policy.name(),
+		);
+	}
+
+	CreateCommand::new("modelpolicy")
+		.description("Sets which model replies continuing a conversation use.")
+		.add_option(policy_option)
+		.add_option(
+			CreateCommandOption::new(
+				CommandOptionType::Boolean,
+				"server",
+				"Set it for everyone in the server who hasn't set their own. Needs Manage Server.",
+			)
+			.required(false),
+		)
+}
+
+// Temperature and length
+
+pub async fn get_sampling_settings(executor: &Pool<Sqlite>, user: UserId) -> Sampling {
+	let user_id = user.get() as i64;
+	query!(
+		"
+		SELECT
+			temperature,
+			max_tokens
+		FROM
+			user_settings
+		WHERE
+			user = ?
+		",
+		user_id
+	)
+	.fetch_optional(executor)
+	.await
+	.unwrap()
+	.map(|record| Sampling {
+		temperature: record.temperature.map(|temperature| temperature as f32),
+		max_tokens: record.max_tokens.map(|max_tokens| max_tokens as u32),
+	})
+	.unwrap_or_default()
+}
+
+async fn set_temperature(executor: &Pool<Sqlite>, user: UserId, temperature: Option<f32>) {
+	let user_id = user.get() as i64;
+	query!(
+		"
+		INSERT INTO
+			user_settings (user, temperature)
+		VALUES
+			(?, ?)
+		ON CONFLICT (user)
+			DO UPDATE SET
+				temperature = excluded.temperature
+		",
+		user_id,
+		temperature,
+	)
+	.execute(executor)
+	.await
+	.unwrap();
+}
+
+async fn set_max_tokens(executor: &Pool<Sqlite>, user: UserId, max_tokens: Option<u32>) {
+	let user_id = user.get() as i64;
+	query!(
+		"
+		INSERT INTO
+			user_settings (user, max_tokens)
+		VALUES
+			(?, ?)
+		ON CONFLICT (user)
+			DO UPDATE SET
+				max_tokens = excluded.max_tokens
+		",
+		user_id,
+		max_tokens,
+	)
+	.execute(executor)
+	.await
+	.unwrap();
+}
+
+/// Set the temperature of responses, or reset it without an argument.
+pub async fn command_set_temperature(
+	context: Context,
+	interaction: CommandInteraction,
+	executor: &Pool<Sqlite>,
+	gpt: &Gpt,
+) -> Result<(), ()> {
+	let user = interaction.user.id;
+	let temperature = interaction
+		.data
+		.options
+		.first()
+		.and_then(|option| option.value.as_f64())
+		.map(|temperature| temperature as f32);
+	let model = get_user_model(executor, gpt, user).await;
+	let output = match temperature {
+		Some(temperature) if !(0.0..=model.max_temperature()).contains(&temperature) => {
+			format!(
+				"{} takes a temperature between 0 and {}.",
+				model.friendly_name(),
+				model.max_temperature()
+			)
+		}
+		_ => {
+			set_temperature(executor, user, temperature).await;
+			let output = match temperature {
+				Some(temperature) => {
+					format!("Temperature for your future prompts set to {temperature}.")
+				}
+				None => String::from("Temperature for your future prompts reset to default."),
+			};
+			if model.supports_temperature() {
+				output
+			} else {
+				format!(
+					"{output} {} doesn't take a temperature, so it only applies to other models.",
+					model.friendly_name()
+				)
+			}
+		}
+	};
+	let _ = interaction_reply(context, interaction, output, true).await;
+	Ok(())
+}
+
+pub fn register_set_temperature() -> CreateCommand {
+	CreateCommand::new("temperature")
+		.description("Sets how random your future replies are. Leave out to reset to default.")
+		.add_option(
+			CreateCommandOption::new(
+				CommandOptionType::Number,
+				"temperature",
+				"From 0 (focused) to 2 (random). Some models only go up to 1.",
+			)
+			.min_number_value(0.0)
+			.max_number_value(2.0)
+			.required(false),
+		)
+}
+
+/// Set the maximum length of responses, or reset it without an argument. It can't be more than the user's model puts out.
+pub async fn command_set_length(
+	context: Context,
+	interaction: CommandInteraction,
+	executor: &Pool<Sqlite>,
+	gpt: &Gpt,
+) -> Result<(), ()> {
+	let user = interaction.user.id;
+	let max_tokens = interaction
+		.data
+		.options
+		.first()
+		.and_then(|option| option.value.as_i64())
+		.map(|max_tokens| u32::try_from(max_tokens).unwrap_or(u32::MAX));
+	let model = get_user_model(executor, gpt, user).await;
+	let output = match max_tokens {
+		Some(max_tokens) if max_tokens == 0 || max_tokens > model.max_output_tokens() => format!(
+			"{} can reply with between 1 and {} tokens.",
+			model.friendly_name(),
+			model.max_output_tokens()
+		),
+		_ => {
+			set_max_tokens(executor, user, max_tokens).await;
+			let sampling = Sampling {
+				max_tokens,
+				..Default::default()
+			};
+			let length = match max_tokens {
+				Some(_) => "set",
+				None => "reset to default",
+			};
+			let cost = model.get_worst_case_cost(&[], &[], sampling);
+			format!(
+				"Maximum length of your future replies {length}. That's {} tokens, which cost up to {} with {}.",
+				model.reply_tokens(sampling),
+				Allowance::from_cost(cost),
+				model.friendly_name()
+			)
+		}
+	};
+	let _ = interaction_reply(context, interaction, output, true).await;
+	Ok(())
+}
+
+pub fn register_set_length() -> CreateCommand {
+	CreateCommand::new("length")
+		.description(
+			"Sets the maximum length of your future replies in tokens. Leave out to reset to default.",
+		)
+		.add_option(
+			CreateCommandOption::new(
+				CommandOptionType::Integer,
+				"tokens",
+				"The maximum number of tokens per reply. Longer replies can cost more.",
+			)
+			.min_int_value(1)
+			.required(false),
+		)
+}
+
 // Personality
 
 /// Get the chat personality set for the specified user.
@@ -1,8 +1,9 @@
 use serenity::{
-	all::{CommandInteraction, Message},
+	all::{ButtonStyle, CommandInteraction, Message, MessageReference, UserId},
 	builder::{
-		CreateEmbed, CreateInteractionResponse, CreateInteractionResponseFollowup,
-		CreateInteractionResponseMessage, CreateMessage,
+		CreateActionRow, CreateButton, CreateEmbed, CreateInteractionResponse,
+		CreateInteractionResponseFollowup, CreateInteractionResponseMessage, CreateMessage,
+		EditMessage,
 	},
 	constants,
 	http::Http,
@@ -11,16 +12,22 @@ use serenity::{
 
 use crate::{
 	allowances::Allowance,
+	conversations::MessageIds,
 	gpt::{GptModel, MessageChoice},
 };
 
 /// Replies to a message, without pinging, putting the text into an embed if it's too long.
-pub async fn reply<S>(message: Message, http: &Http, content: S) -> Result<Message, SerenityError>
+pub async fn reply<S>(
+	message: MessageIds,
+	http: &Http,
+	content: S,
+) -> Result<Message, SerenityError>
 where
 	S: Into<String>,
 {
 	let content: String = content.into();
-	let message_builder = CreateMessage::new().reference_message(&message);
+	let reference = MessageReference::from((message.channel_id, message.message_id));
+	let message_builder = CreateMessage::new().reference_message(reference);
 	if content.chars().count() <= constants::MESSAGE_CODE_LIMIT {
 		message
 			.channel_id
@@ -37,6 +44,74 @@ where
 	}
 }
 
+/// Edits a message, putting the text into an embed if it's too long.
+pub async fn edit_reply<S>(
+	message: MessageIds,
+	http: &Http,
+	content: S,
+) -> Result<(), SerenityError>
+where
+	S: Into<String>,
+{
+	let message_builder = edit_message_builder(content.into());
+	message
+		.channel_id
+		.edit_message(http, message.message_id, message_builder)
+		.await
+		.map(|_| ())
+}
+
+/// Edits a reply like `edit_reply`, and adds a button for the asker to regenerate it.
+pub async fn finish_reply<S>(
+	message: MessageIds,
+	http: &Http,
+	content: S,
+	asker: UserId,
+) -> Result<(), SerenityError>
+where
+	S: Into<String>,
+{
+	let message_builder =
+		edit_message_builder(content.into()).components(vec![regenerate_button(asker)]);
+	message
+		.channel_id
+		.edit_message(http, message.message_id, message_builder)
+		.await
+		.map(|_| ())
+}
+
+fn edit_message_builder(content: String) -> EditMessage {
+	if content.chars().count() <= constants::MESSAGE_CODE_LIMIT {
+		EditMessage::new().content(content).embeds(Vec::new())
+	} else {
+		EditMessage::new()
+			.content("")
+			.embed(CreateEmbed::new().description(content))
+	}
+}
+
+const REGENERATE_PREFIX: &str = "regenerate:";
+
+/// A button for getting another reply in place of this one. Only the user who asked may use it, so they're part of its ID.
+fn regenerate_button(asker: UserId) -> CreateActionRow {
+	CreateActionRow::Buttons(vec![CreateButton::new(format!(
+		"{REGENERATE_PREFIX}{}",
+		asker.get()
+	))
+	.style(ButtonStyle::Secondary)
+	.emoji('🔄')
+	.label("Regenerate")])
+}
+
+/// The asker a regenerate button is for, if the component ID is of one.
+pub fn parse_regenerate_button(custom_id: &str) -> Option<UserId> {
+	custom_id
+		.strip_prefix(REGENERATE_PREFIX)?
+		.parse()
+		.ok()
+		.map(UserId::new)
+}
+
 /// Replies to an interaction, putting the text into an embed if it's too long.
 pub async fn interaction_reply<S>(
 	context: Context,
@@ -107,29 +182,36 @@ where
 	.map(|_| ())
 }
 
-/// Attaches formatting to the message from GPT, like "🤖 Hello. (-0.25 m$, 39.95 m$) (GPT-4)".
+/// Attaches formatting to the message from GPT, like "🤖 Hello. (-0.25 m$, 39.95 m$) (GPT-4) (2 earlier turns left out)".
 pub fn format_chat_message(
 	response: &MessageChoice,
 	emoji: &str,
 	cost: Allowance,
 	allowance: Allowance,
 	model: Option<&GptModel>,
+	note: Option<&str>,
 ) -> String {
 	let output = &response.message.content;
 	let ending = ending_from_finish_reason(&response.finish_reason);
+	let mut message = format!("{} {}{} (-{}, {})", emoji, output, ending, cost, allowance);
 	if let Some(model) = model {
-		format!(
-			"{} {}{} (-{}, {}) ({})",
-			emoji,
-			output,
-			ending,
-			cost,
-			allowance,
-			model.friendly_name(),
-		)
-	} else {
-		format!("{} {}{} (-{}, {})", emoji, output, ending, cost, allowance,)
+		message.push_str(&format!(" ({})", model.friendly_name()));
 	}
+	if let Some(note) = note {
+		message.push(' ');
+		message.push_str(note);
+	}
+	message
+}
+
+/// Formats a message from GPT that is still being generated, like "🤖 Hello ⌛".
+pub fn format_partial_chat_message(content: &str, emoji: &str) -> String {
+	format!("{} {} ⌛", emoji, content)
+}
+
+/// Formats a message from GPT that stopped arriving before it was done, like "🤖 Hello ✂️ (interrupted)".
+pub fn format_truncated_chat_message(content: &str, emoji: &str) -> String {
+	format!("{} {} ✂️ (interrupted)", emoji, content)
 }
 
 pub fn ending_from_finish_reason(finish_reason: &str) -> &'static str {
@@ -140,7 +222,7 @@ pub fn ending_from_finish_reason(finish_reason: &str) -> &'static str {
 		"length" => "…",
 		// Omitted content due to content filters.
 		"content_filter" => " \\🙊",
-		// "function call" should only happen if the AI decides to call a function, "null" means "API response still in progress or incomplete", and other options are not listed.
+		// "tool_calls" is dealt with before getting here, by running the tools and asking again. "null" means "API response still in progress or incomplete", and other options are not listed.
 		reason => {
 			eprintln!("GPT API somehow returned finish reason \"{reason}\".");
 			"⁇"